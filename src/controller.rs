@@ -1,14 +1,21 @@
-use anyhow::Result;
-use crossbeam_channel::{bounded, Receiver, Sender};
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
 use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    io::Write,
+    path::PathBuf,
+    process,
+    rc::Rc,
     thread,
+    time::{Duration, Instant},
 };
+use thread_priority::{set_current_thread_priority, ThreadPriority};
 
-use crate::models::{Model, ModelConfig, ModelId, ModelParams, ModelsCache};
+use crate::models::{
+    self, Capabilities, DownloadStatus, Model, ModelConfig, ModelId, ModelInfo, ModelOptions,
+    ModelParams, ModelsCache, ReasoningTags, StopReason, TokenizerError,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub struct PromptId(u32);
@@ -17,38 +24,103 @@ impl PromptId {
     fn inc(&self) -> PromptId {
         PromptId(self.0 + 1)
     }
+
+    #[cfg(test)]
+    pub(crate) fn new(id: u32) -> PromptId {
+        PromptId(id)
+    }
 }
 
 /// Command for the controller.
 enum Command {
-    /// Load the given model.
-    LoadModel(ModelId),
-    /// Process the given prompt.
-    Prompt(PromptId, String),
+    /// Load the given model, optionally using the weights in the given file.
+    LoadModel(ModelId, Option<PathBuf>),
+    /// Load the given models to compare their replies.
+    LoadModels(Vec<ModelId>),
+    /// Process the given prompt with each of the models, optionally truncating prompts
+    /// that don't fit the model context.
+    Prompt(Vec<(ModelId, PromptId)>, String, bool),
     /// Update the model configuration.
     Config(ModelConfig),
+    /// Update the generation options.
+    Options(Box<ModelOptions>),
     /// Refresh weights for the given model.
     ReloadWeights(ModelId),
+    /// Download the latest cached weights for the given model and load it.
+    UpdateWeights(ModelId),
+    /// Refresh the tokenizer for the given model and load the models again.
+    ReloadTokenizer(ModelId, Vec<ModelId>),
+    /// Encode a prompt with the given model tokenizer.
+    Tokenize(ModelId, String),
+    /// Forget the previous turns kept in the models context.
+    ClearContext,
     /// Stops token generation.
     Stop,
     /// Shutdown controller thread.
     Shutdown,
 }
 
+impl Command {
+    /// Checks if this command stops the reply being generated.
+    ///
+    /// Prompts, tokenize, and configuration commands are queued and processed in order
+    /// after the current reply completes, the other commands interrupt it.
+    fn interrupts(&self) -> bool {
+        !matches!(
+            self,
+            Command::Prompt(..)
+                | Command::Tokenize(..)
+                | Command::Config(_)
+                | Command::Options(_)
+                | Command::ClearContext
+        )
+    }
+}
+
 /// A message sent by the controller task
 pub enum Message {
-    /// A generated token.
-    Token(PromptId, String),
+    /// Generated text with the lowest probability of its tokens.
+    Token(PromptId, String, f32),
+    /// Generated text of the draft that is refined into the reply.
+    Draft(PromptId, String),
+    /// Generated text between the reasoning tags of a reply.
+    Reasoning(PromptId, String),
+    /// Seed used to sample the tokens of a reply.
+    Seed(PromptId, u64),
+    /// Generation has completed with the reason it stopped.
+    Done(PromptId, StopReason),
     /// An error message.
     Error(String),
+    /// An error generating the reply to a prompt.
+    PromptError(PromptId, String),
+    /// An error loading the tokenizer for a model.
+    TokenizerError(ModelId, String),
+    /// Model loading took longer than the configured timeout.
+    LoadTimeout(String),
+    /// The tokens of an encoded prompt with the text of each token.
+    Tokens(Vec<(u32, String)>),
     /// Weights download has started for a model.
     DownloadBegin(String),
     /// Weights download connection.
     DownloadConnecting,
     /// Weights download percent progress.
     DownloadProgress(f32),
+    /// Weights download is rate limited, retrying after the given seconds.
+    DownloadRateLimited(u64),
     /// Weights download has completed.
     DownloadComplete,
+    /// A model has been loaded with the given capabilities.
+    Capabilities(ModelId, Capabilities),
+    /// A model has been loaded with the given size and quantization.
+    Info(ModelId, ModelInfo),
+    /// The prompt text with the template as it was given to the model.
+    Template(PromptId, String),
+    /// The controller has started on the reply to the given prompt.
+    Generating(PromptId),
+    /// Number of tokens generated for a reply or a draft.
+    Usage(usize),
+    /// Number of tokens in the context of a model after a reply.
+    ContextUsed(ModelId, usize),
 }
 
 /// Models controller.
@@ -62,16 +134,25 @@ pub struct Controller {
     task: Option<thread::JoinHandle<()>>,
     last_prompt_id: PromptId,
     model_config: ModelConfig,
+    model_options: ModelOptions,
+    model_ids: Vec<ModelId>,
+    capabilities: HashMap<ModelId, Capabilities>,
+    info: HashMap<ModelId, ModelInfo>,
 }
 
 impl Controller {
-    /// Creates a new controller with the given configuration.
-    pub fn new(model_config: ModelConfig) -> Self {
+    /// Creates a new controller with the given configuration and options.
+    pub fn new(model_config: ModelConfig, model_options: ModelOptions) -> Self {
         let (command_tx, command_rx) = bounded(1024);
         let (message_tx, message_rx) = bounded(1024);
 
+        let options = model_options.clone();
         let task = thread::spawn(move || {
-            message_loop(model_config, command_rx, message_tx);
+            if options.low_priority {
+                lower_priority();
+            }
+
+            message_loop(model_config, options, command_rx, message_tx);
         });
 
         Self {
@@ -80,28 +161,130 @@ impl Controller {
             task: Some(task),
             last_prompt_id: PromptId::default(),
             model_config,
+            model_options,
+            model_ids: Vec::new(),
+            capabilities: HashMap::new(),
+            info: HashMap::new(),
         }
     }
 
     /// Sends a new prompt to the model.
-    pub fn send_prompt(&mut self, prompt: &str) -> PromptId {
+    ///
+    /// The prompt is queued if a reply is being generated. If `truncate` is set the
+    /// oldest prompt tokens that don't fit the model context are dropped.
+    pub fn send_prompt(&mut self, prompt: &str, truncate: bool) -> PromptId {
         self.last_prompt_id = self.last_prompt_id.inc();
 
+        let targets = self
+            .model_ids
+            .iter()
+            .take(1)
+            .map(|model_id| (*model_id, self.last_prompt_id))
+            .collect();
+
         let _ = self
             .command_tx
-            .send(Command::Prompt(self.last_prompt_id, prompt.to_string()));
+            .send(Command::Prompt(targets, prompt.to_string(), truncate));
 
         self.last_prompt_id
     }
 
+    /// Sends a new prompt to the model to generate `count` alternative replies, each
+    /// reply is sampled with a different seed.
+    ///
+    /// Returns the prompt identifier of each reply in the order they are generated.
+    pub fn send_alternatives(
+        &mut self,
+        prompt: &str,
+        count: usize,
+        truncate: bool,
+    ) -> Vec<PromptId> {
+        let Some(model_id) = self.model_ids.first().copied() else {
+            return Vec::new();
+        };
+
+        let mut targets = Vec::with_capacity(count);
+        for _ in 0..count {
+            self.last_prompt_id = self.last_prompt_id.inc();
+            targets.push((model_id, self.last_prompt_id));
+        }
+
+        let _ = self.command_tx.send(Command::Prompt(
+            targets.clone(),
+            prompt.to_string(),
+            truncate,
+        ));
+
+        targets
+            .into_iter()
+            .map(|(_, prompt_id)| prompt_id)
+            .collect()
+    }
+
+    /// Sends a new prompt to all the loaded models.
+    ///
+    /// Returns the prompt identifier used for each model replies.
+    pub fn send_compare_prompt(&mut self, prompt: &str) -> Vec<(ModelId, PromptId)> {
+        let mut targets = Vec::with_capacity(self.model_ids.len());
+        for model_id in &self.model_ids {
+            self.last_prompt_id = self.last_prompt_id.inc();
+            targets.push((*model_id, self.last_prompt_id));
+        }
+
+        let _ = self
+            .command_tx
+            .send(Command::Prompt(targets.clone(), prompt.to_string(), false));
+
+        targets
+    }
+
+    /// Encodes a prompt to show its tokens, the reply is sent with `Message::Tokens`.
+    ///
+    /// If a reply is being generated the tokens are sent after it completes.
+    pub fn tokenize(&self, model_id: ModelId, prompt: &str) {
+        let _ = self
+            .command_tx
+            .send(Command::Tokenize(model_id, prompt.to_string()));
+    }
+
     /// Reloads weights.
     pub fn reload_weights(&self, model_id: ModelId) {
         let _ = self.command_tx.send(Command::ReloadWeights(model_id));
     }
 
+    /// Downloads the weights of a model again from its repo and loads it.
+    pub fn update_model(&mut self, model_id: ModelId) {
+        self.model_ids = vec![model_id];
+        let _ = self.command_tx.send(Command::UpdateWeights(model_id));
+    }
+
+    /// Downloads the tokenizer again without reloading the model weights.
+    pub fn reload_tokenizer(&self, model_id: ModelId) {
+        let _ = self
+            .command_tx
+            .send(Command::ReloadTokenizer(model_id, self.model_ids.clone()));
+    }
+
     /// Loads the a model.
-    pub fn load_model(&self, model_id: ModelId) {
-        let _ = self.command_tx.send(Command::LoadModel(model_id));
+    pub fn load_model(&mut self, model_id: ModelId) {
+        self.model_ids = vec![model_id];
+        let _ = self.command_tx.send(Command::LoadModel(model_id, None));
+    }
+
+    /// Loads a model using the weights in the given GGUF file.
+    pub fn load_model_file(&mut self, model_id: ModelId, model_path: PathBuf) {
+        self.model_ids = vec![model_id];
+        let _ = self
+            .command_tx
+            .send(Command::LoadModel(model_id, Some(model_path)));
+    }
+
+    /// Loads multiple models to compare their replies.
+    pub fn load_models(&mut self, model_ids: &[ModelId]) {
+        self.model_ids = model_ids.to_vec();
+        let _ = self
+            .command_tx
+            .send(Command::LoadModels(model_ids.to_vec()));
     }
 
     /// Returns the current config.
@@ -115,12 +298,53 @@ impl Controller {
         let _ = self.command_tx.send(Command::Config(config));
     }
 
+    /// Sets the generation options.
+    pub fn set_options(&mut self, options: ModelOptions) {
+        self.model_options = options.clone();
+        let _ = self.command_tx.send(Command::Options(Box::new(options)));
+    }
+
+    /// Gets the generation options.
+    pub fn model_options(&self) -> &ModelOptions {
+        &self.model_options
+    }
+
     /// Get the next available controller message.
-    pub fn next_message(&self) -> Option<Message> {
-        self.message_rx.try_recv().ok()
+    pub fn next_message(&mut self) -> Option<Message> {
+        let message = self.message_rx.try_recv().ok();
+        match &message {
+            Some(Message::Capabilities(model_id, capabilities)) => {
+                self.capabilities.insert(*model_id, capabilities.clone());
+            }
+            Some(Message::Info(model_id, info)) => {
+                self.info.insert(*model_id, info.clone());
+            }
+            _ => {}
+        }
+        message
+    }
+
+    /// Gets the capabilities of the current model, if it has been loaded.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.model_ids
+            .first()
+            .and_then(|model_id| self.capabilities.get(model_id))
     }
 
-    /// Stops tokens generation.
+    /// Gets the size and quantization of the current model, if it has been loaded.
+    pub fn model_info(&self) -> Option<&ModelInfo> {
+        self.model_ids
+            .first()
+            .and_then(|model_id| self.info.get(model_id))
+    }
+
+    /// Starts a new conversation for models that keep the previous turns in their
+    /// context, this is applied after the queued prompts.
+    pub fn clear_context(&self) {
+        let _ = self.command_tx.send(Command::ClearContext);
+    }
+
+    /// Stops tokens generation and discards the queued prompts.
     ///
     /// This may be useful when the model is in deranged mode and it keeps generating
     /// text we are not interested in.
@@ -136,149 +360,812 @@ impl Controller {
 }
 
 fn message_loop(
-    model_config: ModelConfig,
+    mut model_config: ModelConfig,
+    mut model_options: ModelOptions,
     command_rx: Receiver<Command>,
     message_tx: Sender<Message>,
 ) {
-    let mut model: Option<Box<dyn Model>> = None;
-    let mut model_params = model_config.params();
+    let mut models: HashMap<ModelId, Box<dyn Model>> = HashMap::new();
+    let mut recent_models = RecentModels::default();
+    let mut model_params = model_config.params().with_options(&model_options);
+    // Weights file used instead of the cached weights.
+    let mut model_file: Option<PathBuf> = None;
+
+    // Commands received while generating a reply.
+    let mut queue = VecDeque::new();
+    let mut loader = ModelLoader::new(command_rx.clone());
+
+    loop {
+        // Commands received while loading a model run after the ones already queued.
+        queue.extend(loader.take_queued());
+
+        let cmd = match queue.pop_front() {
+            Some(cmd) => cmd,
+            None => match command_rx.recv() {
+                Ok(cmd) => cmd,
+                Err(_) => break,
+            },
+        };
 
-    while let Ok(cmd) = command_rx.recv() {
         match cmd {
-            Command::LoadModel(model_id) => {
+            Command::LoadModel(model_id, model_path) => {
+                // Release current models memory before loading the new weights, models
+                // loaded from the cache may be kept to switch back to them later.
+                for (model_id, model) in models.drain() {
+                    if model_options.keep_models && model_file.is_none() {
+                        recent_models.insert(model_id, model);
+                    }
+                }
+                model_file = model_path;
+
+                if model_file.is_none() {
+                    if let Some(model) = recent_models.take(model_id) {
+                        let _ =
+                            message_tx.send(Message::DownloadBegin("Loading Model".to_string()));
+                        let _ = message_tx.send(Message::DownloadProgress(1.0));
+                        let _ = message_tx.send(Message::DownloadComplete);
+                        models.insert(model_id, model);
+                        continue;
+                    }
+                }
+
+                recent_models.evict_for(model_id);
+
                 match load_model(
                     model_id,
-                    model_config.params(),
-                    &command_rx,
+                    model_params.clone(),
+                    &mut loader,
                     &message_tx,
-                    false,
+                    Reload::None,
+                    model_options.load_timeout(),
+                    model_file.clone(),
                 ) {
-                    Ok(m) => model = Some(m),
+                    Ok(m) => {
+                        models.insert(model_id, m);
+                    }
                     Err(e) => {
-                        let _ = message_tx.send(Message::Error(e.to_string()));
+                        let _ = message_tx.send(load_error(model_id, e));
                     }
                 };
             }
-            Command::Prompt(prompt_id, prompt) => {
-                if let Some(model) = model.as_mut() {
-                    let mut token_stream = match model.prompt(&prompt, &model_params) {
-                        Ok(ts) => ts,
+            Command::LoadModels(model_ids) => {
+                models.clear();
+                recent_models.clear();
+                model_file = None;
+
+                if let Err(e) = models::check_memory(&model_ids) {
+                    let _ = message_tx.send(Message::Error(e.to_string()));
+                    continue;
+                }
+
+                for model_id in model_ids {
+                    match load_model(
+                        model_id,
+                        model_params.clone(),
+                        &mut loader,
+                        &message_tx,
+                        Reload::None,
+                        model_options.load_timeout(),
+                        model_file.clone(),
+                    ) {
+                        Ok(m) => {
+                            models.insert(model_id, m);
+                        }
                         Err(e) => {
-                            let _ = message_tx.send(Message::Error(e.to_string()));
-                            continue;
+                            let _ = message_tx.send(load_error(model_id, e));
+                            models.clear();
+                            break;
                         }
                     };
+                }
+            }
+            Command::Prompt(targets, prompt, truncate) => {
+                // Replies are reproducible with the seed sent to the UI.
+                let params = ModelParams {
+                    truncate_prompt: truncate,
+                    seed: model_options.fixed_seed().unwrap_or_else(rand::random),
+                    ..model_params.clone()
+                };
+
+                for (idx, &(model_id, prompt_id)) in targets.iter().enumerate() {
+                    // The model may have failed to load, let the user know instead of
+                    // leaving the reply pending.
+                    let Some(model) = models.get_mut(&model_id) else {
+                        let error = "No model loaded".to_string();
+                        let _ = message_tx.send(Message::PromptError(prompt_id, error));
+                        let _ = message_tx.send(Message::Done(prompt_id, StopReason::Error));
+                        continue;
+                    };
+
+                    let _ = message_tx.send(Message::Generating(prompt_id));
+
+                    // Alternative replies from the same model use consecutive seeds and
+                    // don't continue after each other.
+                    let repeats = targets.iter().filter(|(id, _)| *id == model_id).count();
+                    let previous = targets[..idx].iter().filter(|(id, _)| *id == model_id);
+                    let params = ModelParams {
+                        seed: params.seed.wrapping_add(previous.count() as u64),
+                        keep_context: params.keep_context && repeats == 1,
+                        ..params.clone()
+                    };
+
+                    // In refine mode the reply is generated from a careful draft.
+                    let reply_prompt = if model_options.refine {
+                        let draft_params = ModelParams {
+                            truncate_prompt: truncate,
+                            ..ModelConfig::Careful.params().with_options(&model_options)
+                        };
 
-                    loop {
-                        match token_stream.next(model.as_mut()) {
-                            Ok(Some(token_str)) => {
-                                let _ = message_tx.send(Message::Token(prompt_id, token_str));
+                        match generate(
+                            model.as_mut(),
+                            &prompt,
+                            &draft_params,
+                            &command_rx,
+                            &message_tx,
+                            &mut queue,
+                            |s, _| Message::Draft(prompt_id, s),
+                            |_| None,
+                            |_| None,
+                        ) {
+                            Ok(Generation::Done(draft, _)) => refine_prompt(&prompt, &draft),
+                            Ok(Generation::Interrupted) => {
+                                stop_targets(&targets[idx..], &message_tx);
+                                break;
                             }
-                            Ok(None) => break,
                             Err(e) => {
-                                let _ = message_tx.send(Message::Error(e.to_string()));
-                                break;
+                                let error = Message::PromptError(prompt_id, e.to_string());
+                                let _ = message_tx.send(error);
+                                let _ =
+                                    message_tx.send(Message::Done(prompt_id, StopReason::Error));
+                                continue;
                             }
                         }
+                    } else {
+                        prompt.clone()
+                    };
 
-                        // Skip remainining tokens if there is a new command.
-                        if !command_rx.is_empty() {
+                    // Greedy sampling doesn't use the seed.
+                    if params.top_k > 1 {
+                        let _ = message_tx.send(Message::Seed(prompt_id, params.seed));
+                    }
+
+                    match generate(
+                        model.as_mut(),
+                        &reply_prompt,
+                        &params,
+                        &command_rx,
+                        &message_tx,
+                        &mut queue,
+                        |s, prob| Message::Token(prompt_id, s, prob),
+                        |s| Some(Message::Reasoning(prompt_id, s)),
+                        |s| Some(Message::Template(prompt_id, s)),
+                    ) {
+                        Ok(Generation::Done(reply, reason)) => {
+                            let _ = message_tx.send(Message::Done(prompt_id, reason));
+                            run_completion_command(&model_options.completion_command, reply);
+                        }
+                        Ok(Generation::Interrupted) => {
+                            stop_targets(&targets[idx..], &message_tx);
                             break;
                         }
+                        Err(e) => {
+                            let error = Message::PromptError(prompt_id, e.to_string());
+                            let _ = message_tx.send(error);
+                            let _ = message_tx.send(Message::Done(prompt_id, StopReason::Error));
+                        }
                     }
+
+                    let used = model.context_used();
+                    let _ = message_tx.send(Message::ContextUsed(model_id, used));
+                }
+            }
+            Command::Config(config) => {
+                model_config = config;
+                model_params = model_config.params().with_options(&model_options);
+            }
+            Command::Options(options) => {
+                // The priority can only be lowered, raising it may need privileges.
+                if options.low_priority && !model_options.low_priority {
+                    lower_priority();
+                }
+
+                model_options = *options;
+                model_params = model_config.params().with_options(&model_options);
+
+                if !model_options.keep_models {
+                    recent_models.clear();
                 }
             }
-            Command::Config(config) => model_params = config.params(),
             Command::Stop => {}
+            Command::ClearContext => {
+                for (&model_id, model) in models.iter_mut() {
+                    model.clear_context();
+                    let _ = message_tx.send(Message::ContextUsed(model_id, 0));
+                }
+            }
+            Command::Tokenize(model_id, prompt) => {
+                let Some(model) = models.get_mut(&model_id) else {
+                    let _ = message_tx.send(Message::Error("No model loaded".to_string()));
+                    continue;
+                };
+
+                let tokens = model
+                    .encode_prompt(&prompt, &model_params)
+                    .and_then(|tokens| {
+                        tokens
+                            .into_iter()
+                            .map(|token| Ok((token, model.decode(&[token])?)))
+                            .collect::<Result<Vec<_>>>()
+                    });
+
+                let _ = match tokens {
+                    Ok(tokens) => message_tx.send(Message::Tokens(tokens)),
+                    Err(e) => message_tx.send(Message::Error(e.to_string())),
+                };
+            }
+            Command::ReloadTokenizer(tokenizer_id, model_ids) => {
+                models.clear();
+                recent_models.remove(tokenizer_id);
+
+                for model_id in model_ids {
+                    let reload = if model_id == tokenizer_id {
+                        Reload::Tokenizer
+                    } else {
+                        Reload::None
+                    };
+
+                    match load_model(
+                        model_id,
+                        model_params.clone(),
+                        &mut loader,
+                        &message_tx,
+                        reload,
+                        model_options.load_timeout(),
+                        model_file.clone(),
+                    ) {
+                        Ok(m) => {
+                            models.insert(model_id, m);
+                        }
+                        Err(e) => {
+                            let _ = message_tx.send(load_error(model_id, e));
+                            models.clear();
+                            break;
+                        }
+                    };
+                }
+            }
             Command::ReloadWeights(model_id) => {
+                models.remove(&model_id);
+                recent_models.remove(model_id);
+
                 match load_model(
                     model_id,
-                    model_config.params(),
-                    &command_rx,
+                    model_params.clone(),
+                    &mut loader,
                     &message_tx,
-                    true,
+                    Reload::All,
+                    model_options.load_timeout(),
+                    model_file.clone(),
                 ) {
-                    Ok(m) => model = Some(m),
+                    Ok(m) => {
+                        models.insert(model_id, m);
+                    }
                     Err(e) => {
-                        let _ = message_tx.send(Message::Error(e.to_string()));
+                        let _ = message_tx.send(load_error(model_id, e));
                     }
                 };
             }
+            Command::UpdateWeights(model_id) => {
+                // Weights from a local file cannot be updated, use the cached ones.
+                models.clear();
+                model_file = None;
+                queue.push_front(Command::ReloadWeights(model_id));
+            }
             Command::Shutdown => break,
         }
     }
 }
 
+/// How the generation of a reply ended.
+enum Generation {
+    /// The reply completed with its text and the reason it stopped.
+    Done(String, StopReason),
+    /// A queued command interrupted the reply.
+    Interrupted,
+}
+
+/// Generates a reply to the prompt sending its text with the messages built by
+/// `to_message`.
+///
+/// Text between the reasoning tags is sent with the messages built by
+/// `to_reasoning_message` and is not part of the returned reply, it is dropped if
+/// that returns none.
+///
+/// Commands received while generating are added to the queue, and generation stops
+/// as soon as one of them interrupts the reply.
+#[allow(clippy::too_many_arguments)]
+fn generate(
+    model: &mut dyn Model,
+    prompt: &str,
+    params: &ModelParams,
+    command_rx: &Receiver<Command>,
+    message_tx: &Sender<Message>,
+    queue: &mut VecDeque<Command>,
+    to_message: impl Fn(String, f32) -> Message,
+    to_reasoning_message: impl Fn(String) -> Option<Message>,
+    to_template_message: impl Fn(String) -> Option<Message>,
+) -> Result<Generation> {
+    let mut token_stream = model.prompt(prompt, params)?;
+    if let Some(message) = to_template_message(token_stream.template().to_string()) {
+        let _ = message_tx.send(message);
+    }
+    let mut splitter = ReasoningSplitter::new(params.reasoning_tags.clone());
+
+    let mut reply = String::new();
+    let mut send = |parts: Vec<(bool, String)>, prob: f32| {
+        for (reasoning, text) in parts {
+            if reasoning {
+                if let Some(message) = to_reasoning_message(text) {
+                    let _ = message_tx.send(message);
+                }
+            } else {
+                reply.push_str(&text);
+                let _ = message_tx.send(to_message(text, prob));
+            }
+        }
+    };
+
+    // The model continues from the reply prefix at the end of the prompt.
+    if !params.reply_prefix.is_empty() {
+        send(splitter.push(&params.reply_prefix), 1.0);
+    }
+
+    while let Some(token_str) = token_stream.next(model)? {
+        send(splitter.push(&token_str), token_stream.probability());
+
+        queue.extend(command_rx.try_iter());
+        discard_stopped_prompts(queue, message_tx);
+        if queue.iter().any(Command::interrupts) {
+            let _ = message_tx.send(Message::Usage(token_stream.generated_tokens()));
+            return Ok(Generation::Interrupted);
+        }
+    }
+
+    let mut parts = token_stream
+        .finish()
+        .map(|token_str| splitter.push(&token_str))
+        .unwrap_or_default();
+    parts.extend(splitter.finish());
+    send(parts, token_stream.probability());
+
+    let _ = message_tx.send(Message::Usage(token_stream.generated_tokens()));
+
+    Ok(Generation::Done(reply, token_stream.stop_reason()))
+}
+
+/// Splits the generated text into reasoning and reply parts.
+///
+/// Tags can be split across tokens so the text that may be the start of a tag is
+/// held back until the next token, the whitespace after a tag is dropped.
+struct ReasoningSplitter {
+    tags: Option<ReasoningTags>,
+    reasoning: bool,
+    pending: String,
+    trim_start: bool,
+}
+
+impl ReasoningSplitter {
+    fn new(tags: Option<ReasoningTags>) -> Self {
+        Self {
+            tags,
+            reasoning: false,
+            pending: String::new(),
+            trim_start: false,
+        }
+    }
+
+    /// Adds the text of the next tokens and returns the parts that are complete,
+    /// each part is flagged if it is reasoning.
+    fn push(&mut self, text: &str) -> Vec<(bool, String)> {
+        let Some(tags) = self.tags.clone() else {
+            return vec![(false, text.to_string())];
+        };
+
+        self.pending.push_str(text);
+
+        let mut parts = Vec::new();
+        loop {
+            let tag = if self.reasoning {
+                &tags.close
+            } else {
+                &tags.open
+            };
+
+            if let Some(idx) = self.pending.find(tag.as_str()) {
+                let rest = self.pending.split_off(idx);
+                let text = std::mem::replace(&mut self.pending, rest[tag.len()..].to_string());
+                self.add_part(&mut parts, text);
+                self.reasoning = !self.reasoning;
+                self.trim_start = true;
+            } else {
+                // Keep the longest suffix that is the start of the tag.
+                let keep = (1..tag.len().min(self.pending.len() + 1))
+                    .rev()
+                    .find(|&n| {
+                        let start = self.pending.len() - n;
+                        self.pending.is_char_boundary(start)
+                            && tag.starts_with(&self.pending[start..])
+                    })
+                    .unwrap_or(0);
+
+                let rest = self.pending.split_off(self.pending.len() - keep);
+                let text = std::mem::replace(&mut self.pending, rest);
+                self.add_part(&mut parts, text);
+                return parts;
+            }
+        }
+    }
+
+    /// Returns the text held back at the end of the reply.
+    fn finish(&mut self) -> Vec<(bool, String)> {
+        let mut parts = Vec::new();
+        let text = std::mem::take(&mut self.pending);
+        self.add_part(&mut parts, text);
+        parts
+    }
+
+    fn add_part(&mut self, parts: &mut Vec<(bool, String)>, mut text: String) {
+        if self.trim_start {
+            text = text.trim_start().to_string();
+            self.trim_start = text.is_empty();
+        }
+
+        if !text.is_empty() {
+            parts.push((self.reasoning, text));
+        }
+    }
+}
+
+/// Builds the prompt that asks the model to review and improve a draft reply.
+fn refine_prompt(prompt: &str, draft: &str) -> String {
+    format!(
+        "Review the draft answer to the question below, fix any mistakes and make it \
+         clearer. Reply only with the improved answer.\n\n\
+         Question:\n{prompt}\n\n\
+         Draft answer:\n{draft}"
+    )
+}
+
+/// Discards the prompts queued before a stop command.
+fn discard_stopped_prompts(queue: &mut VecDeque<Command>, message_tx: &Sender<Message>) {
+    let Some(stop_idx) = queue.iter().rposition(|cmd| matches!(cmd, Command::Stop)) else {
+        return;
+    };
+
+    let mut kept = VecDeque::with_capacity(queue.len());
+    for (idx, cmd) in queue.drain(..).enumerate() {
+        match cmd {
+            Command::Prompt(targets, _, _) if idx < stop_idx => {
+                for (_, prompt_id) in targets {
+                    let _ = message_tx.send(Message::Done(prompt_id, StopReason::User));
+                }
+            }
+            cmd => kept.push_back(cmd),
+        }
+    }
+
+    *queue = kept;
+}
+
+/// Lowers the priority of the generation threads so that other apps stay responsive.
+///
+/// The matrix products of the models run on the rayon thread pool so its threads are
+/// lowered too, errors are ignored as generation works at any priority.
+fn lower_priority() {
+    let lower = || {
+        let _ = set_current_thread_priority(ThreadPriority::Min);
+    };
+
+    lower();
+    rayon::broadcast(|_| lower());
+}
+
+/// Runs the completion command with the reply text piped to its stdin.
+///
+/// The command is split on whitespace and run without a shell so that the reply
+/// cannot inject commands, errors are ignored as the command is best effort.
+fn run_completion_command(command: &str, reply: String) {
+    let mut args = command.split_whitespace();
+    let Some(program) = args.next() else {
+        return;
+    };
+
+    let child = process::Command::new(program)
+        .args(args)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn();
+
+    if let Ok(mut child) = child {
+        // Write and wait on another thread so a slow command doesn't block generation.
+        thread::spawn(move || {
+            if let Some(mut stdin) = child.stdin.take() {
+                let _ = stdin.write_all(reply.as_bytes());
+            }
+
+            let _ = child.wait();
+        });
+    }
+}
+
+/// Recently used models kept in memory, the least recently used are evicted first.
+#[derive(Default)]
+struct RecentModels {
+    models: Vec<(ModelId, Box<dyn Model>)>,
+}
+
+impl RecentModels {
+    /// Maximum number of models kept in memory.
+    const CAPACITY: usize = 2;
+
+    fn insert(&mut self, model_id: ModelId, model: Box<dyn Model>) {
+        self.remove(model_id);
+        self.models.push((model_id, model));
+        if self.models.len() > Self::CAPACITY {
+            self.models.remove(0);
+        }
+    }
+
+    fn take(&mut self, model_id: ModelId) -> Option<Box<dyn Model>> {
+        let idx = self.models.iter().position(|(id, _)| *id == model_id)?;
+        Some(self.models.remove(idx).1)
+    }
+
+    fn remove(&mut self, model_id: ModelId) {
+        self.models.retain(|(id, _)| *id != model_id);
+    }
+
+    /// Evicts models until there is enough memory to load the given model.
+    fn evict_for(&mut self, model_id: ModelId) {
+        while !self.models.is_empty() && models::check_memory(&[model_id]).is_err() {
+            self.models.remove(0);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.models.clear();
+    }
+}
+
+/// Files to download again when loading a model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reload {
+    None,
+    All,
+    Tokenizer,
+}
+
+/// Model construction didn't complete within the load timeout.
+#[derive(Debug)]
+struct LoadTimeout(ModelId, Duration);
+
+impl std::fmt::Display for LoadTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Loading {} timed out after {} seconds",
+            self.0.spec().name,
+            self.1.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for LoadTimeout {}
+
+/// Creates the message for a model loading error.
+fn load_error(model_id: ModelId, e: anyhow::Error) -> Message {
+    if e.is::<TokenizerError>() {
+        Message::TokenizerError(model_id, e.to_string())
+    } else if e.is::<LoadTimeout>() {
+        Message::LoadTimeout(e.to_string())
+    } else {
+        Message::Error(e.to_string())
+    }
+}
+
+/// Queues the received commands and checks if any of them interrupts a load.
+fn receive_interrupts(command_rx: &Receiver<Command>, queued: &RefCell<VecDeque<Command>>) -> bool {
+    let mut queued = queued.borrow_mut();
+    queued.extend(command_rx.try_iter());
+    queued.iter().any(Command::interrupts)
+}
+
+/// Loads a model, a cached tokenizer that fails to parse is downloaded again once
+/// before giving up.
 fn load_model(
     model_id: ModelId,
     params: ModelParams,
-    command_rx: &Receiver<Command>,
+    loader: &mut ModelLoader,
+    message_tx: &Sender<Message>,
+    reload: Reload,
+    timeout: Duration,
+    model_path: Option<PathBuf>,
+) -> Result<Box<dyn Model>> {
+    let result = try_load_model(
+        model_id,
+        params.clone(),
+        loader,
+        message_tx,
+        reload,
+        timeout,
+        model_path.clone(),
+    );
+
+    let result = match result {
+        Err(e) if e.is::<TokenizerError>() && reload == Reload::None => try_load_model(
+            model_id,
+            params,
+            loader,
+            message_tx,
+            Reload::Tokenizer,
+            timeout,
+            model_path,
+        ),
+        result => result,
+    };
+
+    if let Ok(model) = &result {
+        let _ = message_tx.send(Message::Capabilities(model_id, model.capabilities()));
+        let _ = message_tx.send(Message::Info(model_id, model.info()));
+    }
+
+    result
+}
+
+/// Time between progress updates while the model is built from its weights.
+const LOAD_PROGRESS_INTERVAL: Duration = Duration::from_millis(25);
+
+/// Builds models from their weights on another thread.
+///
+/// Commands received while loading are queued and a load is interrupted only by the
+/// commands that interrupt a reply. A build that is interrupted or times out keeps
+/// running until the weights are read, the next build waits for it so that two
+/// models are not built in memory at the same time.
+struct ModelLoader {
+    command_rx: Receiver<Command>,
+    queued: Rc<RefCell<VecDeque<Command>>>,
+    abandoned: Option<thread::JoinHandle<()>>,
+}
+
+impl ModelLoader {
+    fn new(command_rx: Receiver<Command>) -> Self {
+        Self {
+            command_rx,
+            queued: Default::default(),
+            abandoned: None,
+        }
+    }
+
+    /// Checks if a command that interrupts the load has been received.
+    fn is_interrupted(&self) -> bool {
+        receive_interrupts(&self.command_rx, &self.queued)
+    }
+
+    /// Gets a function that checks if the load is interrupted, for downloads.
+    fn interrupt_check(&self) -> impl Fn() -> bool + 'static {
+        let command_rx = self.command_rx.clone();
+        let queued = self.queued.clone();
+        move || receive_interrupts(&command_rx, &queued)
+    }
+
+    /// Takes the commands received during the load.
+    fn take_queued(&mut self) -> VecDeque<Command> {
+        self.queued.take()
+    }
+
+    /// Runs the build on another thread and waits for its result, a cycling progress
+    /// is sent while waiting.
+    fn build<T: Send + 'static>(
+        &mut self,
+        message_tx: &Sender<Message>,
+        timeout: Duration,
+        timeout_error: impl FnOnce() -> anyhow::Error,
+        build: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T> {
+        let deadline = Instant::now() + timeout;
+        let mut pct = 0;
+        let mut progress = || {
+            pct = (pct + 1) % 100;
+            let _ = message_tx.send(Message::DownloadProgress(pct as f32 / 100.0));
+        };
+
+        while let Some(abandoned) = self.abandoned.take() {
+            if !abandoned.is_finished() {
+                self.abandoned = Some(abandoned);
+                if self.is_interrupted() {
+                    return Err(anyhow!("Model loading interrupted"));
+                }
+
+                if Instant::now() >= deadline {
+                    return Err(timeout_error());
+                }
+
+                progress();
+                thread::sleep(LOAD_PROGRESS_INTERVAL);
+            }
+        }
+
+        let (result_tx, result_rx) = bounded(1);
+        let handle = thread::spawn(move || {
+            let _ = result_tx.send(build());
+        });
+
+        loop {
+            match result_rx.recv_timeout(LOAD_PROGRESS_INTERVAL) {
+                Ok(result) => return Ok(result),
+                Err(RecvTimeoutError::Disconnected) => return Err(anyhow!("Model loading failed")),
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+
+            if self.is_interrupted() {
+                self.abandoned = Some(handle);
+                return Err(anyhow!("Model loading interrupted"));
+            }
+
+            if Instant::now() >= deadline {
+                self.abandoned = Some(handle);
+                return Err(timeout_error());
+            }
+
+            progress();
+        }
+    }
+}
+
+fn try_load_model(
+    model_id: ModelId,
+    params: ModelParams,
+    loader: &mut ModelLoader,
     message_tx: &Sender<Message>,
-    reload: bool,
+    reload: Reload,
+    timeout: Duration,
+    model_path: Option<PathBuf>,
 ) -> Result<Box<dyn Model>> {
     let cache = ModelsCache::new()?;
     let cached_model = cache.cached_model(model_id);
 
-    if !cached_model.is_model_cached() || reload {
+    // Weights loaded from a local file don't need downloading.
+    let download = !cached_model.is_model_cached() || reload == Reload::All;
+    if model_path.is_none() && download {
         let _ = message_tx.send(Message::DownloadBegin("Downloading Model".to_string()));
         let _ = message_tx.send(Message::DownloadConnecting);
 
-        cached_model.download_model({
-            let message_tx = message_tx.clone();
-            let command_rx = command_rx.clone();
-            move |pct| {
-                if command_rx.is_empty() {
-                    let _ = message_tx.send(Message::DownloadProgress(pct));
-                    true
-                } else {
-                    false
-                }
-            }
-        })?;
+        cached_model.download_model(download_progress(message_tx, loader))?;
     }
 
-    if !cached_model.is_tokenizer_cached() || reload {
-        let _ = message_tx.send(Message::DownloadBegin("Downloading Tokenizer".to_string()));
+    if !cached_model.is_tokenizer_cached() || reload != Reload::None {
+        let title = if reload == Reload::Tokenizer && cached_model.is_tokenizer_cached() {
+            "Downloading Tokenizer Again"
+        } else {
+            "Downloading Tokenizer"
+        };
+        let _ = message_tx.send(Message::DownloadBegin(title.to_string()));
         let _ = message_tx.send(Message::DownloadConnecting);
 
-        cached_model.download_tokenizer({
-            let message_tx = message_tx.clone();
-            let command_rx = command_rx.clone();
-            move |pct| {
-                if command_rx.is_empty() {
-                    let _ = message_tx.send(Message::DownloadProgress(pct));
-                    true
-                } else {
-                    false
-                }
-            }
-        })?;
+        cached_model.download_tokenizer(download_progress(message_tx, loader))?;
     }
 
     let _ = message_tx.send(Message::DownloadBegin("Loading Model".to_string()));
-    let finished = Arc::new(AtomicBool::new(false));
-    let task = thread::spawn({
-        let message_tx = message_tx.clone();
-        let finished = finished.clone();
-        move || {
-            for pct in 0..=1000 {
-                if finished.load(Ordering::Relaxed) {
-                    break;
-                }
-
-                let _ = message_tx.send(Message::DownloadProgress((pct % 100) as f32 / 100.0));
-                thread::sleep(std::time::Duration::from_millis(25));
-            }
-        }
-    });
-
-    // Create model from the loaded weights.
-    let model_result = model_id.model(params);
 
-    // Stop loading thread before checking for error.
-    finished.store(true, Ordering::Relaxed);
-    let _ = task.join();
-    let model = model_result?;
+    // Construction blocks while reading the weights, like downloads it is interrupted
+    // so that going back or closing the app doesn't wait for the weights.
+    let model = loader.build(
+        message_tx,
+        timeout,
+        || LoadTimeout(model_id, timeout).into(),
+        move || model_id.model(params, model_path),
+    )??;
 
     // Show progress and download complete, use a small delay to make it easier to
     // see in the UI.
@@ -288,3 +1175,181 @@ fn load_model(
 
     Ok(model)
 }
+
+/// Ends the replies of the targets that are left when a prompt is interrupted, so
+/// that the UI doesn't keep waiting for them.
+fn stop_targets(targets: &[(ModelId, PromptId)], message_tx: &Sender<Message>) {
+    for &(_, prompt_id) in targets {
+        let _ = message_tx.send(Message::Done(prompt_id, StopReason::User));
+    }
+}
+
+/// Creates the update function for a download.
+///
+/// The download is interrupted as soon as a command that interrupts replies is
+/// received, this includes `Shutdown` so that closing the app doesn't leave a partial
+/// file behind. Progress is sent every half percent.
+fn download_progress(
+    message_tx: &Sender<Message>,
+    loader: &ModelLoader,
+) -> impl Fn(DownloadStatus) -> bool + 'static {
+    let message_tx = message_tx.clone();
+    let is_interrupted = loader.interrupt_check();
+    let last_pct = Cell::new(f32::NEG_INFINITY);
+    move |status| {
+        if is_interrupted() {
+            return false;
+        }
+
+        match status {
+            DownloadStatus::Progress(pct) => {
+                if (pct - last_pct.get()).abs() >= 0.005 {
+                    last_pct.set(pct);
+                    let _ = message_tx.send(Message::DownloadProgress(pct));
+                }
+            }
+            DownloadStatus::RateLimited(secs) => {
+                let _ = message_tx.send(Message::DownloadRateLimited(secs));
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    fn timeout_error() -> anyhow::Error {
+        anyhow!("timeout")
+    }
+
+    #[test]
+    fn build_is_interrupted_promptly() {
+        let (command_tx, command_rx) = bounded(16);
+        let (message_tx, _message_rx) = bounded(1024);
+        let mut loader = ModelLoader::new(command_rx);
+
+        command_tx.send(Command::Stop).unwrap();
+        let start = Instant::now();
+        let result = loader.build(&message_tx, Duration::from_secs(10), timeout_error, || {
+            thread::sleep(Duration::from_secs(2));
+        });
+
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(loader.take_queued().len(), 1);
+    }
+
+    #[test]
+    fn build_queues_commands_that_dont_interrupt() {
+        let (command_tx, command_rx) = bounded(16);
+        let (message_tx, _message_rx) = bounded(1024);
+        let mut loader = ModelLoader::new(command_rx);
+
+        command_tx
+            .send(Command::Config(ModelConfig::Creative))
+            .unwrap();
+        command_tx.send(Command::ClearContext).unwrap();
+        let result = loader.build(&message_tx, Duration::from_secs(10), timeout_error, || {
+            thread::sleep(Duration::from_millis(100));
+            42
+        });
+
+        assert_eq!(result.ok(), Some(42));
+        assert_eq!(loader.take_queued().len(), 2);
+    }
+
+    #[test]
+    fn build_times_out() {
+        let (_command_tx, command_rx) = bounded(16);
+        let (message_tx, _message_rx) = bounded(1024);
+        let mut loader = ModelLoader::new(command_rx);
+
+        let start = Instant::now();
+        let result = loader.build(
+            &message_tx,
+            Duration::from_millis(100),
+            timeout_error,
+            || {
+                thread::sleep(Duration::from_secs(1));
+            },
+        );
+
+        assert_eq!(
+            result.map_err(|e| e.to_string()),
+            Err("timeout".to_string())
+        );
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn build_waits_for_an_abandoned_build() {
+        let (command_tx, command_rx) = bounded(16);
+        let (message_tx, _message_rx) = bounded(1024);
+        let mut loader = ModelLoader::new(command_rx);
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let first = finished.clone();
+        command_tx.send(Command::Stop).unwrap();
+        let result = loader.build(
+            &message_tx,
+            Duration::from_secs(10),
+            timeout_error,
+            move || {
+                thread::sleep(Duration::from_millis(300));
+                first.store(true, Ordering::SeqCst);
+            },
+        );
+        assert!(result.is_err());
+        loader.take_queued();
+
+        let second = finished.clone();
+        let result = loader.build(
+            &message_tx,
+            Duration::from_secs(10),
+            timeout_error,
+            move || second.load(Ordering::SeqCst),
+        );
+        assert_eq!(result.ok(), Some(true));
+    }
+
+    #[test]
+    fn commands_without_a_model_send_errors() {
+        let (command_tx, command_rx) = bounded(16);
+        let (message_tx, message_rx) = bounded(1024);
+        let task = thread::spawn(move || {
+            message_loop(
+                ModelConfig::Careful,
+                ModelOptions::default(),
+                command_rx,
+                message_tx,
+            )
+        });
+
+        let model_id = ModelId::TinyLlamaChat;
+        let prompt_id = PromptId(1);
+        let targets = vec![(model_id, prompt_id)];
+        command_tx
+            .send(Command::Prompt(targets, "Hello".to_string(), false))
+            .unwrap();
+        command_tx
+            .send(Command::Tokenize(model_id, "Hello".to_string()))
+            .unwrap();
+
+        let next = || message_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(
+            matches!(next(), Message::PromptError(id, e) if id == prompt_id && e == "No model loaded")
+        );
+        assert!(matches!(next(), Message::Done(id, StopReason::Error) if id == prompt_id));
+        assert!(matches!(next(), Message::Error(e) if e == "No model loaded"));
+
+        command_tx.send(Command::Shutdown).unwrap();
+        task.join().unwrap();
+    }
+}
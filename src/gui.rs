@@ -1,26 +1,65 @@
 use eframe::egui::*;
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    controller::{Controller, Message},
-    models::ModelConfig,
+    controller::{Controller, Message, PromptId},
+    models::{ModelConfig, ModelId, ModelOptions, StopReason},
 };
 
 mod bubble;
+mod compare_panel;
 mod config;
+mod dropped_file;
+mod export;
 mod gauge;
 mod help;
 mod history;
+mod import;
+mod journal;
+mod latency;
 mod load_panel;
+mod memory;
 mod models_panel;
 mod prompt_panel;
+mod recover;
+mod scroll;
+mod snippets;
+mod templates;
+mod theme;
+
+/// Repaint interval while replies are generated or a panel is animating.
+const BUSY_REPAINT: Duration = Duration::from_millis(50);
+
+/// Repaint interval while idle, this only picks up late controller messages as
+/// input events repaint immediately.
+const IDLE_REPAINT: Duration = Duration::from_secs(1);
+
+/// Maximum number of alternative replies generated for a prompt.
+const MAX_ALTERNATIVES: usize = 4;
+
+/// Frames of the animation shown while a reply is being generated.
+const WAITING_DOTS: [&str; 6] = ["⏺   ", " ⏺  ", "  ⏺ ", "   ⏺", "  ⏺ ", " ⏺  "];
+
+/// Shown for replies that are queued or whose generation has failed.
+const QUEUED_DOTS: &str = "…";
+
+/// Font used to measure the window title, the title bar font is set by the platform.
+const TITLE_FONT: FontId = FontId::new(14.0, FontFamily::Proportional);
+
+/// Part of the window width used by the title, the rest is for the title bar buttons.
+const TITLE_WIDTH: f32 = 0.6;
 
 #[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq)]
 enum UiMode {
     #[default]
     Light,
     Dark,
+    HighContrast,
 }
 
 impl UiMode {
@@ -28,6 +67,7 @@ impl UiMode {
         match self {
             UiMode::Light => Visuals::light(),
             UiMode::Dark => Visuals::dark(),
+            UiMode::HighContrast => Self::high_contrast_visuals(),
         }
     }
 
@@ -35,6 +75,7 @@ impl UiMode {
         match self {
             UiMode::Light => "Light",
             UiMode::Dark => "Dark",
+            UiMode::HighContrast => "High contrast",
         }
     }
 
@@ -42,23 +83,400 @@ impl UiMode {
         match &self {
             UiMode::Light => Color32::from_gray(230),
             UiMode::Dark => Color32::from_gray(50),
+            UiMode::HighContrast => Color32::BLACK,
+        }
+    }
+
+    /// Stroke used to outline bubbles and frames.
+    fn stroke(&self) -> Stroke {
+        match &self {
+            UiMode::Light | UiMode::Dark => Stroke::NONE,
+            UiMode::HighContrast => Stroke::new(2.0, Color32::WHITE),
+        }
+    }
+
+    /// Color for progress and informational text, the lighter or darker accent
+    /// that stands out more on the panels background.
+    fn info_color(&self, accent: AccentColor) -> Color32 {
+        match &self {
+            UiMode::Light | UiMode::Dark => {
+                let background = luminance(self.visuals().panel_fill);
+                let contrast = |color| {
+                    let luminance = luminance(color);
+                    (luminance.max(background) + 0.05) / (luminance.min(background) + 0.05)
+                };
+
+                let (lighter, darker) = (accent.lighten(), accent.darken());
+                if contrast(lighter) >= contrast(darker) {
+                    lighter
+                } else {
+                    darker
+                }
+            }
+            UiMode::HighContrast => Color32::YELLOW,
+        }
+    }
+
+    fn high_contrast_visuals() -> Visuals {
+        let mut visuals = Visuals::dark();
+        visuals.override_text_color = Some(Color32::WHITE);
+        visuals.panel_fill = Color32::BLACK;
+        visuals.window_fill = Color32::BLACK;
+        visuals.extreme_bg_color = Color32::BLACK;
+        visuals.faint_bg_color = Color32::from_gray(20);
+        visuals.window_stroke = Stroke::new(2.0, Color32::WHITE);
+        visuals.hyperlink_color = Color32::YELLOW;
+        visuals.selection.bg_fill = Color32::from_rgb(0, 60, 200);
+        visuals.selection.stroke = Stroke::new(2.0, Color32::YELLOW);
+
+        let widgets = &mut visuals.widgets;
+        for widget in [
+            &mut widgets.noninteractive,
+            &mut widgets.inactive,
+            &mut widgets.hovered,
+            &mut widgets.active,
+            &mut widgets.open,
+        ] {
+            widget.fg_stroke = Stroke::new(2.0, Color32::WHITE);
+            widget.bg_stroke = Stroke::new(1.5, Color32::WHITE);
+        }
+        widgets.noninteractive.bg_stroke = Stroke::new(1.0, Color32::from_gray(200));
+        widgets.inactive.bg_fill = Color32::BLACK;
+        widgets.inactive.weak_bg_fill = Color32::BLACK;
+        widgets.hovered.bg_stroke = Stroke::new(2.0, Color32::YELLOW);
+        widgets.active.bg_stroke = Stroke::new(2.0, Color32::YELLOW);
+
+        visuals
+    }
+}
+
+/// Accent color used for the prompt bubbles, the progress gauge, and informational
+/// text.
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq)]
+struct AccentColor([u8; 3]);
+
+impl Default for AccentColor {
+    fn default() -> Self {
+        Self::PRESETS[0].1
+    }
+}
+
+impl AccentColor {
+    /// Named accent colors shown in the config dialog.
+    const PRESETS: [(&'static str, AccentColor); 4] = [
+        ("Blue", AccentColor([15, 85, 235])),
+        ("Green", AccentColor([25, 135, 85])),
+        ("Purple", AccentColor([120, 70, 200])),
+        ("Orange", AccentColor([215, 100, 20])),
+    ];
+
+    fn description(&self) -> &'static str {
+        Self::PRESETS
+            .iter()
+            .find(|(_, preset)| preset == self)
+            .map(|(name, _)| *name)
+            .unwrap_or("Custom")
+    }
+
+    fn color(&self) -> Color32 {
+        let [r, g, b] = self.0;
+        Color32::from_rgb(r, g, b)
+    }
+
+    /// A lighter accent for text on dark backgrounds.
+    fn lighten(&self) -> Color32 {
+        let [r, g, b] = self.0.map(|c| c + (255 - c) / 4);
+        Color32::from_rgb(r, g, b)
+    }
+
+    /// A darker accent for text on light backgrounds and the high contrast mode.
+    fn darken(&self) -> Color32 {
+        let [r, g, b] = self.0.map(|c| c / 2);
+        Color32::from_rgb(r, g, b)
+    }
+
+    /// Text color that stays readable on top of the accent color.
+    fn text_color(&self) -> Color32 {
+        if luminance(self.color()) > 0.5 {
+            Color32::from_gray(20)
+        } else {
+            Color32::from_rgb(210, 225, 250)
+        }
+    }
+}
+
+/// Relative luminance of the color using the sRGB channel weights.
+fn luminance(color: Color32) -> f32 {
+    let [r, g, b] = [color.r(), color.g(), color.b()].map(f32::from);
+    (0.2126 * r + 0.7152 * g + 0.0722 * b) / 255.0
+}
+
+/// How prompts and replies are laid out in the history area.
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq)]
+enum HistoryLayout {
+    /// Chat style bubbles, prompts on the right and replies on the left.
+    #[default]
+    Bubbles,
+    /// Full width blocks with role labels.
+    Document,
+}
+
+impl HistoryLayout {
+    fn description(&self) -> &'static str {
+        match self {
+            HistoryLayout::Bubbles => "Bubbles",
+            HistoryLayout::Document => "Document",
+        }
+    }
+}
+
+/// When to use smaller fonts, padding, and spacing.
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq)]
+enum CompactMode {
+    /// Compact when the window is narrower than `COMPACT_WIDTH`.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl CompactMode {
+    const COMPACT_WIDTH: f32 = 500.0;
+
+    fn description(&self) -> &'static str {
+        match self {
+            CompactMode::Auto => "Auto",
+            CompactMode::Always => "Always",
+            CompactMode::Never => "Never",
+        }
+    }
+
+    /// Checks if the compact layout is used for the current window size.
+    fn is_compact(&self, ctx: &Context) -> bool {
+        match self {
+            CompactMode::Auto => ctx.screen_rect().width() < Self::COMPACT_WIDTH,
+            CompactMode::Always => true,
+            CompactMode::Never => false,
         }
     }
 }
 
 /// State persisted by egui.
-#[derive(Deserialize, Serialize, Debug, Default)]
+#[derive(Deserialize, Serialize, Debug)]
 struct PersistedState {
     history: Vec<Prompt>,
     model_config: ModelConfig,
     ui_mode: UiMode,
+    #[serde(default)]
+    model_options: ModelOptions,
+    #[serde(default)]
+    show_latency: bool,
+    /// Show the current and peak memory of the process.
+    #[serde(default)]
+    show_memory: bool,
+    /// Show the prompt text with the template given to the model under the prompts.
+    #[serde(default)]
+    show_template: bool,
+    #[serde(default)]
+    snippets: Vec<Snippet>,
+    #[serde(default)]
+    templates: Vec<Template>,
+    #[serde(default)]
+    incognito: bool,
+    #[serde(default)]
+    history_layout: HistoryLayout,
+    #[serde(default)]
+    compact_mode: CompactMode,
+    /// Maximum number of exchanges kept in the history, 0 for unlimited.
+    #[serde(default = "default_max_history")]
+    max_history: usize,
+    /// Show role avatars beside the history bubbles.
+    #[serde(default)]
+    show_avatars: bool,
+    #[serde(default)]
+    accent_color: AccentColor,
+    /// Color the replies text by the tokens probability.
+    #[serde(default)]
+    show_token_probs: bool,
+    /// Request the user attention when a reply completes and the window is not focused.
+    #[serde(default)]
+    notify_done: bool,
+    /// Keep the prompt field text when Escape stops the replies generation.
+    #[serde(default)]
+    keep_prompt_on_stop: bool,
+    /// Collapse replies with more than this many lines, 0 to disable.
+    #[serde(default)]
+    collapse_lines: usize,
+    /// Show the prompt of the reply being read at the top of the history.
+    #[serde(default)]
+    sticky_prompt: bool,
+    /// Check if the cached models weights have been updated in their repos.
+    #[serde(default)]
+    check_model_updates: bool,
+    /// Number of replies generated for each prompt to pick the one to keep.
+    #[serde(default = "default_alternatives")]
+    alternatives: usize,
+    /// Maximum number of tokens generated in the conversation, 0 for no limit.
+    #[serde(default)]
+    token_budget: usize,
+    /// Number of tokens generated in the conversation since the budget was reset.
+    #[serde(default)]
+    generated_tokens: usize,
+    /// Generator mode of the models that have one, the others use `model_config`.
+    #[serde(default)]
+    model_configs: HashMap<ModelId, ModelConfig>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+fn default_max_history() -> usize {
+    500
+}
+
+fn default_alternatives() -> usize {
+    1
+}
+
+impl Default for PersistedState {
+    fn default() -> Self {
+        Self {
+            history: Vec::new(),
+            model_config: ModelConfig::default(),
+            ui_mode: UiMode::default(),
+            model_options: ModelOptions::default(),
+            show_latency: false,
+            show_memory: false,
+            show_template: false,
+            snippets: Vec::new(),
+            templates: Vec::new(),
+            incognito: false,
+            history_layout: HistoryLayout::default(),
+            compact_mode: CompactMode::default(),
+            max_history: default_max_history(),
+            show_avatars: false,
+            accent_color: AccentColor::default(),
+            show_token_probs: false,
+            notify_done: false,
+            keep_prompt_on_stop: false,
+            collapse_lines: 0,
+            sticky_prompt: false,
+            check_model_updates: false,
+            alternatives: default_alternatives(),
+            token_budget: 0,
+            generated_tokens: 0,
+            model_configs: HashMap::new(),
+        }
+    }
+}
+
+impl PersistedState {
+    /// Adds an exchange to the history discarding the oldest ones over the limit.
+    fn push_history(&mut self, prompt: Prompt) {
+        self.history.push(prompt);
+        if self.max_history > 0 && self.history.len() > self.max_history {
+            let excess = self.history.len() - self.max_history;
+            self.history.drain(..excess);
+        }
+    }
+
+    /// Gets the generator mode of a model, or the default mode if there is no model or
+    /// it has no saved mode.
+    fn config_for(&self, model_id: Option<ModelId>) -> ModelConfig {
+        model_id
+            .and_then(|model_id| self.model_configs.get(&model_id).copied())
+            .unwrap_or(self.model_config)
+    }
+
+    /// Saves the generator mode of a model, or the default mode if there is no model.
+    fn set_config_for(&mut self, model_id: Option<ModelId>, config: ModelConfig) {
+        match model_id {
+            Some(model_id) => {
+                self.model_configs.insert(model_id, config);
+            }
+            None => self.model_config = config,
+        }
+    }
+
+    /// Gets an error if the conversation has used all the tokens of its budget.
+    fn budget_error(&self) -> Option<String> {
+        (self.token_budget > 0 && self.generated_tokens >= self.token_budget).then(|| {
+            format!(
+                "The conversation has used its budget of {} tokens, reset it from the \
+                status bar, clear the history, or raise it in the config.",
+                self.token_budget
+            )
+        })
+    }
+
+    /// Marks the replies being generated or queued as done.
+    fn clear_pending(&mut self) {
+        for prompt in &mut self.history {
+            prompt.prompt_id = None;
+        }
+    }
+}
+
+/// A named prompt snippet.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+struct Snippet {
+    name: String,
+    text: String,
+}
+
+/// A named prompt template with `{{name}}` placeholders filled before inserting it.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+struct Template {
+    name: String,
+    text: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 struct Prompt {
     prompt: String,
     reply: String,
     info: String,
+    /// Draft that was refined into the reply, empty if refine mode was off.
+    #[serde(default)]
+    draft: String,
+    /// Text the model wrote between the reasoning tags, empty if tags are not set.
+    #[serde(default)]
+    reasoning: String,
+    /// Prompt text with the template given to the model for the reply.
+    #[serde(skip)]
+    template: String,
+    /// Seed used to sample the reply tokens, none for greedy sampling.
+    #[serde(default)]
+    seed: Option<u64>,
+    /// The reply has been edited after it was generated.
+    #[serde(default)]
+    edited: bool,
+    /// Why the reply ended, none if it ended with the end of stream token.
+    #[serde(default)]
+    stopped: Option<StopReason>,
+    /// Identifier of a reply being generated or waiting in the controller queue.
+    #[serde(skip)]
+    prompt_id: Option<PromptId>,
+    /// The prompt was sent truncated from the start to fit the context.
+    #[serde(skip)]
+    truncate: bool,
+    /// Start offset in the reply of each generated text and its probability.
+    #[serde(skip)]
+    token_probs: Vec<(usize, f32)>,
+    /// Show the full reply when it is longer than the collapse threshold.
+    #[serde(skip)]
+    expanded: bool,
+}
+
+impl Prompt {
+    /// Gets the text shown below the prompt with the reply seed and stop reason.
+    fn footer(&self) -> String {
+        let mut footer = self.info.clone();
+        if let Some(seed) = self.seed {
+            footer.push_str(&format!(" - seed: {seed}"));
+        }
+        if let Some(reason) = self.stopped {
+            footer.push_str(&format!(" - stopped: {}", reason.description()));
+        }
+        footer
+    }
 }
 
 trait Panel: Debug {
@@ -75,6 +493,16 @@ trait Panel: Debug {
     fn is_start_panel(&mut self) -> bool {
         false
     }
+
+    /// The model whose generator mode is edited in the config, none for the default.
+    fn model_id(&self) -> Option<ModelId> {
+        None
+    }
+
+    /// Checks if the panel is waiting for controller messages or animating.
+    fn is_busy(&self, _ctx: &AppContext) -> bool {
+        false
+    }
 }
 
 #[derive(Debug)]
@@ -88,7 +516,23 @@ struct AppContext {
 pub struct App {
     ctx: AppContext,
     show_config: bool,
+    config_error: Option<String>,
+    /// Title and message of the last import or export error.
+    file_error: Option<(&'static str, String)>,
+    show_snippets: bool,
+    show_templates: bool,
     show_help: bool,
+    dropped_file: Option<dropped_file::DroppedFile>,
+    journal: journal::Journal,
+    /// Exchanges recovered from the journal waiting to be restored.
+    recovered: Option<Vec<Prompt>>,
+    /// Incognito mode enabled with the `COZE_INCOGNITO` environment variable.
+    env_incognito: bool,
+    /// History last written to storage, it is written again in incognito mode so
+    /// that the saved history is left untouched.
+    saved_history: Vec<Prompt>,
+    /// When the theme was last changed with the shortcut.
+    theme_notice: Option<Instant>,
     active_panel: Box<dyn Panel>,
 }
 
@@ -103,7 +547,18 @@ impl App {
 
         cc.egui_ctx.set_visuals(state.ui_mode.visuals());
 
-        let controller = Controller::new(state.model_config);
+        // Only offer to recover exchanges that are not already in the history.
+        let journal = journal::Journal::new();
+        let recovered = journal
+            .recover()
+            .map(|mut exchanges| {
+                exchanges.retain(|prompt| !state.history.contains(prompt));
+                exchanges
+            })
+            .filter(|exchanges| !exchanges.is_empty());
+
+        let saved_history = state.history.clone();
+        let controller = Controller::new(state.model_config, state.model_options.clone());
         let state = AppContext {
             state,
             controller,
@@ -113,16 +568,41 @@ impl App {
         Self {
             ctx: state,
             show_config: false,
+            config_error: None,
+            file_error: None,
+            show_snippets: false,
+            show_templates: false,
             show_help: false,
+            dropped_file: None,
+            journal,
+            recovered,
+            env_incognito: std::env::var_os("COZE_INCOGNITO").is_some(),
+            saved_history,
+            theme_notice: None,
             active_panel: Box::new(models_panel::ModelsPanel::new()),
         }
     }
+
+    fn is_incognito(&self) -> bool {
+        self.env_incognito || self.ctx.state.incognito
+    }
 }
 
 impl eframe::App for App {
     /// Called by the framework to save state before shutdown.
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
-        eframe::set_value(storage, eframe::APP_KEY, &self.ctx.state);
+        if self.is_incognito() {
+            // Keep prompts and replies in memory only, the settings are still saved.
+            let history = std::mem::replace(
+                &mut self.ctx.state.history,
+                std::mem::take(&mut self.saved_history),
+            );
+            eframe::set_value(storage, eframe::APP_KEY, &self.ctx.state);
+            self.saved_history = std::mem::replace(&mut self.ctx.state.history, history);
+        } else {
+            eframe::set_value(storage, eframe::APP_KEY, &self.ctx.state);
+            self.saved_history = self.ctx.state.history.clone();
+        }
     }
 
     /// Handle input and repaint screen.
@@ -132,11 +612,34 @@ impl eframe::App for App {
             self.ctx.controller.model_config().description()
         )));
 
-        if let Some(m) = self.ctx.controller.next_message() {
+        let received = self.ctx.controller.next_message();
+        let has_message = received.is_some();
+        if let Some(m) = received {
+            if let Message::Usage(tokens) = &m {
+                self.ctx.state.generated_tokens += tokens;
+            }
+
+            if let Message::Done(_, reason) = &m {
+                let focused = ctx.input(|i| i.focused);
+                if self.ctx.state.notify_done && !focused && *reason != StopReason::User {
+                    ctx.send_viewport_cmd(ViewportCommand::RequestUserAttention(
+                        UserAttentionType::Informational,
+                    ));
+                }
+            }
+
             self.active_panel.handle_message(&mut self.ctx, m);
         };
 
+        // Keep the journal until the user decides about the recovered exchanges.
+        if self.recovered.is_none() && !self.is_incognito() {
+            self.journal
+                .update(&self.ctx.state.history, &self.saved_history);
+        }
+
+        self.handle_theme_shortcut(ctx);
         self.active_panel.handle_input(&mut self.ctx);
+        self.handle_dropped_files(ctx);
 
         // Render menu
         TopBottomPanel::top("top_panel").show(ctx, |ui| {
@@ -145,6 +648,7 @@ impl eframe::App for App {
                     let arrow = RichText::new("⬅").font(FontId::new(24.0, FontFamily::Monospace));
                     if ui.add(Button::new(arrow).frame(false)).clicked() {
                         self.ctx.controller.stop();
+                        self.ctx.state.clear_pending();
                         self.active_panel = Box::new(models_panel::ModelsPanel::new());
                     }
                 }
@@ -155,33 +659,134 @@ impl eframe::App for App {
                         ui.close_menu();
                     }
 
+                    if ui.button("Snippets").clicked() {
+                        self.show_snippets = true;
+                        ui.close_menu();
+                    }
+
+                    if ui.button("Templates").clicked() {
+                        self.show_templates = true;
+                        ui.close_menu();
+                    }
+
+                    if ui.button("Copy as OpenAI messages").clicked() {
+                        ctx.copy_text(export::openai_messages(&self.ctx.state.history));
+                        ui.close_menu();
+                    }
+
+                    if ui.button("Import OpenAI messages").clicked() {
+                        ui.close_menu();
+                        self.import_messages();
+                    }
+
+                    if ui.button("Export to HTML").clicked() {
+                        ui.close_menu();
+                        self.export_html();
+                    }
+
                     if ui.button("Clear history").clicked() {
                         self.ctx.state.history.clear();
+                        self.ctx.state.generated_tokens = 0;
+                        self.ctx.controller.clear_context();
                         ui.close_menu();
                     }
+
+                    ui.add_enabled_ui(!self.env_incognito, |ui| {
+                        ui.checkbox(&mut self.ctx.state.incognito, "Incognito")
+                            .on_hover_text("Don't save prompts and replies to disk");
+                    });
                 });
 
                 if ui.button("Help").clicked() {
                     self.show_help = true;
                     ui.close_menu();
                 }
+
+                if self.is_incognito() {
+                    ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                        ui.label(
+                            RichText::new("🕶 Incognito, history is not saved")
+                                .color(ui.visuals().warn_fg_color),
+                        );
+                    });
+                }
             });
         });
 
         self.active_panel.update(&mut self.ctx);
 
         self.config_window(ctx);
+        self.snippets_window(ctx);
+        self.templates_window(ctx);
         self.help_window(ctx);
+        self.dropped_file_window(ctx);
+        self.file_error_window(ctx);
+        self.recover_window(ctx);
+        self.theme_notice(ctx);
 
         if let Some(panel) = self.active_panel.next_panel(&mut self.ctx) {
             self.active_panel = panel;
         }
 
-        // Run 20 frames per second.
-        ctx.request_repaint_after(std::time::Duration::from_millis(50));
+        // Run 20 frames per second only while there is something to update.
+        if has_message || self.active_panel.is_busy(&self.ctx) {
+            ctx.request_repaint_after(BUSY_REPAINT);
+        } else {
+            ctx.request_repaint_after(IDLE_REPAINT);
+        }
     }
 
     fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
         self.ctx.controller.shutdown();
+        self.journal.clear();
+    }
+}
+
+/// Gets the placeholder of an empty reply, it is animated only while the reply is
+/// being generated.
+fn waiting_dots(frame_counter: usize, generating: bool) -> &'static str {
+    if generating {
+        WAITING_DOTS[(frame_counter / 18) % WAITING_DOTS.len()]
+    } else {
+        QUEUED_DOTS
     }
 }
+
+/// Builds the window title with the model name and the generator mode, the name is
+/// truncated if the title doesn't fit the window width.
+fn model_title(ctx: &Context, name: &str, config: ModelConfig) -> String {
+    let mode = format!(" ({})", config.description());
+    let max_width = ctx.screen_rect().width() * TITLE_WIDTH - text_width(ctx, &mode, &TITLE_FONT);
+    format!(
+        "{}{mode}",
+        truncate_words(ctx, name, &TITLE_FONT, max_width)
+    )
+}
+
+/// Truncates the text at a word boundary and adds an ellipsis if it is wider than
+/// `max_width`, the first word is always kept.
+fn truncate_words(ctx: &Context, text: &str, font: &FontId, max_width: f32) -> String {
+    if text_width(ctx, text, font) <= max_width {
+        return text.to_string();
+    }
+
+    let words = text.split_whitespace().collect::<Vec<_>>();
+    (1..words.len())
+        .rev()
+        .map(|n| format!("{}…", words[..n].join(" ")))
+        .find(|truncated| text_width(ctx, truncated, font) <= max_width)
+        .unwrap_or_else(|| match words.as_slice() {
+            [first, _, ..] => format!("{first}…"),
+            _ => text.to_string(),
+        })
+}
+
+/// Gets the width of the text laid out on a single line.
+fn text_width(ctx: &Context, text: &str, font: &FontId) -> f32 {
+    ctx.fonts(|fonts| {
+        fonts
+            .layout_no_wrap(text.to_string(), font.clone(), Color32::PLACEHOLDER)
+            .size()
+            .x
+    })
+}
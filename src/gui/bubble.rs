@@ -1,10 +1,33 @@
 use eframe::egui::*;
 
-use super::UiMode;
+use super::{AccentColor, HistoryLayout, UiMode};
 
 const TEXT_FONT: FontId = FontId::new(15.0, FontFamily::Monospace);
 const FOOTER_FONT: FontId = FontId::new(10.0, FontFamily::Monospace);
+const COMPACT_TEXT_FONT: FontId = FontId::new(13.0, FontFamily::Monospace);
+const COMPACT_FOOTER_FONT: FontId = FontId::new(9.0, FontFamily::Monospace);
+const AVATAR_FONT: FontId = FontId::new(12.0, FontFamily::Monospace);
 const ROUNDING: f32 = 8.0;
+const AVATAR_SIZE: f32 = 24.0;
+
+/// Badge shown in the avatar of prompt bubbles.
+pub const USER_BADGE: &str = "U";
+
+/// Interpolates from red to green as the probability increases.
+fn prob_color(prob: f32) -> Color32 {
+    let low = Rgba::from(Color32::from_rgb(220, 50, 50));
+    let high = Rgba::from(Color32::from_rgb(40, 170, 60));
+    let prob = prob.clamp(0.0, 1.0);
+    Color32::from(low * (1.0 - prob) + high * prob)
+}
+
+/// Gets the badge shown in the avatar of reply bubbles from the model name.
+pub fn model_badge(name: &str) -> String {
+    name.chars()
+        .find(|c| c.is_alphanumeric())
+        .map(|c| c.to_uppercase().collect())
+        .unwrap_or_else(|| "?".to_string())
+}
 
 pub enum BubbleContent {
     Prompt,
@@ -16,6 +39,10 @@ pub struct Bubble {
     content: BubbleContent,
     ui_mode: UiMode,
     footer: Option<WidgetText>,
+    layout: HistoryLayout,
+    avatar: Option<String>,
+    accent: AccentColor,
+    compact: bool,
 }
 
 impl Bubble {
@@ -26,9 +53,64 @@ impl Bubble {
             content,
             ui_mode,
             footer: None,
+            layout: HistoryLayout::Bubbles,
+            avatar: None,
+            accent: AccentColor::default(),
+            compact: false,
+        }
+    }
+
+    /// Creates a reply bubble with its text colored by the tokens probability, green
+    /// for likely tokens and red for unlikely ones.
+    ///
+    /// `probs` has the start offset of each part of the text and its probability.
+    pub fn with_token_probs(text: &str, probs: &[(usize, f32)], ui_mode: UiMode) -> Self {
+        let format = |color| TextFormat {
+            font_id: TEXT_FONT,
+            color,
+            ..Default::default()
+        };
+
+        // Text before the first probability uses the bubble text color.
+        let mut job = text::LayoutJob::default();
+        let first = probs.first().map(|(start, _)| *start).unwrap_or(text.len());
+        job.append(&text[..first], 0.0, format(Color32::PLACEHOLDER));
+
+        for (idx, (start, prob)) in probs.iter().enumerate() {
+            let end = probs.get(idx + 1).map(|(s, _)| *s).unwrap_or(text.len());
+            job.append(&text[*start..end], 0.0, format(prob_color(*prob)));
+        }
+
+        Self {
+            text: WidgetText::from(job),
+            ..Self::new("", BubbleContent::Reply, ui_mode)
         }
     }
 
+    /// Sets the layout, in document layout the bubble uses the full width and shows
+    /// a role label.
+    pub fn with_layout(self, layout: HistoryLayout) -> Self {
+        Self { layout, ..self }
+    }
+
+    /// Sets the accent color used for the prompt bubbles.
+    pub fn with_accent(self, accent: AccentColor) -> Self {
+        Self { accent, ..self }
+    }
+
+    /// Sets the badge of an avatar shown on the left of the bubble, `None` hides it.
+    pub fn with_avatar(self, badge: Option<&str>) -> Self {
+        Self {
+            avatar: badge.map(String::from),
+            ..self
+        }
+    }
+
+    /// Uses smaller fonts and padding for narrow windows.
+    pub fn with_compact(self, compact: bool) -> Self {
+        Self { compact, ..self }
+    }
+
     pub fn with_footer(self, footer: &str) -> Self {
         let footer = WidgetText::from(RichText::new(footer).font(FOOTER_FONT).monospace());
         Self {
@@ -37,19 +119,68 @@ impl Bubble {
         }
     }
 
-    fn fill_color(content: &BubbleContent, ui_mode: UiMode) -> Color32 {
+    /// Changes the font of all the text sections.
+    fn with_font(text: WidgetText, font_id: FontId) -> WidgetText {
+        match text {
+            WidgetText::RichText(text) => WidgetText::RichText(text.font(font_id)),
+            WidgetText::LayoutJob(mut job) => {
+                for section in &mut job.sections {
+                    section.format.font_id = font_id.clone();
+                }
+                WidgetText::LayoutJob(job)
+            }
+            text => text,
+        }
+    }
+
+    fn role_label(content: &BubbleContent) -> &'static str {
+        match content {
+            BubbleContent::Prompt => "User",
+            BubbleContent::Reply => "Assistant",
+        }
+    }
+
+    /// Gets the background color of a bubble.
+    pub fn fill_color(
+        content: &BubbleContent,
+        ui_mode: UiMode,
+        layout: HistoryLayout,
+        accent: AccentColor,
+    ) -> Color32 {
+        if layout == HistoryLayout::Document {
+            return ui_mode.fill_color();
+        }
+
         match content {
-            BubbleContent::Prompt => Color32::from_rgb(15, 85, 235),
+            BubbleContent::Prompt => match ui_mode {
+                UiMode::Light | UiMode::Dark => accent.color(),
+                UiMode::HighContrast => accent.darken(),
+            },
             BubbleContent::Reply => ui_mode.fill_color(),
         }
     }
 
-    fn text_color(content: &BubbleContent, ui_mode: UiMode) -> Color32 {
+    /// Gets the text color of a bubble.
+    pub fn text_color(
+        content: &BubbleContent,
+        ui_mode: UiMode,
+        layout: HistoryLayout,
+        accent: AccentColor,
+    ) -> Color32 {
+        if layout == HistoryLayout::Document {
+            let layout = HistoryLayout::Bubbles;
+            return Self::text_color(&BubbleContent::Reply, ui_mode, layout, accent);
+        }
+
         match content {
-            BubbleContent::Prompt => Color32::from_rgb(210, 225, 250),
+            BubbleContent::Prompt => match ui_mode {
+                UiMode::Light | UiMode::Dark => accent.text_color(),
+                UiMode::HighContrast => Color32::WHITE,
+            },
             BubbleContent::Reply => match ui_mode {
                 UiMode::Light => Color32::from_gray(60),
                 UiMode::Dark => Color32::from_gray(180),
+                UiMode::HighContrast => Color32::WHITE,
             },
         }
     }
@@ -59,17 +190,61 @@ impl Widget for Bubble {
     fn ui(self, ui: &mut Ui) -> Response {
         const PADDING: f32 = 10.0;
         const WIDTH_PCT: f32 = 0.9;
+        const COMPACT_PADDING: f32 = 6.0;
+        const COMPACT_WIDTH_PCT: f32 = 0.95;
 
         let Bubble {
             text,
             content,
             ui_mode,
             footer,
+            layout,
+            avatar,
+            accent,
+            compact,
         } = self;
 
-        let text_wrap_width = ui.available_width() * WIDTH_PCT - 2.0 * PADDING;
+        let (padding, text_font) = if compact {
+            (COMPACT_PADDING, COMPACT_TEXT_FONT)
+        } else {
+            (PADDING, TEXT_FONT)
+        };
+        let (text, footer) = if compact {
+            let text = Self::with_font(text, COMPACT_TEXT_FONT);
+            let footer = footer.map(|f| Self::with_font(f, COMPACT_FOOTER_FONT));
+            (text, footer)
+        } else {
+            (text, footer)
+        };
+
+        // Space reserved on the left for the avatar.
+        let avatar_width = if avatar.is_some() {
+            AVATAR_SIZE + padding
+        } else {
+            0.0
+        };
+        let available_width = ui.available_width() - avatar_width;
+
+        let document = layout == HistoryLayout::Document;
+        let width_pct = match (document, compact) {
+            (true, _) => 1.0,
+            (false, true) => COMPACT_WIDTH_PCT,
+            (false, false) => WIDTH_PCT,
+        };
+        let text_wrap_width = available_width * width_pct - 2.0 * padding;
 
-        let footer_padding = if footer.is_some() { PADDING / 2.0 } else { 0.0 };
+        let header_padding = if document { padding / 2.0 } else { 0.0 };
+        let header_galley = document.then(|| {
+            WidgetText::from(
+                RichText::new(Self::role_label(&content))
+                    .font(text_font)
+                    .strong(),
+            )
+            .into_galley(ui, None, text_wrap_width, TextStyle::Monospace)
+        });
+        let header_size = header_galley.as_ref().map(|g| g.size()).unwrap_or_default();
+
+        let footer_padding = if footer.is_some() { padding / 2.0 } else { 0.0 };
         let footer_galley =
             footer.map(|f| f.into_galley(ui, None, text_wrap_width, TextStyle::Monospace));
         let footer_size = footer_galley.as_ref().map(|g| g.size()).unwrap_or_default();
@@ -77,25 +252,41 @@ impl Widget for Bubble {
         let text_galley = text.into_galley(ui, Some(true), text_wrap_width, TextStyle::Monospace);
         let text_size = text_galley.size();
 
+        let bubble_width = if document {
+            available_width
+        } else {
+            text_size.x.max(footer_size.x) + 2.0 * padding
+        };
         let bubble_size = Vec2::new(
-            text_size.x.max(footer_size.x) + 2.0 * PADDING,
-            text_size.y + footer_size.y + footer_padding + 2.0 * PADDING,
+            bubble_width,
+            text_size.y
+                + header_size.y
+                + header_padding
+                + footer_size.y
+                + footer_padding
+                + 2.0 * padding,
         );
 
         let desired_size = Vec2::new(ui.available_width(), bubble_size.y);
         let (rect, response) = ui.allocate_at_least(desired_size, Sense::click());
 
-        let dx = ui.available_width() - bubble_size.x;
+        let dx = available_width - bubble_size.x;
         let paint_rect = if matches!(content, BubbleContent::Prompt) {
             // Move prompt to the right
-            Rect::from_min_max(Pos2::new(rect.min.x + dx, rect.min.y), rect.max)
+            Rect::from_min_max(
+                Pos2::new(rect.min.x + avatar_width + dx, rect.min.y),
+                rect.max,
+            )
         } else {
-            Rect::from_min_max(rect.min, Pos2::new(rect.max.x - dx, rect.max.y))
+            Rect::from_min_max(
+                Pos2::new(rect.min.x + avatar_width, rect.min.y),
+                Pos2::new(rect.max.x - dx, rect.max.y),
+            )
         };
 
         if ui.is_rect_visible(rect) {
-            let fill_color = Self::fill_color(&content, ui_mode);
-            let text_color = Self::text_color(&content, ui_mode);
+            let fill_color = Self::fill_color(&content, ui_mode, layout, accent);
+            let text_color = Self::text_color(&content, ui_mode, layout, accent);
 
             // On click expand animation.
             let expand = ui
@@ -114,25 +305,48 @@ impl Widget for Bubble {
                 paint_rect,
                 Rounding::same(ROUNDING),
                 fill_color,
-                Stroke::default(),
+                ui_mode.stroke(),
             );
 
-            let text_pos = ui
-                .layout()
-                .align_size_within_rect(
-                    text_size,
-                    paint_rect
-                        .shrink2(Vec2::splat(PADDING + expand))
-                        .translate(Vec2::new(0.0, -footer_size.y)),
-                )
-                .min;
+            if let Some(badge) = avatar {
+                // Use the bubbles colors so the prompt and reply avatars differ.
+                let center = rect.min + Vec2::new(AVATAR_SIZE / 2.0, padding + AVATAR_SIZE / 2.0);
+                let bubbles = HistoryLayout::Bubbles;
+                let avatar_fill = Self::fill_color(&content, ui_mode, bubbles, accent);
+                let avatar_text = Self::text_color(&content, ui_mode, bubbles, accent);
+                ui.painter()
+                    .circle(center, AVATAR_SIZE / 2.0, avatar_fill, ui_mode.stroke());
+                ui.painter().text(
+                    center,
+                    Align2::CENTER_CENTER,
+                    badge,
+                    AVATAR_FONT,
+                    avatar_text,
+                );
+            }
+
+            let text_pos = if let Some(header_galley) = header_galley {
+                // Role label above the text.
+                let header_pos = paint_rect.min + Vec2::splat(padding + expand);
+                ui.painter().galley(header_pos, header_galley, text_color);
+                header_pos + Vec2::new(0.0, header_size.y + header_padding)
+            } else {
+                ui.layout()
+                    .align_size_within_rect(
+                        text_size,
+                        paint_rect
+                            .shrink2(Vec2::splat(padding + expand))
+                            .translate(Vec2::new(0.0, -footer_size.y)),
+                    )
+                    .min
+            };
 
             ui.painter()
                 .galley(text_pos, text_galley.clone(), text_color);
 
             if let Some(footer_galley) = footer_galley {
                 let text_pos = Pos2::new(
-                    paint_rect.right() - PADDING - footer_size.x - expand,
+                    paint_rect.right() - padding - footer_size.x - expand,
                     paint_rect.bottom() - footer_padding - footer_size.y - expand,
                 );
                 ui.painter().galley(text_pos, footer_galley, text_color);
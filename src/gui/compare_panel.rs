@@ -0,0 +1,370 @@
+use eframe::egui::*;
+
+use crate::{
+    controller::{Message, PromptId},
+    gui::{
+        bubble::{model_badge, Bubble, BubbleContent, USER_BADGE},
+        model_title,
+        scroll::ScrollAnchor,
+        waiting_dots, AppContext, Panel,
+    },
+    models::ModelId,
+};
+
+const TEXT_FONT: FontId = FontId::new(15.0, FontFamily::Monospace);
+const COMPACT_TEXT_FONT: FontId = FontId::new(13.0, FontFamily::Monospace);
+const LABEL_FONT: FontId = FontId::new(13.0, FontFamily::Monospace);
+const ROUNDING: f32 = 8.0;
+
+/// Shows the replies of two models to the same prompt side by side.
+#[derive(Debug)]
+pub struct ComparePanel {
+    prompt: String,
+    prompt_field_id: Id,
+    model_names: Vec<String>,
+    exchanges: Vec<Exchange>,
+    /// Replies that are not done yet.
+    pending: Vec<PromptId>,
+    /// The reply the controller is working on.
+    generating: Option<PromptId>,
+    error: Option<String>,
+    frame_counter: usize,
+    scroll_to_bottom: bool,
+    scroll_anchor: ScrollAnchor,
+}
+
+/// A prompt with the replies from each model.
+#[derive(Debug)]
+struct Exchange {
+    prompt: String,
+    replies: Vec<(PromptId, String)>,
+}
+
+impl ComparePanel {
+    pub fn new(model_ids: &[ModelId]) -> Self {
+        Self {
+            prompt: Default::default(),
+            prompt_field_id: Id::new("compare-prompt-id"),
+            model_names: model_ids
+                .iter()
+                .map(|model_id| model_id.spec().name.to_string())
+                .collect(),
+            exchanges: Vec::new(),
+            pending: Vec::new(),
+            generating: None,
+            error: None,
+            frame_counter: 0,
+            scroll_to_bottom: false,
+            scroll_anchor: ScrollAnchor::default(),
+        }
+    }
+
+    fn send_prompt(&mut self, ctx: &mut AppContext) {
+        let prompt = self.prompt.trim();
+        // Prompts are not sent once the conversation has used its token budget.
+        if let Some(error) = ctx.state.budget_error().filter(|_| !prompt.is_empty()) {
+            self.error = Some(error);
+            return;
+        }
+
+        if !prompt.is_empty() {
+            let replies: Vec<_> = ctx
+                .controller
+                .send_compare_prompt(prompt)
+                .into_iter()
+                .map(|(_, prompt_id)| (prompt_id, String::new()))
+                .collect();
+            // Replies to previous prompts may still be queued or generating.
+            self.pending
+                .extend(replies.iter().map(|(prompt_id, _)| *prompt_id));
+
+            self.exchanges.push(Exchange {
+                prompt: prompt.to_owned(),
+                replies,
+            });
+        }
+
+        self.reset_prompt(&ctx.egui_ctx);
+    }
+
+    fn reset_prompt(&mut self, ctx: &Context) {
+        self.prompt.clear();
+
+        let state = text_edit::TextEditState::default();
+        state.store(ctx, self.prompt_field_id);
+    }
+
+    fn error_window(&mut self, ctx: &Context) {
+        if self.error.is_some() {
+            Window::new("Error")
+                .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.with_layout(Layout::top_down(Align::Center), |ui| {
+                        let msg = self.error.as_ref().unwrap();
+                        ui.label(RichText::new(msg).font(TEXT_FONT));
+                        ui.add_space(ui.spacing().item_spacing.y * 2.5);
+                        if ui.button("Close").clicked() {
+                            self.error = None;
+                        }
+                    });
+                });
+        }
+    }
+}
+
+impl Panel for ComparePanel {
+    fn update(&mut self, ctx: &mut AppContext) {
+        ctx.egui_ctx
+            .send_viewport_cmd(ViewportCommand::Title(model_title(
+                &ctx.egui_ctx,
+                &self.model_names.join(" vs "),
+                ctx.controller.model_config(),
+            )));
+
+        self.frame_counter += 1;
+
+        // Smaller fonts and spacing for narrow windows.
+        let compact = ctx.state.compact_mode.is_compact(&ctx.egui_ctx);
+        let (margin, text_font, reply_spacing) = if compact {
+            (5.0, COMPACT_TEXT_FONT, 1.5)
+        } else {
+            (10.0, TEXT_FONT, 2.5)
+        };
+
+        let egui_ctx = ctx.egui_ctx.clone();
+        let prompt_frame = Frame::none()
+            .fill(ctx.egui_ctx.style().visuals.window_fill)
+            .outer_margin(Margin::same(0.0))
+            .inner_margin(Margin::same(margin));
+
+        // Render prompt panel.
+        TopBottomPanel::bottom("compare_bottom_panel")
+            .show_separator_line(false)
+            .frame(prompt_frame)
+            .show(&egui_ctx, |ui| {
+                Frame::group(ui.style())
+                    .rounding(Rounding::same(ROUNDING))
+                    .fill(ctx.state.ui_mode.fill_color())
+                    .stroke(ctx.state.ui_mode.stroke())
+                    .show(ui, |ui| {
+                        // Keep focus on the prompt unless another field is being edited.
+                        egui_ctx.memory_mut(|m| {
+                            if m.focus().is_none() {
+                                m.request_focus(self.prompt_field_id)
+                            }
+                        });
+
+                        // Override multiline Enter behavior when the prompt has focus, so
+                        // that Enter in the snippets and templates dialogs adds a newline.
+                        let prompt_focused = egui_ctx.memory(|m| m.has_focus(self.prompt_field_id));
+                        if prompt_focused
+                            && ui.input_mut(|i| i.consume_key(Modifiers::NONE, Key::Enter))
+                        {
+                            self.send_prompt(ctx);
+                            self.scroll_to_bottom = true;
+                        }
+
+                        let text = TextEdit::multiline(&mut self.prompt)
+                            .id(self.prompt_field_id)
+                            .cursor_at_end(true)
+                            .font(text_font)
+                            .frame(false)
+                            .margin(Vec2::new(5.0, 5.0))
+                            .desired_rows(1)
+                            .hint_text("Compare models! (Enter to send)");
+
+                        ui.add_sized([ui.available_width(), 10.0], text);
+                    })
+            });
+
+        // Render models names and replies side by side.
+        CentralPanel::default().show(&egui_ctx, |ui| {
+            ui.columns(self.model_names.len(), |columns| {
+                for (column, name) in columns.iter_mut().zip(&self.model_names) {
+                    column.vertical_centered(|ui| {
+                        ui.label(RichText::new(name).font(LABEL_FONT).strong());
+                    });
+                }
+            });
+
+            ui.separator();
+
+            let output = ScrollArea::vertical()
+                .auto_shrink(false)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    let ui_mode = ctx.state.ui_mode;
+                    let layout = ctx.state.history_layout;
+                    let avatars = ctx.state.show_avatars;
+                    let accent = ctx.state.accent_color;
+                    let badges = self
+                        .model_names
+                        .iter()
+                        .map(|name| model_badge(name))
+                        .collect::<Vec<_>>();
+                    for exchange in &self.exchanges {
+                        let r = ui.add(
+                            Bubble::new(&exchange.prompt, BubbleContent::Prompt, ui_mode)
+                                .with_layout(layout)
+                                .with_accent(accent)
+                                .with_avatar(avatars.then_some(USER_BADGE))
+                                .with_compact(compact),
+                        );
+                        if r.clicked() {
+                            ui.ctx().copy_text(exchange.prompt.clone());
+                        }
+
+                        ui.add_space(ui.spacing().item_spacing.y);
+
+                        if exchange.replies.is_empty() {
+                            continue;
+                        }
+
+                        ui.columns(exchange.replies.len(), |columns| {
+                            let replies = exchange.replies.iter().zip(&badges);
+                            for (column, ((prompt_id, reply), badge)) in
+                                columns.iter_mut().zip(replies)
+                            {
+                                if !reply.is_empty() {
+                                    let r = column.add(
+                                        Bubble::new(reply, BubbleContent::Reply, ui_mode)
+                                            .with_layout(layout)
+                                            .with_accent(accent)
+                                            .with_avatar(avatars.then_some(badge))
+                                            .with_compact(compact),
+                                    );
+                                    if r.clicked() {
+                                        column.ctx().copy_text(reply.clone());
+                                    }
+                                } else if self.pending.contains(prompt_id) {
+                                    column.add(
+                                        Bubble::new(
+                                            waiting_dots(
+                                                self.frame_counter,
+                                                self.generating == Some(*prompt_id),
+                                            ),
+                                            BubbleContent::Reply,
+                                            ui_mode,
+                                        )
+                                        .with_layout(layout)
+                                        .with_accent(accent)
+                                        .with_avatar(avatars.then_some(badge))
+                                        .with_compact(compact),
+                                    );
+                                }
+                            }
+                        });
+
+                        ui.add_space(ui.spacing().item_spacing.y * reply_spacing);
+                    }
+
+                    if self.scroll_to_bottom {
+                        ui.scroll_to_cursor(Some(Align::BOTTOM));
+                    }
+                });
+            self.scroll_anchor.update(ui.ctx(), &output);
+
+            ui.allocate_space(ui.available_size());
+        });
+
+        self.error_window(&egui_ctx);
+
+        self.scroll_to_bottom = false;
+    }
+
+    fn handle_input(&mut self, app: &mut AppContext) {
+        if app
+            .egui_ctx
+            .input_mut(|i| i.consume_key(Modifiers::NONE, Key::Escape))
+        {
+            app.controller.stop();
+            if !app.state.keep_prompt_on_stop {
+                self.reset_prompt(&app.egui_ctx);
+            }
+        }
+    }
+
+    fn is_busy(&self, _ctx: &AppContext) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn handle_message(&mut self, _app: &mut AppContext, msg: Message) {
+        match msg {
+            Message::Token(prompt_id, s, _) => {
+                if let Some(reply) = find_reply(&mut self.exchanges, prompt_id) {
+                    reply.push_str(&s);
+                }
+            }
+            Message::Done(prompt_id, _) => {
+                if self.generating == Some(prompt_id) {
+                    self.generating = None;
+                }
+
+                self.pending.retain(|id| *id != prompt_id);
+            }
+            Message::Generating(prompt_id) => self.generating = Some(prompt_id),
+            Message::Error(s) => self.error = Some(s),
+            Message::PromptError(prompt_id, s) => {
+                // Another prompt may already be generating.
+                if self.generating == Some(prompt_id) {
+                    self.generating = None;
+                }
+                self.error = Some(s);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Finds the reply being generated for a prompt id.
+///
+/// Messages are routed by prompt id, the reply may belong to any exchange as prompts
+/// are queued while replies are generated.
+fn find_reply(exchanges: &mut [Exchange], prompt_id: PromptId) -> Option<&mut String> {
+    exchanges
+        .iter_mut()
+        .rev()
+        .flat_map(|exchange| exchange.replies.iter_mut())
+        .find(|(id, _)| *id == prompt_id)
+        .map(|(_, reply)| reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(prompt: &str, ids: &[u32]) -> Exchange {
+        Exchange {
+            prompt: prompt.to_string(),
+            replies: ids
+                .iter()
+                .map(|&id| (PromptId::new(id), String::new()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn find_reply_routes_interleaved_prompt_ids() {
+        let mut exchanges = vec![exchange("first", &[1, 2]), exchange("second", &[3, 4])];
+
+        // Replies to a queued prompt arrive while the previous one is still running.
+        for (id, s) in [(1, "a"), (3, "b"), (2, "c"), (1, "d"), (4, "e"), (3, "f")] {
+            find_reply(&mut exchanges, PromptId::new(id))
+                .unwrap()
+                .push_str(s);
+        }
+
+        let replies = |exchange: &Exchange| {
+            exchange
+                .replies
+                .iter()
+                .map(|(_, reply)| reply.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(replies(&exchanges[0]), ["ad", "c"]);
+        assert_eq!(replies(&exchanges[1]), ["bf", "e"]);
+        assert!(find_reply(&mut exchanges, PromptId::new(5)).is_none());
+    }
+}
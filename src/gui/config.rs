@@ -1,8 +1,8 @@
 use eframe::egui::*;
 
 use crate::{
-    gui::{App, UiMode},
-    models::ModelConfig,
+    gui::{AccentColor, App, CompactMode, HistoryLayout, UiMode, MAX_ALTERNATIVES},
+    models::{env_seed, ChatTemplate, ModelConfig, ReasoningTags, RoleLabels},
 };
 
 impl App {
@@ -15,64 +15,525 @@ impl App {
                 .collapsible(false)
                 .resizable(false)
                 .show(ctx, |ui| {
-                    Grid::new("TextLayoutDemo")
-                        .num_columns(2)
-                        .spacing([20.0, 4.0])
-                        .show(ui, |ui| {
-                            ui.label("Generator mode: ");
-                            ComboBox::from_id_source("gm")
-                                .selected_text(self.ctx.state.model_config.description())
-                                .show_ui(ui, |ui| {
-                                    ui.style_mut().wrap = Some(false);
-                                    ui.set_min_width(60.0);
-                                    ui.selectable_value(
-                                        &mut self.ctx.state.model_config,
-                                        ModelConfig::Careful,
-                                        ModelConfig::Careful.description(),
-                                    );
-                                    ui.selectable_value(
-                                        &mut self.ctx.state.model_config,
-                                        ModelConfig::Creative,
-                                        ModelConfig::Creative.description(),
+                    // Long option lists scroll so the buttons stay on screen.
+                    let max_height = (ctx.screen_rect().height() - 160.0).max(120.0);
+                    ScrollArea::vertical().max_height(max_height).show(ui, |ui| {
+                        Grid::new("TextLayoutDemo")
+                            .num_columns(2)
+                            .spacing([20.0, 4.0])
+                            .show(ui, |ui| {
+                                // The mode is saved for the current model, or as the default
+                                // for models without a mode when no model is loaded.
+                                let model_id = self.active_panel.model_id();
+                                let current_config = self.ctx.state.config_for(model_id);
+                                let mut model_config = current_config;
+                                ui.label("Generator mode: ");
+                                ComboBox::from_id_source("gm")
+                                    .selected_text(model_config.description())
+                                    .show_ui(ui, |ui| {
+                                        ui.style_mut().wrap = Some(false);
+                                        ui.set_min_width(60.0);
+                                        ui.selectable_value(
+                                            &mut model_config,
+                                            ModelConfig::Careful,
+                                            ModelConfig::Careful.description(),
+                                        );
+                                        ui.selectable_value(
+                                            &mut model_config,
+                                            ModelConfig::Creative,
+                                            ModelConfig::Creative.description(),
+                                        );
+                                        ui.selectable_value(
+                                            &mut model_config,
+                                            ModelConfig::Deranged,
+                                            ModelConfig::Deranged.description(),
+                                        );
+                                    })
+                                    .response
+                                    .on_hover_text(if model_id.is_some() {
+                                        "Generator mode of this model"
+                                    } else {
+                                        "Generator mode of models without their own mode"
+                                    });
+                                if model_config != current_config {
+                                    self.ctx.state.set_config_for(model_id, model_config);
+                                }
+                                ui.end_row();
+
+                                ui.label("Ui mode: ");
+                                ComboBox::from_id_source("um")
+                                    .selected_text(self.ctx.state.ui_mode.description())
+                                    .show_ui(ui, |ui| {
+                                        ui.style_mut().wrap = Some(false);
+                                        ui.set_min_width(60.0);
+                                        ui.selectable_value(
+                                            &mut self.ctx.state.ui_mode,
+                                            UiMode::Light,
+                                            UiMode::Light.description(),
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.ctx.state.ui_mode,
+                                            UiMode::Dark,
+                                            UiMode::Dark.description(),
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.ctx.state.ui_mode,
+                                            UiMode::HighContrast,
+                                            UiMode::HighContrast.description(),
+                                        );
+                                    });
+                                ctx.set_visuals(self.ctx.state.ui_mode.visuals());
+                                ui.end_row();
+
+                                ui.label("Layout: ");
+                                ComboBox::from_id_source("hl")
+                                    .selected_text(self.ctx.state.history_layout.description())
+                                    .show_ui(ui, |ui| {
+                                        ui.style_mut().wrap = Some(false);
+                                        ui.set_min_width(60.0);
+                                        ui.selectable_value(
+                                            &mut self.ctx.state.history_layout,
+                                            HistoryLayout::Bubbles,
+                                            HistoryLayout::Bubbles.description(),
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.ctx.state.history_layout,
+                                            HistoryLayout::Document,
+                                            HistoryLayout::Document.description(),
+                                        );
+                                    });
+                                ui.end_row();
+
+                                ui.label("Compact: ");
+                                ComboBox::from_id_source("cm")
+                                    .selected_text(self.ctx.state.compact_mode.description())
+                                    .show_ui(ui, |ui| {
+                                        ui.style_mut().wrap = Some(false);
+                                        ui.set_min_width(60.0);
+                                        for mode in
+                                            [CompactMode::Auto, CompactMode::Always, CompactMode::Never]
+                                        {
+                                            ui.selectable_value(
+                                                &mut self.ctx.state.compact_mode,
+                                                mode,
+                                                mode.description(),
+                                            );
+                                        }
+                                    })
+                                    .response
+                                    .on_hover_text(
+                                        "Smaller fonts and padding, Auto for narrow windows",
                                     );
-                                    ui.selectable_value(
-                                        &mut self.ctx.state.model_config,
-                                        ModelConfig::Deranged,
-                                        ModelConfig::Deranged.description(),
+                                ui.end_row();
+
+                                ui.label("Chat template: ");
+                                let chat_template = &mut self.ctx.state.model_options.chat_template;
+                                ui.horizontal(|ui| {
+                                    ComboBox::from_id_source("ct")
+                                        .selected_text(chat_template.description())
+                                        .show_ui(ui, |ui| {
+                                            ui.style_mut().wrap = Some(false);
+                                            ui.set_min_width(60.0);
+                                            ui.selectable_value(
+                                                chat_template,
+                                                ChatTemplate::Model,
+                                                ChatTemplate::Model.description(),
+                                            );
+                                            ui.selectable_value(
+                                                chat_template,
+                                                ChatTemplate::ChatMl,
+                                                ChatTemplate::ChatMl.description(),
+                                            );
+                                        });
+
+                                    // Models without the ChatML tags tokenize them as text.
+                                    let no_chat_ml = self
+                                        .ctx
+                                        .controller
+                                        .capabilities()
+                                        .is_some_and(|capabilities| !capabilities.chat_ml);
+                                    if *chat_template == ChatTemplate::ChatMl && no_chat_ml {
+                                        ui.colored_label(
+                                            ui.visuals().warn_fg_color,
+                                            "Model has no ChatML tokens",
+                                        );
+                                    }
+                                });
+                                ui.end_row();
+
+                                ui.label("Accent color: ");
+                                ui.horizontal(|ui| {
+                                    let accent = &mut self.ctx.state.accent_color;
+                                    ComboBox::from_id_source("ac")
+                                        .selected_text(accent.description())
+                                        .show_ui(ui, |ui| {
+                                            ui.style_mut().wrap = Some(false);
+                                            ui.set_min_width(60.0);
+                                            for (name, preset) in AccentColor::PRESETS {
+                                                ui.selectable_value(accent, preset, name);
+                                            }
+                                        });
+                                    ui.color_edit_button_srgb(&mut accent.0)
+                                        .on_hover_text("Pick a custom color");
+                                });
+                                ui.end_row();
+
+                                ui.label("Avatars: ");
+                                ui.checkbox(&mut self.ctx.state.show_avatars, "")
+                                    .on_hover_text("Show role icons beside the bubbles");
+                                ui.end_row();
+
+                                ui.label("JSON output: ");
+                                ui.checkbox(&mut self.ctx.state.model_options.json_output, "")
+                                    .on_hover_text("Constrain replies to a JSON object");
+                                ui.end_row();
+
+                                ui.label("Stop at newlines: ");
+                                ui.add(
+                                    DragValue::new(&mut self.ctx.state.model_options.stop_newlines)
+                                        .clamp_range(0..=10),
+                                )
+                                .on_hover_text("Stop after this many consecutive newlines, 0 is off");
+                                ui.end_row();
+
+                                ui.label("Stop regex: ");
+                                ui.add(
+                                    TextEdit::singleline(&mut self.ctx.state.model_options.stop_regex)
+                                        .hint_text("regex")
+                                        .desired_width(160.0),
+                                )
+                                .on_hover_text("Stop when the reply matches this regex, empty is off");
+                                ui.end_row();
+
+                                ui.label("Min reply tokens: ");
+                                ui.add(
+                                    DragValue::new(&mut self.ctx.state.model_options.min_tokens)
+                                        .clamp_range(0..=1024),
+                                )
+                                .on_hover_text("Replies can't end before this many tokens, 0 is off");
+                                ui.end_row();
+
+                                ui.label("Load timeout: ");
+                                ui.add(
+                                    DragValue::new(&mut self.ctx.state.model_options.load_timeout_secs)
+                                        .clamp_range(30..=3600)
+                                        .suffix("s"),
+                                )
+                                .on_hover_text("Give up loading a model after this many seconds");
+                                ui.end_row();
+
+                                ui.label("History size: ");
+                                ui.add(
+                                    DragValue::new(&mut self.ctx.state.max_history)
+                                        .clamp_range(0..=100_000),
+                                )
+                                .on_hover_text(
+                                    "Oldest exchanges over this limit are discarded, 0 is unlimited",
+                                );
+                                ui.end_row();
+
+                                ui.label("Collapse replies: ");
+                                ui.add(
+                                    DragValue::new(&mut self.ctx.state.collapse_lines)
+                                        .clamp_range(0..=1000)
+                                        .suffix(" lines"),
+                                )
+                                .on_hover_text("Collapse replies longer than this, 0 is off");
+                                ui.end_row();
+
+                                ui.label("Alternatives: ");
+                                ui.add(
+                                    DragValue::new(&mut self.ctx.state.alternatives)
+                                        .clamp_range(1..=MAX_ALTERNATIVES)
+                                        .suffix(" replies"),
+                                )
+                                .on_hover_text("Generate replies with different seeds to pick one");
+                                ui.end_row();
+
+                                ui.label("Token budget: ");
+                                ui.add(
+                                    DragValue::new(&mut self.ctx.state.token_budget)
+                                        .clamp_range(0..=10_000_000)
+                                        .speed(100)
+                                        .suffix(" tokens"),
+                                )
+                                .on_hover_text("Tokens generated in a conversation, 0 is unlimited");
+                                ui.end_row();
+
+                                let options = &mut self.ctx.state.model_options;
+                                for (name, text, hint) in [
+                                    (
+                                        "Prompt prefix: ",
+                                        &mut options.prompt_prefix,
+                                        "Added before prompts",
+                                    ),
+                                    (
+                                        "Prompt suffix: ",
+                                        &mut options.prompt_suffix,
+                                        "Added after prompts",
+                                    ),
+                                    (
+                                        "Reply starts with: ",
+                                        &mut options.reply_prefix,
+                                        "Text the model continues",
+                                    ),
+                                ] {
+                                    ui.label(name);
+                                    ui.add(
+                                        TextEdit::singleline(text)
+                                            .hint_text(hint)
+                                            .desired_width(160.0),
                                     );
+                                    ui.end_row();
+                                }
+
+                                let seed = &mut self.ctx.state.model_options.seed;
+                                let mut fixed_seed = seed.is_some();
+                                ui.label("Seed: ");
+                                ui.horizontal(|ui| {
+                                    // The environment seed overrides the one set here.
+                                    if let Some(env_seed) = env_seed() {
+                                        ui.label(format!("{env_seed} (COZE_SEED)"));
+                                        return;
+                                    }
+
+                                    ui.checkbox(&mut fixed_seed, "Fixed")
+                                        .on_hover_text("Use the same seed for every prompt");
+                                    if let Some(seed) = seed {
+                                        ui.add(DragValue::new(seed));
+                                    }
                                 });
-                            ui.end_row();
-
-                            ui.label("Ui mode: ");
-                            ComboBox::from_id_source("um")
-                                .selected_text(self.ctx.state.ui_mode.description())
-                                .show_ui(ui, |ui| {
-                                    ui.style_mut().wrap = Some(false);
-                                    ui.set_min_width(60.0);
-                                    ui.selectable_value(
-                                        &mut self.ctx.state.ui_mode,
-                                        UiMode::Light,
-                                        UiMode::Light.description(),
+                                ui.end_row();
+
+                                if fixed_seed != seed.is_some() {
+                                    *seed = fixed_seed.then_some(0);
+                                }
+
+                                ui.label("Keep context: ");
+                                ui.checkbox(&mut self.ctx.state.model_options.keep_context, "")
+                                    .on_hover_text("Replies see the previous prompts and replies");
+                                ui.end_row();
+
+                                ui.label("KV cache tokens: ");
+                                ui.add(
+                                    DragValue::new(&mut self.ctx.state.model_options.kv_cache_tokens)
+                                        .clamp_range(0..=32768),
+                                )
+                                .on_hover_text(
+                                    "Older tokens are dropped past this size, 0 is the model context",
+                                );
+                                ui.end_row();
+
+                                ui.label("Penalize context: ");
+                                ui.checkbox(&mut self.ctx.state.model_options.penalize_context, "")
+                                    .on_hover_text("The repeat penalty includes the previous turns");
+                                ui.end_row();
+
+                                ui.label("Draft then refine: ");
+                                ui.checkbox(&mut self.ctx.state.model_options.refine, "")
+                                    .on_hover_text(
+                                    "Advanced: draft a reply first and then refine it, twice as slow",
+                                );
+                                ui.end_row();
+
+                                ui.label("Low priority: ");
+                                ui.checkbox(&mut self.ctx.state.model_options.low_priority, "")
+                                    .on_hover_text(
+                                        "Generate replies at a lower priority than other apps",
                                     );
-                                    ui.selectable_value(
-                                        &mut self.ctx.state.ui_mode,
-                                        UiMode::Dark,
-                                        UiMode::Dark.description(),
+                                ui.end_row();
+
+                                ui.label("Decoder layers: ");
+                                ui.add(
+                                    DragValue::new(&mut self.ctx.state.model_options.decoder_layers)
+                                        .clamp_range(0..=64),
+                                )
+                                .on_hover_text(
+                                    "Experimental: StableLM runs only this many layers, 0 is all",
+                                );
+                                ui.end_row();
+
+                                ui.label("Sticky prompt: ");
+                                ui.checkbox(&mut self.ctx.state.sticky_prompt, "")
+                                    .on_hover_text("Show the prompt of the reply at the top");
+                                ui.end_row();
+
+                                ui.label("Check model updates: ");
+                                ui.checkbox(&mut self.ctx.state.check_model_updates, "")
+                                    .on_hover_text(
+                                        "Check Hugging Face for newer weights of the cached models",
                                     );
-                                });
-                            ctx.set_visuals(self.ctx.state.ui_mode.visuals());
-                            ui.end_row();
-                        });
+                                ui.end_row();
+
+                                ui.label("Keep recent models: ");
+                                ui.checkbox(&mut self.ctx.state.model_options.keep_models, "")
+                                    .on_hover_text("Keep recently used models in memory if RAM allows");
+                                ui.end_row();
+
+                                ui.label("Notify when done: ");
+                                ui.checkbox(&mut self.ctx.state.notify_done, "")
+                                    .on_hover_text(
+                                        "Flash the window when a reply completes in the background",
+                                    );
+                                ui.end_row();
+
+                                ui.label("Keep prompt on stop: ");
+                                ui.checkbox(&mut self.ctx.state.keep_prompt_on_stop, "")
+                                    .on_hover_text(
+                                        "Escape stops the replies without clearing the prompt",
+                                    );
+                                ui.end_row();
+
+                                ui.label("On completion run: ");
+                                ui.add(
+                                    TextEdit::singleline(
+                                        &mut self.ctx.state.model_options.completion_command,
+                                    )
+                                    .hint_text("command and args")
+                                    .desired_width(160.0),
+                                )
+                                .on_hover_text("Command run with the reply on stdin, empty is off");
+                                ui.end_row();
+
+                                ui.label("Token confidence: ");
+                                ui.checkbox(&mut self.ctx.state.show_token_probs, "")
+                                    .on_hover_text("Color replies by the tokens probability");
+                                ui.end_row();
+
+                                ui.label("Token latency: ");
+                                ui.checkbox(&mut self.ctx.state.show_latency, "")
+                                    .on_hover_text("Show the time between reply tokens");
+                                ui.end_row();
+
+                                ui.label("Memory usage: ");
+                                ui.checkbox(&mut self.ctx.state.show_memory, "")
+                                    .on_hover_text("Show the current and peak memory of the process");
+                                ui.end_row();
+
+                                ui.label("Show template: ");
+                                ui.checkbox(&mut self.ctx.state.show_template, "")
+                                    .on_hover_text("Show the templated prompt given to the model");
+                                ui.end_row();
+
+                                let role_labels = &mut self.ctx.state.model_options.role_labels;
+                                let mut custom_labels = role_labels.is_some();
+                                ui.label("Role labels: ");
+                                ui.checkbox(&mut custom_labels, "Custom")
+                                    .on_hover_text("Override the model template role labels");
+                                ui.end_row();
+
+                                if custom_labels != role_labels.is_some() {
+                                    *role_labels = custom_labels.then(RoleLabels::default);
+                                }
+
+                                if let Some(labels) = role_labels {
+                                    for (name, label) in [
+                                        ("  System: ", &mut labels.system),
+                                        ("  User: ", &mut labels.user),
+                                        ("  Assistant: ", &mut labels.assistant),
+                                    ] {
+                                        ui.label(name);
+                                        ui.add(TextEdit::singleline(label).desired_width(120.0));
+                                        ui.end_row();
+                                    }
+                                }
+
+                                let reasoning_tags = &mut self.ctx.state.model_options.reasoning_tags;
+                                let mut show_reasoning = reasoning_tags.is_some();
+                                ui.label("Reasoning tags: ");
+                                ui.checkbox(&mut show_reasoning, "Separate").on_hover_text(
+                                    "Show the text between these tags apart from the reply",
+                                );
+                                ui.end_row();
+
+                                if show_reasoning != reasoning_tags.is_some() {
+                                    *reasoning_tags = show_reasoning.then(ReasoningTags::default);
+                                }
+
+                                if let Some(tags) = reasoning_tags {
+                                    for (name, tag) in
+                                        [("  Open: ", &mut tags.open), ("  Close: ", &mut tags.close)]
+                                    {
+                                        ui.label(name);
+                                        ui.add(TextEdit::singleline(tag).desired_width(120.0));
+                                        ui.end_row();
+                                    }
+                                }
+                            });
+                    });
+
+                    if let Some(error) = &self.config_error {
+                        ui.colored_label(ui.visuals().error_fg_color, error);
+                    }
 
                     ui.separator();
 
                     ui.vertical_centered(|ui| {
-                        if ui.button("Close").clicked() {
-                            self.ctx.controller.set_config(self.ctx.state.model_config);
-                            self.show_config = false;
+                        let pending = self
+                            .ctx
+                            .state
+                            .history
+                            .iter()
+                            .any(|prompt| prompt.prompt_id.is_some());
+                        let regenerate = ui
+                            .add_enabled(pending, Button::new("Apply and regenerate"))
+                            .on_hover_text("Stop the current reply and generate it again")
+                            .clicked();
+
+                        if ui.button("Close").clicked() || regenerate {
+                            let options = &self.ctx.state.model_options;
+                            self.config_error = options
+                                .role_labels
+                                .as_ref()
+                                .and_then(|labels| labels.validate().err())
+                                .or_else(|| {
+                                    options
+                                        .reasoning_tags
+                                        .as_ref()
+                                        .and_then(|tags| tags.validate().err())
+                                })
+                                .or_else(|| options.stop_regex().err())
+                                .map(|e| e.to_string());
+
+                            if self.config_error.is_none() {
+                                let model_id = self.active_panel.model_id();
+                                let model_config = self.ctx.state.config_for(model_id);
+                                self.ctx.controller.set_config(model_config);
+                                self.ctx.controller.set_options(options.clone());
+                                if regenerate {
+                                    self.regenerate_pending();
+                                }
+                                self.show_config = false;
+                            }
                         }
                     });
                 });
         }
     }
+
+    /// Stops the replies being generated and sends their prompts again so that they
+    /// use the new settings.
+    fn regenerate_pending(&mut self) {
+        self.ctx.controller.stop();
+
+        let history = &mut self.ctx.state.history;
+        for prompt in history.iter_mut().filter(|p| p.prompt_id.is_some()) {
+            // Prompts are truncated as they were when first sent.
+            let prompt_id = self
+                .ctx
+                .controller
+                .send_prompt(&prompt.prompt, prompt.truncate);
+            prompt.prompt_id = Some(prompt_id);
+            prompt.reply.clear();
+            prompt.draft.clear();
+            prompt.reasoning.clear();
+            prompt.template.clear();
+            prompt.token_probs.clear();
+            prompt.expanded = false;
+
+            // The new reply reports its own seed and stop reason.
+            prompt.seed = None;
+            prompt.stopped = None;
+        }
+    }
 }
@@ -0,0 +1,95 @@
+use eframe::egui::*;
+use std::path::PathBuf;
+
+use crate::{
+    gui::{load_panel::LoadPanel, App},
+    models::{self, ModelId},
+};
+
+/// A GGUF file dropped on the window.
+#[derive(Debug)]
+pub struct DroppedFile {
+    path: PathBuf,
+    model_id: ModelId,
+    error: Option<String>,
+}
+
+impl App {
+    /// Checks for GGUF files dropped on the window.
+    pub fn handle_dropped_files(&mut self, ctx: &Context) {
+        let path = ctx.input(|i| i.raw.dropped_files.iter().find_map(|f| f.path.clone()));
+        if let Some(path) = path {
+            let error = models::check_gguf(&path).err().map(|e| e.to_string());
+            self.dropped_file = Some(DroppedFile {
+                path,
+                model_id: ModelId::models()[0],
+                error,
+            });
+        }
+    }
+
+    pub fn dropped_file_window(&mut self, ctx: &Context) {
+        let Some(dropped) = &mut self.dropped_file else {
+            return;
+        };
+
+        let mut close = false;
+        let mut load = false;
+
+        Window::new("Load model file")
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(dropped.path.display().to_string());
+                ui.add_space(ui.spacing().item_spacing.y);
+
+                if let Some(error) = &dropped.error {
+                    ui.label(RichText::new(error).color(Color32::LIGHT_RED));
+                } else {
+                    ui.horizontal(|ui| {
+                        ui.label("Architecture: ");
+                        ComboBox::from_id_source("dropped_model_id")
+                            .selected_text(dropped.model_id.spec().name)
+                            .show_ui(ui, |ui| {
+                                for model_id in ModelId::models() {
+                                    ui.selectable_value(
+                                        &mut dropped.model_id,
+                                        model_id,
+                                        model_id.spec().name,
+                                    );
+                                }
+                            });
+                    });
+
+                    ui.label("The tokenizer is downloaded for the selected model if needed.");
+                }
+
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    if dropped.error.is_none() && ui.button("Load").clicked() {
+                        load = true;
+                    }
+
+                    if ui.button("Cancel").clicked() {
+                        close = true;
+                    }
+                });
+            });
+
+        if load {
+            if let Some(dropped) = self.dropped_file.take() {
+                self.ctx.controller.stop();
+                self.ctx.state.clear_pending();
+                self.active_panel = Box::new(LoadPanel::from_file(
+                    dropped.model_id,
+                    dropped.path,
+                    &mut self.ctx,
+                ));
+            }
+        } else if close {
+            self.dropped_file = None;
+        }
+    }
+}
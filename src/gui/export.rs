@@ -0,0 +1,153 @@
+use chrono::prelude::*;
+use eframe::egui::*;
+use serde::Serialize;
+
+use crate::gui::{
+    bubble::{Bubble, BubbleContent},
+    AccentColor, App, HistoryLayout, Prompt, UiMode,
+};
+
+/// A message in the OpenAI chat completions format.
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'static str,
+    content: &'a str,
+}
+
+/// Serializes the history as an OpenAI chat `messages` array.
+///
+/// Replies that are still being generated are left out.
+pub fn openai_messages(history: &[Prompt]) -> String {
+    let messages = history
+        .iter()
+        .flat_map(|prompt| {
+            let user = ChatMessage {
+                role: "user",
+                content: &prompt.prompt,
+            };
+            let assistant =
+                (prompt.prompt_id.is_none() && !prompt.reply.is_empty()).then(|| ChatMessage {
+                    role: "assistant",
+                    content: &prompt.reply,
+                });
+            std::iter::once(user).chain(assistant)
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::to_string_pretty(&messages).unwrap_or_default()
+}
+
+impl App {
+    /// Saves the history as a standalone HTML page styled with the current theme.
+    pub fn export_html(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("HTML", &["html"])
+            .set_file_name("coze.html")
+            .save_file()
+        else {
+            return;
+        };
+
+        let state = &self.ctx.state;
+        let html = history_html(&state.history, state.ui_mode, state.accent_color);
+        if let Err(e) = std::fs::write(&path, html) {
+            let error = format!("Unable to write {}: {e}", path.display());
+            self.file_error = Some(("Export error", error));
+        }
+    }
+}
+
+/// Renders the history as an HTML page with embedded CSS.
+///
+/// Each prompt shows its info with the model name and time, fenced code blocks in
+/// the text become `pre` blocks. Replies that are still being generated are left out.
+pub fn history_html(history: &[Prompt], ui_mode: UiMode, accent: AccentColor) -> String {
+    let layout = HistoryLayout::Bubbles;
+    let prompt = BubbleContent::Prompt;
+    let reply = BubbleContent::Reply;
+    let style = format!(
+        "body {{ background: {}; font-family: monospace; font-size: 15px; margin: 2em auto; \
+         max-width: 60em; }}\n\
+         .bubble {{ border-radius: 8px; padding: 10px; margin: 8px 0; }}\n\
+         .prompt {{ background: {}; color: {}; margin-left: 10%; }}\n\
+         .reply {{ background: {}; color: {}; margin-right: 10%; }}\n\
+         .text {{ white-space: pre-wrap; overflow-wrap: anywhere; }}\n\
+         .info {{ font-size: 10px; opacity: 0.7; margin-top: 6px; }}\n\
+         .header {{ color: {}; font-size: 12px; }}\n\
+         pre {{ background: rgba(0, 0, 0, 0.15); border-radius: 4px; padding: 8px; \
+         overflow-x: auto; }}",
+        css_color(ui_mode.visuals().panel_fill),
+        css_color(Bubble::fill_color(&prompt, ui_mode, layout, accent)),
+        css_color(Bubble::text_color(&prompt, ui_mode, layout, accent)),
+        css_color(Bubble::fill_color(&reply, ui_mode, layout, accent)),
+        css_color(Bubble::text_color(&reply, ui_mode, layout, accent)),
+        css_color(ui_mode.visuals().weak_text_color()),
+    );
+
+    let mut body = format!(
+        "<div class=\"header\">Exported from coze - {}</div>\n",
+        Local::now().format("%F %T")
+    );
+    for prompt in history {
+        body.push_str(&format!(
+            "<div class=\"bubble prompt\">{}<div class=\"info\">{}</div></div>\n",
+            text_html(&prompt.prompt),
+            escape_html(&prompt.footer())
+        ));
+
+        if prompt.prompt_id.is_none() && !prompt.reply.is_empty() {
+            body.push_str(&format!(
+                "<div class=\"bubble reply\">{}</div>\n",
+                text_html(&prompt.reply)
+            ));
+        }
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Coze chat</title>\n<style>\n{style}\n</style>\n</head>\n\
+         <body>\n{body}</body>\n</html>\n"
+    )
+}
+
+/// Converts a message text to HTML, fenced code blocks become `pre` blocks without
+/// their language tag and the rest keeps its line breaks.
+fn text_html(text: &str) -> String {
+    let mut html = String::new();
+    for (idx, part) in text.split("```").enumerate() {
+        if idx % 2 == 0 {
+            let part = part.trim_matches('\n');
+            if !part.is_empty() {
+                html.push_str(&format!("<div class=\"text\">{}</div>", escape_html(part)));
+            }
+        } else {
+            let code = part.split_once('\n').map_or("", |(_, code)| code);
+            let code = code.trim_end_matches('\n');
+            html.push_str(&format!("<pre><code>{}</code></pre>", escape_html(code)));
+        }
+    }
+
+    html
+}
+
+/// Escapes the HTML special characters.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Formats a color as a CSS hex color.
+fn css_color(color: Color32) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r(), color.g(), color.b())
+}
@@ -1,5 +1,7 @@
 use eframe::{egui::*, epaint::*};
 
+use super::AccentColor;
+
 const ASPECT: f32 = 1.5;
 
 pub struct Gauge {
@@ -14,7 +16,7 @@ impl Gauge {
         Self {
             progress,
             min_size: Vec2::new(64.0, 64.0 / ASPECT),
-            color: Color32::from_rgb(15, 85, 235),
+            color: AccentColor::default().color(),
             font_size: 48.0,
         }
     }
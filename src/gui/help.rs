@@ -1,28 +1,223 @@
 use super::*;
+use crate::models::ModelsCache;
 
 const TEXT_FONT: FontId = FontId::new(15.0, FontFamily::Monospace);
 const HELP_TEXT: &str = "# Prompt field
 
 Enter a prompt and press return to generate reply tokens. The prompts appear as
-blue bubbles in the history area while the replies as gray bubbles.
+blue bubbles in the history area while the replies as gray bubbles. Press
+Shift+Enter to add a new line, the prompt field grows with its text up to 40% of
+the window height and then scrolls.
 
-Press Escape at any time to stop the replies generation and clear the prompt field.
+Press Escape at any time to stop the replies generation, discard queued prompts,
+and clear the prompt field, enable `Keep prompt on stop` in the config to keep the
+prompt text. Press Ctrl+L (Cmd+L on macOS) to only clear the prompt field.
+
+Press Ctrl+D (Cmd+D on macOS) to switch between light and dark mode, the new mode
+is briefly shown at the top of the window and saved like the config.
+
+Prompts sent while a reply is being generated are queued and answered in order.
+
+When the history is empty a few example prompts are shown, click on one to copy it
+to the prompt field and press return to send it.
 
 Click on any bubble to copy its text to the clipboard, double click on a prompt
-bubble to copy its text to the prompt field.
+bubble to copy its text to the prompt field. Double click on a reply bubble to edit
+it and click `Save` to keep the changes, edited replies are marked as `Edited` and
+the edited text is the one copied and exported.
+
+Right click on a prompt bubble and choose `Show tokens` to see the token ids and the
+text of each token for the prompt formatted with the model template. Enable `Show
+template` in the config to see, under each prompt sent in this session, the exact
+text given to the model with the template and the special tokens, this helps to
+find prompt formatting mistakes.
 
 Use the up and down arrows to navigate the prompt history, if the prompt field
 contains some text it is used to filter the history using fuzzy matching.
 
+Click the 📎 button to insert the contents of a text file at the cursor as a code
+block, a warning is shown if the file takes a large part of the model context.
+
+The status bar above the prompt field shows the model name, the device it runs on,
+the generator mode, and how many of the model context tokens are in use after the
+last reply. Click the generator mode to change it, each model remembers its own mode
+and models without one use the mode set in the config from the models list. Hover on
+the device to see the CPU instructions detected and if the build uses their fast
+path, the models list shows a warning when the build and the CPU don't match.
+
+Type `/name` at the start of the prompt field and press Tab to replace it with the
+text of the snippet with the given name.
+
 # Edit menu
 
 The `Config` menu item shows a dialog with two combo boxes, one for choosing the
-token generation randomness and the other for choosing the UI light mode.
+token generation randomness and the other for choosing the UI mode (light, dark, or
+high contrast). The `Layout` combo box switches between chat bubbles and a full
+width document view with role labels. The `Accent color` sets the color of the
+prompt bubbles, the loading gauge, and informational text, pick one of the presets
+or a custom color, the prompt text switches to a dark color on light accents. The
+`Avatars` checkbox shows a small icon beside each bubble, the reply icon has the
+first letter of the model name. The `Chat template` combo box formats prompts with
+the ChatML `<|im_start|>`/`<|im_end|>` tags instead of the model template, replies
+stop at `<|im_end|>` if the model vocabulary has it, a warning is shown beside the
+combo box when the loaded model doesn't. The `JSON output` checkbox constrains the
+model replies to a valid JSON object. `Stop at newlines` stops a reply after the
+given number of consecutive newlines (0 disables it). `Stop regex` stops a reply
+when the text generated so far matches the given regex and drops the match, text
+that was already shown before the match completed is kept (empty disables it).
+`Min reply tokens` keeps the model from ending a reply before it has generated the
+given number of tokens (0 disables it). `Load timeout` sets how many seconds to wait
+for a model to load before giving up and returning to the models list. The
+`Role labels` custom option overrides the system, user, and assistant tags used by
+the model prompt template, this is useful to experiment with fine-tunes that expect
+different tags.
+
+`Compact` uses smaller fonts, padding, and spacing so that more text fits in small
+windows, `Auto` switches to it when the window is narrower than 500 points.
+
+`Collapse replies` shows only the first lines of longer replies with a `Show more`
+button to expand them (0 disables it), clicking a collapsed reply still copies all
+of its text.
+
+`Prompt prefix` and `Prompt suffix` are added before and after every prompt inside
+the model template, for example a prefix like `Answer concisely: ` nudges the
+replies without editing the prompts. Leave them empty to disable them.
+
+`Reply starts with` is added after the assistant turn opener in the template and at
+the start of every reply, the model continues from it. A value like `Sure,` or the
+start of a code block steers the reply format, leave it empty to disable it.
+
+`Seed` sets the seed used to sample the reply tokens in the creative and deranged
+modes, when it is not fixed each prompt uses a new random seed. The seed of a reply
+is shown below its prompt, right click on the prompt bubble and choose `Copy seed`
+to copy it, then set it as the fixed seed to generate the same reply again. Setting
+the `COZE_SEED` environment variable fixes the seed of every reply and takes
+precedence over this option, useful for automated tests. It only makes the sampling
+reproducible, replies may still differ across machines.
+
+`Token budget` limits the number of tokens generated in a conversation, once it is
+used new prompts are not sent until the budget is reset. The tokens left are shown
+in the status bar, click them to reset the budget, clearing the history also resets
+it. Set it to 0 for no limit.
+
+`Alternatives` generates up to 4 replies to each prompt one after the other, each
+with a different seed, click the one to keep and the others are discarded. Sending
+a new prompt before picking keeps the first one. This is most useful in the
+creative and deranged modes as careful replies are all the same.
+
+`Sticky prompt` shows the first line of the prompt at the top of the history while
+scrolling through a reply that is longer than the view.
+
+`Keep context` lets the model see the previous prompts and replies, instead of
+starting from an empty context for each prompt the new prompt continues after the
+previous turns, which is also faster as they are not processed again. When the
+conversation no longer fits the model context it starts again from the new prompt,
+clearing or importing the history also starts a new conversation. Alternative
+replies always start from an empty context.
+
+`KV cache tokens` caps how many tokens the model keeps in memory for the context,
+past this size the oldest tokens are dropped so that long conversations don't run
+out of memory (0 uses the model context length). Mistral 7B v0.1 runs on the
+candle model that doesn't support it.
+
+`Penalize context` makes the repeat penalty also look at the tokens of the previous
+turns kept in the model context, so that replies don't repeat the earlier ones. The
+penalty still only looks at the last tokens set by the generator mode, 64 for
+careful and creative and 128 for deranged.
+
+`Draft then refine` is an advanced option that generates each reply in two passes,
+the model first writes a draft in careful mode and is then asked to fix and improve
+it. Only the refined reply is shown, click `Draft` above it to see the draft.
+Replies take about twice as long and the draft plus the prompt must fit the model
+context.
+
+`Reasoning tags` is for models that think before answering, the text between the
+tags, like `<think>` and `</think>`, is collapsed under `Reasoning` above the reply
+and is not part of the reply that is copied or exported.
+
+`Low priority` runs replies generation at the lowest thread priority so that other
+apps stay responsive, replies are slower when other apps are busy. Turning it off
+takes effect after a restart.
+
+`Decoder layers` is an experimental option that makes StableLM faster by running
+only the first layers of the model before picking the next token, 0 runs all of
+them. The replies get worse as fewer layers are used, and the other models ignore
+it.
+
+`Keep recent models` keeps the last two models in memory when switching to another
+model so that going back to them is instant, the oldest are released if there is
+not enough memory for a new model.
+
+`Check model updates` asks Hugging Face if the weights of the cached models have
+changed when the models list is shown, updated models get an `Update available`
+badge and a `Download Update` button that downloads and loads the new weights. It
+is off by default to avoid network requests at startup, models that cannot be
+checked, for example when offline, are shown as up to date.
+
+`Notify when done` flashes the window in the taskbar or bounces the dock icon when
+a reply completes while Coze is in the background, use `On completion run` with a
+command that plays a sound for an audible notification.
+
+`On completion run` is a command that is run when a reply completes with the reply
+text piped to its standard input, the arguments are split on spaces and the command
+is run without a shell. Leave it empty to disable it.
+
+`Token confidence` colors the replies text from green to red as the probability of
+the generated tokens drops, this shows where the model was uncertain. Replies
+restored from a previous session don't have the probabilities.
+
+`History size` sets the maximum number of exchanges kept in the history, when a new
+prompt exceeds it the oldest exchanges are discarded (0 keeps all of them).
+
+While a reply is generated the `Apply and regenerate` button in the Config dialog
+stops it and generates it again with the new settings, replies that are queued also
+use them. The `Close` button applies the settings from the next prompt.
+
+The `Snippets` menu item shows a dialog to add, edit, and delete named prompt
+snippets.
+
+The `Templates` menu item shows a dialog to add, edit, and delete named prompt
+templates, use `{{name}}` placeholders for the parts that change. Click the 🗐
+button next to the prompt field to choose a template, a form asks for the value of
+each placeholder before the filled template replaces the prompt.
+
+The `Copy as OpenAI messages` menu item copies the history to the clipboard as a
+JSON array of `user` and `assistant` messages in the OpenAI chat API format.
+
+The `Import OpenAI messages` menu item replaces the history with the messages in a
+JSON file in the same format, or an object with a `messages` field. Each user
+message becomes a prompt with the assistant message that follows it as its reply,
+system messages are skipped.
+
+The `Export to HTML` menu item saves the history to a standalone HTML file with the
+colors of the current theme, each prompt shows the model name and the time of its
+reply, and fenced code blocks are shown as code blocks.
 
 The `Clear history` menu item removes all the prompts and replies from the history
 area.
 
-The history and window position is saved using the `egui` storage system.";
+The `Incognito` checkbox stops saving prompts and replies to disk, the history is
+kept in memory for the current session only and the previously saved history is left
+as it was. Incognito mode can also be enabled for a single session by setting the
+`COZE_INCOGNITO` environment variable.
+
+# Model files
+
+Drop a GGUF file on the window to load its weights instead of the downloaded ones,
+choose the model architecture that matches the file and its tokenizer is downloaded
+if needed.
+
+Downloaded models are saved in the cache directory shown at the bottom of this help,
+`~/.cache/coze` by default, set the `COZE_CACHE_DIR` environment variable to use
+another directory. If the home directory cannot be found the cache is in the temp
+directory and downloads may not persist. When Hugging Face rate limits a download
+it is retried after the wait the server asks for, long waits or repeated limits stop
+the download with an error, wait a few minutes and click `Try Reload`.
+
+The history and window position is saved using the `egui` storage system. The
+exchanges added since the history was last saved are also saved to a journal file in
+the cache directory as the replies are generated, if coze doesn't exit cleanly it
+offers to restore them on the next start.";
 
 impl App {
     pub fn help_window(&mut self, ctx: &Context) {
@@ -42,6 +237,11 @@ impl App {
                             render_text(ui);
                         });
 
+                    if let Ok(cache) = ModelsCache::new() {
+                        ui.add_space(ui.spacing().item_spacing.y * 2.0);
+                        ui.label(format!("Cache directory: {}", cache.cache_dir().display()));
+                    }
+
                     ui.vertical_centered(|ui| {
                         ui.add_space(ui.spacing().item_spacing.y * 2.0);
                         if ui.button("Close").clicked() {
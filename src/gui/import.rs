@@ -0,0 +1,128 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::prelude::*;
+use eframe::egui::*;
+use serde_json::Value;
+
+use crate::gui::{App, Prompt};
+
+impl App {
+    /// Replaces the history with the messages in an OpenAI chat JSON file.
+    pub fn import_messages(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("JSON", &["json"])
+            .pick_file()
+        else {
+            return;
+        };
+
+        let history = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Unable to read {}: {e}", path.display()))
+            .and_then(|json| parse_openai_messages(&json));
+
+        match history {
+            Ok(history) => {
+                self.ctx.controller.stop();
+                self.ctx.controller.clear_context();
+                self.ctx.state.history = history;
+            }
+            Err(e) => self.file_error = Some(("Import error", e.to_string())),
+        }
+    }
+
+    /// Shows the error of the last import or export.
+    pub fn file_error_window(&mut self, ctx: &Context) {
+        if let Some((title, error)) = &self.file_error {
+            let mut close = false;
+
+            Window::new(*title)
+                .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.with_layout(Layout::top_down(Align::Center), |ui| {
+                        ui.label(error);
+                        ui.add_space(ui.spacing().item_spacing.y * 2.0);
+                        close = ui.button("Close").clicked();
+                    });
+                });
+
+            if close {
+                self.file_error = None;
+            }
+        }
+    }
+}
+
+/// Parses an OpenAI chat `messages` array, or an object with a `messages` field, into
+/// history prompts.
+///
+/// Each user message starts a new prompt and the assistant message that follows it
+/// is its reply, system messages are skipped.
+pub fn parse_openai_messages(json: &str) -> Result<Vec<Prompt>> {
+    let value: Value = serde_json::from_str(json).map_err(|e| anyhow!("Invalid JSON: {e}"))?;
+    let messages = match &value {
+        Value::Array(messages) => messages,
+        Value::Object(object) => match object.get("messages") {
+            Some(Value::Array(messages)) => messages,
+            _ => bail!("Expected an object with a `messages` array"),
+        },
+        _ => bail!("Expected a `messages` array"),
+    };
+
+    let info = format!("Imported - {}", Local::now().format("%F %T%.3f"));
+    let mut history: Vec<Prompt> = Vec::new();
+    for (idx, message) in messages.iter().enumerate() {
+        let num = idx + 1;
+        let role = message
+            .get("role")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("Message {num} has no `role`"))?;
+        let content = message
+            .get("content")
+            .and_then(message_text)
+            .ok_or_else(|| anyhow!("Message {num} has no text `content`"))?;
+
+        match role {
+            "system" => {}
+            "user" => history.push(Prompt {
+                prompt: content,
+                reply: String::new(),
+                info: info.clone(),
+                draft: String::new(),
+                reasoning: String::new(),
+                template: String::new(),
+                seed: None,
+                edited: false,
+                stopped: None,
+                prompt_id: None,
+                truncate: false,
+                token_probs: Vec::new(),
+                expanded: false,
+            }),
+            "assistant" => match history.last_mut() {
+                Some(prompt) if prompt.reply.is_empty() => prompt.reply = content,
+                _ => bail!("Message {num} is an assistant reply without a user message"),
+            },
+            role => bail!("Message {num} has unsupported role `{role}`"),
+        }
+    }
+
+    if history.is_empty() {
+        bail!("No user messages found");
+    }
+
+    Ok(history)
+}
+
+/// Gets the text of a message content, either a string or an array of text parts.
+fn message_text(content: &Value) -> Option<String> {
+    match content {
+        Value::String(text) => Some(text.clone()),
+        Value::Array(parts) => parts
+            .iter()
+            .map(|part| part.get("text").and_then(Value::as_str))
+            .collect::<Option<Vec<_>>>()
+            .map(|parts| parts.join("\n")),
+        _ => None,
+    }
+}
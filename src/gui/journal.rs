@@ -0,0 +1,96 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use super::Prompt;
+use crate::models::ModelsCache;
+
+const JOURNAL_FILE: &str = "journal.json";
+
+/// Minimum time between journal writes.
+const WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Autosaves the exchanges added since the history was last saved so that they can
+/// be recovered if coze doesn't shutdown cleanly, this is separate from the egui
+/// storage that is only saved periodically.
+#[derive(Debug)]
+pub struct Journal {
+    path: Option<PathBuf>,
+    last_write: Instant,
+    written: Option<Vec<(usize, usize, usize)>>,
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        let path = ModelsCache::new()
+            .ok()
+            .map(|cache| cache.cache_dir().join(JOURNAL_FILE));
+
+        Self {
+            path,
+            last_write: Instant::now(),
+            written: None,
+        }
+    }
+
+    /// Reads the exchanges left by a session that didn't exit cleanly.
+    pub fn recover(&self) -> Option<Vec<Prompt>> {
+        let data = fs::read(self.path.as_ref()?).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Writes the exchanges of the history that are not in the saved history, if they
+    /// have changed since the last write.
+    pub fn update(&mut self, history: &[Prompt], saved_history: &[Prompt]) {
+        if self.last_write.elapsed() < WRITE_INTERVAL {
+            return;
+        }
+
+        let exchanges = unsaved(history, saved_history);
+        let lengths = exchanges
+            .iter()
+            .map(|p| (p.prompt.len(), p.reply.len(), p.info.len()))
+            .collect::<Vec<_>>();
+        if self.written.as_ref() == Some(&lengths) {
+            return;
+        }
+
+        if let (Some(path), Ok(data)) = (&self.path, serde_json::to_vec(exchanges)) {
+            let _ = fs::write(path, data);
+        }
+
+        self.written = Some(lengths);
+        self.last_write = Instant::now();
+    }
+
+    /// Removes the journal file.
+    pub fn clear(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+
+        self.written = None;
+    }
+}
+
+/// Gets the exchanges at the end of the history that differ from the saved history.
+///
+/// The saved history may start with older exchanges that have since been dropped by
+/// the history limit.
+fn unsaved<'a>(history: &'a [Prompt], saved_history: &[Prompt]) -> &'a [Prompt] {
+    let saved = history
+        .first()
+        .and_then(|first| saved_history.iter().position(|p| p == first))
+        .map(|start| {
+            history
+                .iter()
+                .zip(&saved_history[start..])
+                .take_while(|(p, saved)| p == saved)
+                .count()
+        })
+        .unwrap_or(0);
+
+    &history[saved..]
+}
@@ -0,0 +1,71 @@
+use eframe::egui::*;
+use std::time::Instant;
+
+/// Maximum number of latencies kept for a reply.
+const MAX_SAMPLES: usize = 4096;
+
+/// Tracks the time between the tokens of the current reply.
+#[derive(Debug, Default)]
+pub struct TokenLatency {
+    last_token: Option<Instant>,
+    latencies: Vec<f32>,
+}
+
+impl TokenLatency {
+    /// Clears the latencies for a new reply.
+    pub fn reset(&mut self) {
+        self.last_token = None;
+        self.latencies.clear();
+    }
+
+    /// Records the arrival of a new token.
+    pub fn record(&mut self) {
+        let now = Instant::now();
+        if let Some(last_token) = self.last_token {
+            if self.latencies.len() == MAX_SAMPLES {
+                self.latencies.remove(0);
+            }
+
+            self.latencies
+                .push(now.duration_since(last_token).as_secs_f32() * 1000.0);
+        }
+
+        self.last_token = Some(now);
+    }
+
+    /// Shows the min/avg/max latency and a sparkline of the latencies.
+    pub fn ui(&self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            let font = FontId::new(11.0, FontFamily::Monospace);
+            if self.latencies.is_empty() {
+                ui.label(RichText::new("Token latency: -").font(font).weak());
+                return;
+            }
+
+            let min = self.latencies.iter().copied().fold(f32::INFINITY, f32::min);
+            let max = self.latencies.iter().copied().fold(0.0, f32::max);
+            let avg = self.latencies.iter().sum::<f32>() / self.latencies.len() as f32;
+            let text = format!("Token latency min {min:.0}ms avg {avg:.0}ms max {max:.0}ms");
+            ui.label(RichText::new(text).font(font).weak());
+
+            let (rect, _) = ui.allocate_exact_size(Vec2::new(160.0, 16.0), Sense::hover());
+            if ui.is_rect_visible(rect) && max > 0.0 {
+                let dx = rect.width() / (self.latencies.len().max(2) - 1) as f32;
+                let points = self
+                    .latencies
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, latency)| {
+                        Pos2::new(
+                            rect.left() + idx as f32 * dx,
+                            rect.bottom() - rect.height() * latency / max,
+                        )
+                    })
+                    .collect();
+
+                let stroke = Stroke::new(1.0, ui.visuals().weak_text_color());
+                ui.painter().add(Shape::line(points, stroke));
+            }
+        });
+    }
+}
@@ -1,8 +1,12 @@
 use eframe::egui::*;
+use std::path::PathBuf;
 
 use crate::{
     controller::Message,
-    gui::{gauge::Gauge, prompt_panel::PromptPanel, AppContext, Panel},
+    gui::{
+        compare_panel::ComparePanel, gauge::Gauge, model_title, models_panel::ModelsPanel,
+        prompt_panel::PromptPanel, AppContext, Panel,
+    },
     models::ModelId,
 };
 
@@ -13,40 +17,87 @@ const PROGRESS_FONT: FontId = FontId::new(28.0, FontFamily::Monospace);
 pub struct LoadPanel {
     load_pct: f32,
     connecting: bool,
+    retry_secs: Option<u64>,
     download_msg: String,
     error: Option<String>,
-    complete: bool,
+    tokenizer_error: Option<ModelId>,
+    timeout_error: Option<String>,
+    loaded: usize,
     frame_counter: usize,
     model_name: String,
-    model_id: ModelId,
+    model_ids: Vec<ModelId>,
 }
 
 impl LoadPanel {
     pub fn new(model_id: ModelId, ctx: &mut AppContext) -> Self {
+        ctx.controller
+            .set_config(ctx.state.config_for(Some(model_id)));
         ctx.controller.load_model(model_id);
+        Self::with_models(&[model_id])
+    }
+
+    /// Downloads the latest weights of a model and loads it.
+    pub fn update(model_id: ModelId, ctx: &mut AppContext) -> Self {
+        ctx.controller
+            .set_config(ctx.state.config_for(Some(model_id)));
+        ctx.controller.update_model(model_id);
+        Self::with_models(&[model_id])
+    }
+
+    /// Loads a model using the weights in a GGUF file.
+    pub fn from_file(model_id: ModelId, model_path: PathBuf, ctx: &mut AppContext) -> Self {
+        let file_name = model_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        ctx.controller
+            .set_config(ctx.state.config_for(Some(model_id)));
+        ctx.controller.load_model_file(model_id, model_path);
+
+        let mut panel = Self::with_models(&[model_id]);
+        panel.model_name = format!("{} ({file_name})", panel.model_name);
+        panel
+    }
+
+    /// Loads two models to compare their replies.
+    pub fn compare(model_ids: &[ModelId], ctx: &mut AppContext) -> Self {
+        ctx.controller.set_config(ctx.state.model_config);
+        ctx.controller.load_models(model_ids);
+        Self::with_models(model_ids)
+    }
+
+    fn with_models(model_ids: &[ModelId]) -> Self {
+        let model_name = model_ids
+            .iter()
+            .map(|model_id| model_id.spec().name)
+            .collect::<Vec<_>>()
+            .join(" vs ");
 
         Self {
             load_pct: 0.0,
             connecting: false,
+            retry_secs: None,
             download_msg: Default::default(),
             error: None,
-            complete: false,
+            tokenizer_error: None,
+            timeout_error: None,
+            loaded: 0,
             frame_counter: 0,
-            model_name: model_id.spec().name.to_string(),
-            model_id,
+            model_name,
+            model_ids: model_ids.to_vec(),
         }
     }
 }
 
 impl Panel for LoadPanel {
     fn update(&mut self, ctx: &mut AppContext) {
-        const INFO_COLOR: Color32 = Color32::from_rgb(20, 140, 255);
+        let info_color = ctx.state.ui_mode.info_color(ctx.state.accent_color);
 
         ctx.egui_ctx
-            .send_viewport_cmd(ViewportCommand::Title(format!(
-                "{} ({})",
-                self.model_id.spec().name,
-                ctx.controller.model_config().description(),
+            .send_viewport_cmd(ViewportCommand::Title(model_title(
+                &ctx.egui_ctx,
+                &self.model_name,
+                ctx.controller.model_config(),
             )));
 
         self.frame_counter += 1;
@@ -60,11 +111,11 @@ impl Panel for LoadPanel {
 
                 if self.connecting {
                     ui.add_space(ui.spacing().item_spacing.y * 10.0);
-                    ui.label(
-                        RichText::new("Connecting to Hugging Face")
-                            .font(TEXT_FONT)
-                            .color(INFO_COLOR),
-                    );
+                    let text = match self.retry_secs {
+                        Some(secs) => format!("Rate limited, retrying in {secs}s"),
+                        None => "Connecting to Hugging Face".to_string(),
+                    };
+                    ui.label(RichText::new(text).font(TEXT_FONT).color(info_color));
 
                     ui.add_space(ui.spacing().item_spacing.y * 5.0);
 
@@ -73,11 +124,11 @@ impl Panel for LoadPanel {
                     ui.horizontal(|ui| {
                         let w = ui.available_width();
                         ui.add_space(w * 0.1 + w * 0.7 * pos);
-                        ui.label(RichText::new("⏺").font(PROGRESS_FONT).color(INFO_COLOR));
+                        ui.label(RichText::new("⏺").font(PROGRESS_FONT).color(info_color));
                     });
                 } else {
                     let width = ui.available_width() * 0.9;
-                    ui.add(Gauge::new(self.load_pct).color(INFO_COLOR).width(width));
+                    ui.add(Gauge::new(self.load_pct).color(info_color).width(width));
                 }
 
                 if let Some(error) = &self.error {
@@ -103,8 +154,33 @@ impl Panel for LoadPanel {
                     .rounding(4.0);
 
                     if ui.add(button).clicked() {
-                        ctx.controller.reload_weights(self.model_id);
+                        if let [model_id] = self.model_ids[..] {
+                            ctx.controller.reload_weights(model_id);
+                        } else {
+                            ctx.controller.load_models(&self.model_ids);
+                        }
+
+                        self.loaded = 0;
                         self.error = None;
+                        self.tokenizer_error = None;
+                    }
+
+                    // Offer to download only the tokenizer when the weights are fine.
+                    if let Some(model_id) = self.tokenizer_error {
+                        ui.add_space(ui.spacing().item_spacing.y);
+
+                        let button = Button::new(
+                            RichText::new("Download Tokenizer")
+                                .font(FontId::new(14.0, FontFamily::Monospace)),
+                        )
+                        .rounding(4.0);
+
+                        if ui.add(button).clicked() {
+                            ctx.controller.reload_tokenizer(model_id);
+                            self.loaded = 0;
+                            self.error = None;
+                            self.tokenizer_error = None;
+                        }
                     }
                 }
             });
@@ -114,22 +190,44 @@ impl Panel for LoadPanel {
     fn handle_message(&mut self, _ctx: &mut AppContext, msg: Message) {
         match msg {
             Message::DownloadBegin(s) => self.download_msg = s,
-            Message::DownloadConnecting => self.connecting = true,
+            Message::DownloadConnecting => {
+                self.connecting = true;
+                self.retry_secs = None;
+            }
+            Message::DownloadRateLimited(secs) => {
+                self.connecting = true;
+                self.retry_secs = Some(secs);
+            }
             Message::DownloadProgress(pct) => {
                 self.connecting = false;
+                self.retry_secs = None;
                 self.load_pct = pct;
             }
-            Message::DownloadComplete => self.complete = true,
+            Message::DownloadComplete => self.loaded += 1,
             Message::Error(s) => self.error = Some(s),
+            Message::TokenizerError(model_id, s) => {
+                self.error = Some(s);
+                self.tokenizer_error = Some(model_id);
+            }
+            Message::LoadTimeout(s) => self.timeout_error = Some(s),
             _ => {}
         }
     }
 
+    fn is_busy(&self, _ctx: &AppContext) -> bool {
+        // The gauge animates while loading, loading stops on errors.
+        self.error.is_none()
+    }
+
     fn next_panel(&mut self, _ctx: &mut AppContext) -> Option<Box<dyn Panel>> {
-        if self.complete {
-            Some(Box::new(PromptPanel::new(self.model_id)))
-        } else {
+        if let Some(error) = self.timeout_error.take() {
+            Some(Box::new(ModelsPanel::with_error(error)))
+        } else if self.loaded < self.model_ids.len() {
             None
+        } else if let [model_id] = self.model_ids[..] {
+            Some(Box::new(PromptPanel::new(model_id)))
+        } else {
+            Some(Box::new(ComparePanel::new(&self.model_ids)))
         }
     }
 }
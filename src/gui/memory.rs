@@ -0,0 +1,62 @@
+use eframe::egui::*;
+use std::time::{Duration, Instant};
+use sysinfo::System;
+
+/// Time between samples of the process memory.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Tracks the current and peak resident memory of the process.
+///
+/// The peak is the highest sampled value, short spikes between samples are missed.
+#[derive(Debug, Default)]
+pub struct MemoryUsage {
+    system: System,
+    last_sample: Option<Instant>,
+    current: u64,
+    peak: u64,
+}
+
+impl MemoryUsage {
+    /// Samples the process memory if the sample interval has elapsed.
+    pub fn sample(&mut self) {
+        if self
+            .last_sample
+            .is_some_and(|last| last.elapsed() < SAMPLE_INTERVAL)
+        {
+            return;
+        }
+
+        self.last_sample = Some(Instant::now());
+        let Ok(pid) = sysinfo::get_current_pid() else {
+            return;
+        };
+
+        if self.system.refresh_process(pid) {
+            self.current = self.system.process(pid).map_or(0, |p| p.memory());
+            self.peak = self.peak.max(self.current);
+        }
+    }
+
+    /// Shows the current and peak memory and schedules the next sample.
+    pub fn ui(&self, ui: &mut Ui) {
+        let font = FontId::new(11.0, FontFamily::Monospace);
+        let text = format!(
+            "Memory {} peak {}",
+            format_size(self.current),
+            format_size(self.peak)
+        );
+        ui.label(RichText::new(text).font(font).weak())
+            .on_hover_text("Resident memory of the process, sampled every few seconds");
+        ui.ctx().request_repaint_after(SAMPLE_INTERVAL);
+    }
+}
+
+/// Formats a size in bytes as megabytes or gigabytes.
+fn format_size(bytes: u64) -> String {
+    let mb = bytes as f64 / (1 << 20) as f64;
+    if mb < 1024.0 {
+        format!("{mb:.0}M")
+    } else {
+        format!("{:.1}G", mb / 1024.0)
+    }
+}
@@ -1,20 +1,36 @@
+use crossbeam_channel::{unbounded, Receiver};
 use eframe::egui::*;
+use std::thread;
 
 use crate::{
-    gui::{load_panel::LoadPanel, AppContext, Panel},
-    models::{ModelId, ModelSpec, ModelsCache},
+    gui::{load_panel::LoadPanel, truncate_words, AppContext, Panel},
+    models::{cpu_warning, total_memory, MemoryFit, ModelId, ModelSpec, ModelsCache},
 };
 
 const ROUNDING: f32 = 8.0;
 
+/// Font of the model names in the models buttons.
+const NAME_FONT: FontId = FontId::new(22.0, FontFamily::Monospace);
+
+/// Space before each text in the models buttons.
+const BUTTON_PADDING: f32 = 10.0;
+
 #[derive(Debug)]
 pub struct ModelsPanel {
     selected: Option<ModelId>,
+    compare: bool,
+    compare_ids: Vec<ModelId>,
     models: Vec<ModelData>,
+    error: Option<String>,
+    cache_warning: Option<String>,
+    cpu_warning: Option<String>,
+    update: Option<ModelId>,
+    update_rx: Option<Receiver<ModelId>>,
 }
 
 impl ModelsPanel {
     pub fn new() -> Self {
+        let total_memory = total_memory();
         let models = ModelId::models()
             .into_iter()
             .map(|model_id| {
@@ -24,29 +40,141 @@ impl ModelsPanel {
                 let cached = ModelsCache::new()
                     .map(|c| c.cached_model(model_id).is_cached())
                     .unwrap_or(false);
-                ModelData { spec, cached }
+                let memory_fit = spec.memory_fit(total_memory);
+                ModelData {
+                    spec,
+                    cached,
+                    memory_fit,
+                    update_available: false,
+                }
             })
             .collect();
 
         Self {
             selected: None,
+            compare: false,
+            compare_ids: Vec::new(),
             models,
+            error: None,
+            cache_warning: cache_warning(),
+            cpu_warning: cpu_warning(),
+            update: None,
+            update_rx: None,
+        }
+    }
+
+    /// Checks for updated weights of the cached models on another thread, models
+    /// that cannot be checked, for example when offline, are reported as up to date.
+    fn check_updates(&mut self) {
+        let model_ids = self
+            .models
+            .iter()
+            .filter(|model| model.cached)
+            .map(|model| model.spec.model_id)
+            .collect::<Vec<_>>();
+
+        let (update_tx, update_rx) = unbounded();
+        thread::spawn(move || {
+            let Ok(cache) = ModelsCache::new() else {
+                return;
+            };
+
+            for model_id in model_ids {
+                if cache.cached_model(model_id).check_update().unwrap_or(false) {
+                    let _ = update_tx.send(model_id);
+                }
+            }
+        });
+
+        self.update_rx = Some(update_rx);
+    }
+
+    /// Shows the models with an error from a failed load.
+    pub fn with_error(error: String) -> Self {
+        Self {
+            error: Some(error),
+            ..Self::new()
         }
     }
 }
 
 impl Panel for ModelsPanel {
     fn update(&mut self, ctx: &mut AppContext) {
+        if ctx.state.check_model_updates && self.update_rx.is_none() {
+            self.check_updates();
+        }
+
+        if let Some(update_rx) = &self.update_rx {
+            for model_id in update_rx.try_iter() {
+                if let Some(model) = self.models.iter_mut().find(|m| m.spec.model_id == model_id) {
+                    model.update_available = true;
+                }
+            }
+        }
+
         CentralPanel::default().show(&ctx.egui_ctx, |ui| {
             ScrollArea::vertical()
                 .auto_shrink(false)
                 .stick_to_bottom(true)
                 .show(ui, |ui| {
+                    if let Some(error) = &self.error {
+                        ui.label(RichText::new(error).color(Color32::LIGHT_RED));
+                    }
+
+                    for warning in [&self.cache_warning, &self.cpu_warning]
+                        .into_iter()
+                        .flatten()
+                    {
+                        ui.label(RichText::new(warning).color(ui.visuals().warn_fg_color));
+                    }
+
+                    if ui
+                        .checkbox(&mut self.compare, "Compare two models")
+                        .changed()
+                    {
+                        self.compare_ids.clear();
+                    }
+
                     let width = ui.available_width();
+                    let name_width = width - BUTTON_PADDING - 2.0 * ui.spacing().button_padding.x;
                     for model in &self.models {
-                        let r = ui.add(model.button(ui).min_size(Vec2::new(width, 120.0)));
+                        let model_id = model.spec.model_id;
+                        let selected = self.compare_ids.contains(&model_id);
+
+                        // Long names are truncated on narrow windows, hover to see them.
+                        let name =
+                            truncate_words(ui.ctx(), model.spec.name, &NAME_FONT, name_width);
+                        let truncated = name != model.spec.name;
+                        let mut r = ui.add(
+                            model
+                                .button(ui, &name)
+                                .selected(selected)
+                                .min_size(Vec2::new(width, 120.0)),
+                        );
+                        if truncated {
+                            r = r.on_hover_text(model.spec.name);
+                        }
+
                         if r.clicked() {
-                            self.selected = Some(model.spec.model_id);
+                            if !self.compare {
+                                self.selected = Some(model_id);
+                            } else if selected {
+                                self.compare_ids.retain(|id| *id != model_id);
+                            } else {
+                                self.compare_ids.push(model_id);
+                            }
+                        }
+
+                        if model.update_available && !self.compare {
+                            let button = Button::new(
+                                RichText::new("Download Update")
+                                    .font(FontId::new(14.0, FontFamily::Monospace)),
+                            )
+                            .rounding(4.0);
+
+                            if ui.add(button).clicked() {
+                                self.update = Some(model_id);
+                            }
                         }
                     }
                 })
@@ -54,8 +182,12 @@ impl Panel for ModelsPanel {
     }
 
     fn next_panel(&mut self, ctx: &mut AppContext) -> Option<Box<dyn Panel>> {
-        if let Some(model_id) = self.selected {
+        if let Some(model_id) = self.update {
+            Some(Box::new(LoadPanel::update(model_id, ctx)))
+        } else if let Some(model_id) = self.selected {
             Some(Box::new(LoadPanel::new(model_id, ctx)))
+        } else if self.compare_ids.len() == 2 {
+            Some(Box::new(LoadPanel::compare(&self.compare_ids, ctx)))
         } else {
             None
         }
@@ -66,22 +198,36 @@ impl Panel for ModelsPanel {
     }
 }
 
+/// Gets a warning if the models cache cannot be used or won't persist downloads.
+fn cache_warning() -> Option<String> {
+    match ModelsCache::new() {
+        Ok(cache) if cache.is_persistent() => None,
+        Ok(cache) => Some(format!(
+            "Home directory not found, downloads are saved to {} and may not persist, \
+            set COZE_CACHE_DIR to keep them.",
+            cache.cache_dir().display()
+        )),
+        Err(e) => Some(e.to_string()),
+    }
+}
+
 #[derive(Debug)]
 struct ModelData {
     spec: ModelSpec,
     cached: bool,
+    memory_fit: Option<MemoryFit>,
+    update_available: bool,
 }
 
 impl ModelData {
-    fn button(&self, ui: &Ui) -> Button<'_> {
-        const PADDING: f32 = 10.0;
-
+    /// Builds the model button showing the given name.
+    fn button(&self, ui: &Ui, name: &str) -> Button<'_> {
         let mut job = text::LayoutJob::default();
 
-        let font_id = FontId::new(22.0, FontFamily::Monospace);
+        let font_id = NAME_FONT;
         job.append(
-            self.spec.name,
-            PADDING,
+            name,
+            BUTTON_PADDING,
             TextFormat {
                 font_id: font_id.clone(),
                 color: ui.visuals().text_color(),
@@ -91,7 +237,7 @@ impl ModelData {
 
         job.append(
             "\n\n",
-            PADDING,
+            BUTTON_PADDING,
             TextFormat {
                 font_id,
                 color: ui.visuals().text_color(),
@@ -103,7 +249,7 @@ impl ModelData {
 
         job.append(
             &format!("Size: {}M", self.spec.size / (1 << 20)),
-            PADDING,
+            BUTTON_PADDING,
             TextFormat {
                 font_id: font_id.clone(),
                 color: ui.visuals().text_color(),
@@ -114,15 +260,45 @@ impl ModelData {
         if self.cached {
             job.append(
                 "(Cached)",
-                PADDING,
+                BUTTON_PADDING,
                 TextFormat {
-                    font_id,
+                    font_id: font_id.clone(),
                     color: ui.visuals().text_color(),
                     ..Default::default()
                 },
             );
         }
 
+        if self.update_available {
+            job.append(
+                "(Update available)",
+                BUTTON_PADDING,
+                TextFormat {
+                    font_id: font_id.clone(),
+                    color: ui.visuals().warn_fg_color,
+                    ..Default::default()
+                },
+            );
+        }
+
+        // Advisory badge based on the system memory.
+        if let Some(memory_fit) = self.memory_fit {
+            let color = match memory_fit {
+                MemoryFit::Recommended => Color32::from_rgb(40, 160, 60),
+                MemoryFit::Tight => ui.visuals().warn_fg_color,
+                MemoryFit::MayNotFit => ui.visuals().error_fg_color,
+            };
+            job.append(
+                memory_fit.description(),
+                BUTTON_PADDING,
+                TextFormat {
+                    font_id,
+                    color,
+                    ..Default::default()
+                },
+            );
+        }
+
         Button::new(job).rounding(ROUNDING).wrap(true)
     }
 }
@@ -4,60 +4,236 @@ use eframe::egui::*;
 use crate::{
     controller::{Message, PromptId},
     gui::{
-        bubble::{Bubble, BubbleContent},
+        bubble::{model_badge, Bubble, BubbleContent, USER_BADGE},
         history::HistoryNavigator,
-        AppContext, Panel, Prompt,
+        latency::TokenLatency,
+        memory::MemoryUsage,
+        model_title,
+        scroll::ScrollAnchor,
+        templates::TemplateForm,
+        waiting_dots, AppContext, Panel, Prompt, MAX_ALTERNATIVES,
+    },
+    models::{
+        cpu_isa, device_name, ModelConfig, ModelId, ModelOptions, ModelParams, PromptCounter,
+        StopReason,
     },
-    models::ModelId,
 };
 
 const TEXT_FONT: FontId = FontId::new(15.0, FontFamily::Monospace);
+const COMPACT_TEXT_FONT: FontId = FontId::new(13.0, FontFamily::Monospace);
 const ROUNDING: f32 = 8.0;
+const STATUS_FONT: FontId = FontId::new(11.0, FontFamily::Monospace);
+
+/// Prompts shown as examples when the history is empty.
+const EXAMPLE_PROMPTS: [&str; 4] = [
+    "Explain recursion to a beginner",
+    "Write a haiku about Rust",
+    "What are the pros and cons of remote work?",
+    "Suggest a name for a cat that likes boxes",
+];
+
+/// Maximum height of the prompt field as a fraction of the window height, longer
+/// prompts scroll inside the field.
+const MAX_PROMPT_HEIGHT: f32 = 0.4;
 
 #[derive(Debug)]
 pub struct PromptPanel {
     prompt: String,
     prompt_field_id: Id,
-    last_prompt_id: PromptId,
     error: Option<String>,
     history: HistoryNavigator,
     frame_counter: usize,
     scroll_to_bottom: bool,
     model_name: String,
+    counter: Option<PromptCounter>,
+    /// Generator mode and options used to count the prompt tokens.
+    count_options: Option<(ModelConfig, ModelOptions)>,
+    count_params: ModelParams,
+    prompt_tokens: usize,
+    max_prompt_tokens: usize,
+    truncate_prompt: bool,
+    /// Number of tokens in the model context after the last reply.
+    context_used: usize,
+    latency: TokenLatency,
+    memory: MemoryUsage,
+    file_warning: Option<String>,
+    scroll_anchor: ScrollAnchor,
+    model_id: ModelId,
+    prompt_tokens_view: Option<Vec<(u32, String)>>,
+    template_form: Option<TemplateForm>,
+    alternatives: Vec<Alternative>,
+    editing: Option<(usize, String)>,
+    /// The reply the controller is working on.
+    generating: Option<PromptId>,
+}
+
+/// An alternative reply to the last prompt, only the one picked is saved.
+#[derive(Debug)]
+struct Alternative {
+    prompt_id: PromptId,
+    reply: String,
+    seed: Option<u64>,
+    done: bool,
 }
 
 impl PromptPanel {
     pub fn new(model_id: ModelId) -> Self {
         Self {
             prompt_field_id: Id::new("prompt-id"),
-            last_prompt_id: PromptId::default(),
             error: None,
             prompt: Default::default(),
             history: HistoryNavigator::new(),
             frame_counter: 0,
             scroll_to_bottom: false,
             model_name: model_id.spec().name.to_string(),
+            counter: PromptCounter::new(model_id).ok(),
+            count_options: None,
+            count_params: ModelConfig::default().params(),
+            prompt_tokens: 0,
+            max_prompt_tokens: model_id.spec().max_prompt_tokens(),
+            truncate_prompt: false,
+            context_used: 0,
+            latency: TokenLatency::default(),
+            memory: MemoryUsage::default(),
+            file_warning: None,
+            scroll_anchor: ScrollAnchor::default(),
+            model_id,
+            prompt_tokens_view: None,
+            template_form: None,
+            alternatives: Vec::new(),
+            editing: None,
+            generating: None,
         }
     }
 
     fn send_prompt(&mut self, ctx: &mut AppContext) {
+        let truncate = self.truncate_prompt && self.prompt_tokens > self.max_prompt_tokens;
         let prompt = self.prompt.trim();
+        // Prompts are not sent once the conversation has used its token budget.
+        if let Some(error) = ctx.state.budget_error().filter(|_| !prompt.is_empty()) {
+            self.error = Some(error);
+            return;
+        }
+
         if !prompt.is_empty() {
-            // Flush tokens from previous prompt
-            while ctx.controller.next_message().is_some() {}
+            // Sending a prompt may drop old entries and shift the edited reply.
+            self.editing = None;
+
+            // Alternatives not picked yet keep the first one.
+            if !self.alternatives.is_empty() {
+                keep_alternative(ctx, &mut self.alternatives, 0);
+            }
 
-            self.last_prompt_id = ctx.controller.send_prompt(prompt);
+            // The prompt is queued if a reply is being generated.
+            let count = ctx.state.alternatives.clamp(1, MAX_ALTERNATIVES);
+            let prompt_id = if count > 1 {
+                let prompt_ids = ctx.controller.send_alternatives(prompt, count, truncate);
+                self.alternatives = prompt_ids
+                    .iter()
+                    .map(|prompt_id| Alternative {
+                        prompt_id: *prompt_id,
+                        reply: String::new(),
+                        seed: None,
+                        done: false,
+                    })
+                    .collect();
+
+                // The prompt is pending until the last alternative is done.
+                prompt_ids.last().copied()
+            } else {
+                Some(ctx.controller.send_prompt(prompt, truncate))
+            };
+            self.latency.reset();
 
             let info = format!("{} - {}", self.model_name, Local::now().format("%F %T%.3f"));
-            ctx.state.history.push(Prompt {
+            ctx.state.push_history(Prompt {
                 prompt: prompt.to_owned(),
                 reply: Default::default(),
                 info,
+                draft: Default::default(),
+                reasoning: Default::default(),
+                template: Default::default(),
+                seed: None,
+                edited: false,
+                stopped: None,
+                prompt_id,
+                truncate,
+                token_probs: Vec::new(),
+                expanded: false,
             });
         }
 
         self.reset_prompt(&ctx.egui_ctx, "".to_string());
         self.history.reset(&self.prompt);
+        self.file_warning = None;
+    }
+
+    /// Inserts the contents of a text file at the prompt cursor as a code block.
+    fn insert_file(&mut self, ctx: &Context) {
+        let Some(path) = rfd::FileDialog::new().pick_file() else {
+            return;
+        };
+
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let contents = match std::fs::read(&path) {
+            Ok(bytes) => match String::from_utf8(bytes) {
+                Ok(text) if !text.contains('\0') => text,
+                _ => {
+                    self.error = Some(format!("{file_name} is not a UTF-8 text file"));
+                    return;
+                }
+            },
+            Err(e) => {
+                self.error = Some(format!("Unable to read {file_name}: {e}"));
+                return;
+            }
+        };
+
+        let lang = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let block = format!("```{lang}\n{}\n```\n", contents.trim_end());
+
+        // Insert at the cursor position and move the cursor after the block.
+        let mut state =
+            text_edit::TextEditState::load(ctx, self.prompt_field_id).unwrap_or_default();
+        let char_idx = state
+            .cursor
+            .char_range()
+            .map(|range| range.primary.index)
+            .unwrap_or_else(|| self.prompt.chars().count());
+        let byte_idx = self
+            .prompt
+            .char_indices()
+            .nth(char_idx)
+            .map(|(idx, _)| idx)
+            .unwrap_or(self.prompt.len());
+        self.prompt.insert_str(byte_idx, &block);
+
+        let cursor = text::CCursor::new(char_idx + block.chars().count());
+        state
+            .cursor
+            .set_char_range(Some(text::CCursorRange::one(cursor)));
+        state.store(ctx, self.prompt_field_id);
+
+        self.history.reset(&self.prompt);
+        self.count_tokens();
+
+        // Warn when the file takes a large part of the model context.
+        self.file_warning = self.counter.as_ref().and_then(|counter| {
+            let tokens = counter.count(&block, &self.count_params);
+            (tokens > self.max_prompt_tokens / 2).then(|| {
+                format!(
+                    "{file_name} is {tokens} tokens, the model context fits {} tokens",
+                    self.max_prompt_tokens
+                )
+            })
+        });
     }
 
     fn reset_prompt(&mut self, ctx: &Context, prompt: String) {
@@ -65,6 +241,178 @@ impl PromptPanel {
 
         let state = text_edit::TextEditState::default();
         state.store(ctx, self.prompt_field_id);
+
+        self.count_tokens();
+    }
+
+    fn count_tokens(&mut self) {
+        if let Some(counter) = &self.counter {
+            self.prompt_tokens = counter.count(&self.prompt, &self.count_params);
+        }
+    }
+
+    /// Inserts a template in the prompt field, asks for the placeholders values first
+    /// if it has any.
+    fn insert_template(&mut self, ctx: &Context, form: TemplateForm) {
+        if form.is_empty() {
+            if let Ok(prompt) = form.fill() {
+                self.reset_prompt(ctx, prompt);
+                self.history.reset(&self.prompt);
+            }
+        } else {
+            self.template_form = Some(form);
+        }
+    }
+
+    fn template_window(&mut self, ctx: &Context) {
+        let Some(form) = &mut self.template_form else {
+            return;
+        };
+
+        let mut close = false;
+        let mut prompt = None;
+        Window::new(format!("Template {}", form.name()))
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                Grid::new("template_form_grid")
+                    .num_columns(2)
+                    .spacing([10.0, 4.0])
+                    .show(ui, |ui| {
+                        for (name, value) in &mut form.values {
+                            ui.label(name.as_str());
+                            ui.add(TextEdit::singleline(value).desired_width(240.0));
+                            ui.end_row();
+                        }
+                    });
+
+                if let Some(error) = &form.error {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    if ui.button("Insert").clicked() {
+                        match form.fill() {
+                            Ok(text) => prompt = Some(text),
+                            Err(e) => form.error = Some(e.to_string()),
+                        }
+                    }
+
+                    if ui.button("Cancel").clicked() {
+                        close = true;
+                    }
+                });
+            });
+
+        if let Some(prompt) = prompt {
+            self.reset_prompt(ctx, prompt);
+            self.history.reset(&self.prompt);
+            close = true;
+        }
+
+        if close {
+            self.template_form = None;
+        }
+    }
+
+    fn tokens_window(&mut self, ctx: &Context) {
+        let Some(tokens) = &self.prompt_tokens_view else {
+            return;
+        };
+
+        let mut close = false;
+        Window::new("Prompt tokens")
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(format!("{} tokens", tokens.len()));
+                ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                    Grid::new("tokens_grid")
+                        .num_columns(2)
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for (token, text) in tokens {
+                                ui.monospace(token.to_string());
+                                ui.monospace(format!("{text:?}"));
+                                ui.end_row();
+                            }
+                        });
+                });
+
+                ui.vertical_centered(|ui| {
+                    if ui.button("Close").clicked() {
+                        close = true;
+                    }
+                });
+            });
+
+        if close {
+            self.prompt_tokens_view = None;
+        }
+    }
+
+    /// Shows the model name and size, device, generator mode, and prompt context usage.
+    fn status_bar(&self, ui: &mut Ui, ctx: &mut AppContext) {
+        let status = |text: String| RichText::new(text).font(STATUS_FONT).weak();
+
+        ui.horizontal(|ui| {
+            ui.label(status(self.model_name.clone()));
+            let info = ctx.controller.model_info().cloned().unwrap_or_default();
+            ui.label(status(info.description()))
+                .on_hover_text("Parameters and quantization of the weights");
+            ui.separator();
+            ui.label(status(device_name().to_string()))
+                .on_hover_text(cpu_isa());
+            ui.separator();
+
+            // Change the generator mode from the status bar.
+            let model_config = ctx.controller.model_config();
+            ui.menu_button(status(model_config.description().to_string()), |ui| {
+                for config in [
+                    ModelConfig::Careful,
+                    ModelConfig::Creative,
+                    ModelConfig::Deranged,
+                ] {
+                    let r = ui.selectable_label(model_config == config, config.description());
+                    if r.clicked() {
+                        ctx.state.set_config_for(Some(self.model_id), config);
+                        ctx.controller.set_config(config);
+                        ui.close_menu();
+                    }
+                }
+            })
+            .response
+            .on_hover_text("Generator mode of this model");
+            ui.separator();
+
+            let context_length = self.model_id.spec().context_length;
+            let context = format!("Context {}/{context_length} tokens", self.context_used);
+            ui.label(status(context));
+
+            if ctx.state.token_budget > 0 {
+                ui.separator();
+
+                let remaining = ctx
+                    .state
+                    .token_budget
+                    .saturating_sub(ctx.state.generated_tokens);
+                let mut text = status(format!("Budget {remaining} tokens left"));
+                if remaining == 0 {
+                    text = text.color(ui.visuals().warn_fg_color);
+                }
+
+                let r = ui
+                    .add(Label::new(text).sense(Sense::click()))
+                    .on_hover_text("Click to reset the token budget");
+                if r.clicked() {
+                    ctx.state.generated_tokens = 0;
+                }
+            }
+        });
     }
 
     fn error_window(&mut self, ctx: &Context) {
@@ -91,33 +439,81 @@ impl PromptPanel {
 impl Panel for PromptPanel {
     fn update(&mut self, ctx: &mut AppContext) {
         ctx.egui_ctx
-            .send_viewport_cmd(ViewportCommand::Title(format!(
-                "{} ({})",
+            .send_viewport_cmd(ViewportCommand::Title(model_title(
+                &ctx.egui_ctx,
                 &self.model_name,
-                ctx.controller.model_config().description(),
+                ctx.controller.model_config(),
             )));
 
         self.frame_counter += 1;
 
+        // Count again with the template options used for generation when they change.
+        let options = (
+            ctx.controller.model_config(),
+            ctx.controller.model_options().clone(),
+        );
+        if self.count_options.as_ref() != Some(&options) {
+            self.count_params = options.0.params().with_options(&options.1);
+            self.count_options = Some(options);
+            self.count_tokens();
+        }
+
+        // The history has been cleared or replaced.
+        if ctx.state.history.is_empty() {
+            self.alternatives.clear();
+            self.editing = None;
+        }
+
+        // Smaller fonts and spacing for narrow windows.
+        let compact = ctx.state.compact_mode.is_compact(&ctx.egui_ctx);
+        let (margin, text_font, reply_spacing) = if compact {
+            (5.0, COMPACT_TEXT_FONT, 1.5)
+        } else {
+            (10.0, TEXT_FONT, 2.5)
+        };
+
         let egui_ctx = ctx.egui_ctx.clone();
         let prompt_frame = Frame::none()
             .fill(ctx.egui_ctx.style().visuals.window_fill)
             .outer_margin(Margin::same(0.0))
-            .inner_margin(Margin::same(10.0));
+            .inner_margin(Margin::same(margin));
 
         // Render prompt panel.
         TopBottomPanel::bottom("bottom_panel")
             .show_separator_line(false)
             .frame(prompt_frame)
             .show(&egui_ctx, |ui| {
+                if ctx.state.show_latency {
+                    self.latency.ui(ui);
+                }
+
+                if ctx.state.show_memory {
+                    self.memory.sample();
+                    self.memory.ui(ui);
+                }
+
+                self.status_bar(ui, ctx);
+
                 Frame::group(ui.style())
                     .rounding(Rounding::same(ROUNDING))
                     .fill(ctx.state.ui_mode.fill_color())
+                    .stroke(ctx.state.ui_mode.stroke())
                     .show(ui, |ui| {
-                        egui_ctx.memory_mut(|m| m.request_focus(self.prompt_field_id));
+                        // Keep focus on the prompt unless another field is being edited.
+                        egui_ctx.memory_mut(|m| {
+                            if m.focus().is_none() {
+                                m.request_focus(self.prompt_field_id)
+                            }
+                        });
 
-                        // Override multiline Enter behavior
-                        if ui.input_mut(|i| i.consume_key(Modifiers::NONE, Key::Enter)) {
+                        // Override multiline Enter behavior when the prompt has focus,
+                        // unless a template is being filled or a reply edited.
+                        let prompt_focused = egui_ctx.memory(|m| m.has_focus(self.prompt_field_id));
+                        if prompt_focused
+                            && self.template_form.is_none()
+                            && self.editing.is_none()
+                            && ui.input_mut(|i| i.consume_key(Modifiers::NONE, Key::Enter))
+                        {
                             self.send_prompt(ctx);
                             self.scroll_to_bottom = true;
                         }
@@ -125,77 +521,397 @@ impl Panel for PromptPanel {
                         let text = TextEdit::multiline(&mut self.prompt)
                             .id(self.prompt_field_id)
                             .cursor_at_end(true)
-                            .font(TEXT_FONT)
+                            .font(text_font.clone())
                             .frame(false)
                             .margin(Vec2::new(5.0, 5.0))
                             .desired_rows(1)
                             .hint_text("Prompt me! (Enter to send)");
 
-                        let r = ui.add_sized([ui.available_width(), 10.0], text);
-                        if r.changed() {
+                        let templates = &ctx.state.templates;
+                        let (insert_file, template, changed) = ui
+                            .horizontal(|ui| {
+                                let attach =
+                                    Button::new(RichText::new("📎").font(TEXT_FONT)).frame(false);
+                                let attach = ui.add(attach).on_hover_text("Insert file contents");
+
+                                let mut template = None;
+                                if !templates.is_empty() {
+                                    let icon = RichText::new("🗐").font(TEXT_FONT);
+                                    ui.menu_button(icon, |ui| {
+                                        for t in templates {
+                                            if ui.button(&t.name).clicked() {
+                                                template = Some(t.clone());
+                                                ui.close_menu();
+                                            }
+                                        }
+                                    })
+                                    .response
+                                    .on_hover_text("Insert template");
+                                }
+
+                                let max_height =
+                                    egui_ctx.screen_rect().height() * MAX_PROMPT_HEIGHT;
+                                let r = ScrollArea::vertical()
+                                    .id_source("prompt-scroll")
+                                    .max_height(max_height)
+                                    .stick_to_bottom(true)
+                                    .show(ui, |ui| ui.add_sized([ui.available_width(), 10.0], text))
+                                    .inner;
+                                (attach.clicked(), template, r.changed())
+                            })
+                            .inner;
+
+                        if changed {
                             self.history.reset(&self.prompt);
+                            self.count_tokens();
+                        }
+
+                        if insert_file {
+                            self.insert_file(&egui_ctx);
+                        }
+
+                        if let Some(template) = template {
+                            self.insert_template(&egui_ctx, TemplateForm::new(template));
+                        }
+
+                        if let Some(warning) = &self.file_warning {
+                            ui.colored_label(ui.visuals().warn_fg_color, warning);
+                        }
+
+                        // Warn when the prompt doesn't fit the model context.
+                        if self.prompt_tokens > self.max_prompt_tokens {
+                            ui.horizontal(|ui| {
+                                let msg = format!(
+                                    "Prompt is {} tokens over the {} tokens limit",
+                                    self.prompt_tokens - self.max_prompt_tokens,
+                                    self.max_prompt_tokens
+                                );
+                                ui.colored_label(ui.visuals().warn_fg_color, msg);
+                                ui.checkbox(&mut self.truncate_prompt, "Truncate from start");
+                            });
                         }
                     })
             });
 
         // Render message panel.
+        let mut scroll_to_latest = false;
+        let mut example = None;
         CentralPanel::default().show(&egui_ctx, |ui| {
-            ScrollArea::vertical()
+            // Examples help first time users get started, clicking one fills the prompt.
+            if ctx.state.history.is_empty() {
+                ui.vertical_centered(|ui| {
+                    ui.add_space(ui.spacing().item_spacing.y * 4.0);
+                    ui.label(RichText::new("Try one of these prompts").font(text_font.clone()));
+                    ui.add_space(ui.spacing().item_spacing.y);
+                    for prompt in EXAMPLE_PROMPTS {
+                        let button = Button::new(RichText::new(prompt).font(text_font.clone()))
+                            .rounding(Rounding::same(ROUNDING));
+                        if ui.add(button).clicked() {
+                            example = Some(prompt);
+                        }
+                    }
+                });
+            }
+
+            let output = ScrollArea::vertical()
                 .auto_shrink(false)
                 .stick_to_bottom(true)
                 .show(ui, |ui| {
-                    let mut iter = ctx.state.history.iter().peekable();
-                    while let Some(prompt) = iter.next() {
+                    let avatars = ctx.state.show_avatars;
+                    let accent = ctx.state.accent_color;
+                    let model_badge = model_badge(&self.model_name);
+                    let mut toggle_expanded = None;
+                    let mut keep = None;
+                    let mut edit = None;
+                    let mut save_edit = None;
+                    let mut sticky = None;
+                    let last_idx = ctx.state.history.len().saturating_sub(1);
+                    for (idx, prompt) in ctx.state.history.iter().enumerate() {
                         let r = ui.add(
                             Bubble::new(&prompt.prompt, BubbleContent::Prompt, ctx.state.ui_mode)
-                                .with_footer(&prompt.info),
+                                .with_footer(&prompt.footer())
+                                .with_layout(ctx.state.history_layout)
+                                .with_accent(accent)
+                                .with_avatar(avatars.then_some(USER_BADGE))
+                                .with_compact(compact),
                         );
+                        let prompt_bottom = r.rect.bottom();
                         if r.clicked() {
                             ui.ctx().copy_text(prompt.prompt.clone());
                         }
 
+                        r.context_menu(|ui| {
+                            if ui.button("Show tokens").clicked() {
+                                ctx.controller.tokenize(self.model_id, &prompt.prompt);
+                                ui.close_menu();
+                            }
+
+                            if let Some(seed) = prompt.seed {
+                                if ui.button("Copy seed").clicked() {
+                                    ui.ctx().copy_text(seed.to_string());
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+
                         if r.double_clicked() {
                             self.prompt = prompt.prompt.clone();
                             self.scroll_to_bottom = true;
+                            self.count_tokens();
+                        }
+
+                        // The templated prompt helps to debug prompt formatting.
+                        if ctx.state.show_template && !prompt.template.is_empty() {
+                            CollapsingHeader::new("Template")
+                                .id_source(("template", idx))
+                                .default_open(false)
+                                .show(ui, |ui| {
+                                    ui.label(RichText::new(&prompt.template).monospace());
+                                });
                         }
 
                         ui.add_space(ui.spacing().item_spacing.y);
 
-                        if !prompt.reply.is_empty() {
-                            let r = ui.add(Bubble::new(
-                                &prompt.reply,
-                                BubbleContent::Reply,
-                                ctx.state.ui_mode,
-                            ));
+                        // The draft of a refined reply is collapsed above it.
+                        if !prompt.draft.is_empty() {
+                            CollapsingHeader::new("Draft")
+                                .id_source(("draft", idx))
+                                .default_open(false)
+                                .show(ui, |ui| {
+                                    ui.add(
+                                        Bubble::new(
+                                            &prompt.draft,
+                                            BubbleContent::Reply,
+                                            ctx.state.ui_mode,
+                                        )
+                                        .with_layout(ctx.state.history_layout)
+                                        .with_accent(accent)
+                                        .with_compact(compact),
+                                    );
+                                });
+                        }
+
+                        // The reasoning is collapsed above the reply.
+                        if !prompt.reasoning.is_empty() {
+                            CollapsingHeader::new("Reasoning")
+                                .id_source(("reasoning", idx))
+                                .default_open(false)
+                                .show(ui, |ui| {
+                                    ui.add(
+                                        Bubble::new(
+                                            &prompt.reasoning,
+                                            BubbleContent::Reply,
+                                            ctx.state.ui_mode,
+                                        )
+                                        .with_layout(ctx.state.history_layout)
+                                        .with_accent(accent)
+                                        .with_compact(compact),
+                                    );
+                                });
+                        }
+
+                        if idx == last_idx && !self.alternatives.is_empty() {
+                            // Alternative replies to the last prompt, click one to keep it.
+                            let count = self.alternatives.len();
+                            for (n, alternative) in self.alternatives.iter().enumerate() {
+                                ui.label(
+                                    RichText::new(format!("Alternative {} of {count}", n + 1))
+                                        .font(STATUS_FONT),
+                                );
+
+                                let reply = if alternative.reply.is_empty() && !alternative.done {
+                                    let generating = self.generating == Some(alternative.prompt_id);
+                                    waiting_dots(self.frame_counter, generating)
+                                } else {
+                                    &alternative.reply
+                                };
+
+                                let r = ui
+                                    .add(
+                                        Bubble::new(reply, BubbleContent::Reply, ctx.state.ui_mode)
+                                            .with_layout(ctx.state.history_layout)
+                                            .with_accent(accent)
+                                            .with_avatar(avatars.then_some(&model_badge))
+                                            .with_compact(compact),
+                                    )
+                                    .on_hover_text("Click to keep this reply");
+                                if r.clicked() {
+                                    keep = Some(n);
+                                }
+
+                                ui.add_space(ui.spacing().item_spacing.y);
+                            }
+
+                            ui.add_space(ui.spacing().item_spacing.y * reply_spacing);
+                        } else if let Some((_, text)) = self
+                            .editing
+                            .as_mut()
+                            .filter(|(edit_idx, _)| *edit_idx == idx)
+                        {
+                            // Reply being edited, the changes are saved to the history.
+                            ui.add(
+                                TextEdit::multiline(text)
+                                    .font(text_font.clone())
+                                    .desired_width(f32::INFINITY),
+                            );
+                            ui.horizontal(|ui| {
+                                if ui.small_button("Save").clicked() {
+                                    save_edit = Some(true);
+                                }
+
+                                if ui.small_button("Cancel").clicked() {
+                                    save_edit = Some(false);
+                                }
+                            });
+
+                            ui.add_space(ui.spacing().item_spacing.y * reply_spacing);
+                        } else if !prompt.reply.is_empty() {
+                            // Long replies only show their first lines until expanded.
+                            let collapse_at = collapse_at(&prompt.reply, ctx.state.collapse_lines);
+                            let reply = match collapse_at {
+                                Some(end) if !prompt.expanded => &prompt.reply[..end],
+                                _ => &prompt.reply,
+                            };
+
+                            let ui_mode = ctx.state.ui_mode;
+                            let bubble = if ctx.state.show_token_probs {
+                                let probs = &prompt.token_probs;
+                                let len = probs.partition_point(|(start, _)| *start < reply.len());
+                                Bubble::with_token_probs(reply, &probs[..len], ui_mode)
+                            } else {
+                                Bubble::new(reply, BubbleContent::Reply, ui_mode)
+                            };
+                            let r = ui.add(
+                                bubble
+                                    .with_layout(ctx.state.history_layout)
+                                    .with_accent(accent)
+                                    .with_avatar(avatars.then_some(&model_badge))
+                                    .with_compact(compact),
+                            );
                             if r.clicked() {
                                 ui.ctx().copy_text(prompt.reply.clone());
                             }
 
-                            ui.add_space(ui.spacing().item_spacing.y * 2.5);
+                            // Replies can be edited once they are complete.
+                            if r.double_clicked() && prompt.prompt_id.is_none() {
+                                edit = Some(idx);
+                            }
+
+                            if prompt.edited {
+                                ui.label(RichText::new("Edited").font(STATUS_FONT).weak())
+                                    .on_hover_text("The reply was changed after it was generated");
+                            }
+
+                            if let Some(end) = collapse_at {
+                                let label = if prompt.expanded {
+                                    "Show less".to_string()
+                                } else {
+                                    let lines = prompt.reply[end..].lines().count();
+                                    format!("Show more ({lines} lines)")
+                                };
+                                if ui.small_button(label).clicked() {
+                                    toggle_expanded = Some(idx);
+                                }
+                            }
+
+                            ui.add_space(ui.spacing().item_spacing.y * reply_spacing);
                         } else {
-                            // Show waiting animation for last entry.
-                            if iter.peek().is_none() {
-                                let dots = ["⏺   ", " ⏺  ", "  ⏺ ", "   ⏺", "  ⏺ ", " ⏺  "];
-                                ui.add(Bubble::new(
-                                    dots[(self.frame_counter / 18) % dots.len()],
-                                    BubbleContent::Reply,
-                                    ctx.state.ui_mode,
-                                ));
+                            // Show waiting animation for replies not started yet.
+                            if prompt.prompt_id.is_some() {
+                                let generating = self.generating == prompt.prompt_id;
+                                ui.add(
+                                    Bubble::new(
+                                        waiting_dots(self.frame_counter, generating),
+                                        BubbleContent::Reply,
+                                        ctx.state.ui_mode,
+                                    )
+                                    .with_layout(ctx.state.history_layout)
+                                    .with_accent(accent)
+                                    .with_avatar(avatars.then_some(&model_badge))
+                                    .with_compact(compact),
+                                );
                             }
-                            ui.add_space(ui.spacing().item_spacing.y * 2.5);
+                            ui.add_space(ui.spacing().item_spacing.y * reply_spacing);
+                        }
+
+                        // The exchange at the top of the view with its prompt scrolled out.
+                        let top = ui.clip_rect().top();
+                        if prompt_bottom < top && ui.cursor().top() > top {
+                            sticky = Some(idx);
                         }
                     }
 
+                    if let Some(idx) = toggle_expanded {
+                        let prompt = &mut ctx.state.history[idx];
+                        prompt.expanded = !prompt.expanded;
+                    }
+
+                    if let Some(idx) = edit {
+                        self.editing = Some((idx, ctx.state.history[idx].reply.clone()));
+                    }
+
+                    if let Some(save) = save_edit {
+                        let editing = self.editing.take().filter(|_| save);
+                        if let Some((idx, text)) = editing {
+                            let prompt = &mut ctx.state.history[idx];
+                            if prompt.reply != text {
+                                prompt.reply = text;
+                                prompt.edited = true;
+                                // The offsets don't match the edited text.
+                                prompt.token_probs.clear();
+                            }
+                        }
+                    }
+
+                    if let Some(idx) = keep {
+                        keep_alternative(ctx, &mut self.alternatives, idx);
+                    }
+
                     if self.scroll_to_bottom {
                         ui.scroll_to_cursor(Some(Align::BOTTOM));
                     }
+
+                    sticky
                 });
+
+            self.scroll_anchor.update(ui.ctx(), &output);
+
+            if let Some(idx) = output.inner.filter(|_| ctx.state.sticky_prompt) {
+                let prompt = &ctx.state.history[idx].prompt;
+                sticky_prompt(
+                    ui,
+                    output.inner_rect,
+                    prompt,
+                    ctx.state.ui_mode.fill_color(),
+                );
+            }
+
+            // Show a button to jump to the latest reply when scrolled up.
+            let max_offset = output.content_size.y - output.inner_rect.height();
+            if output.state.offset.y < max_offset - 1.0 {
+                let size = Vec2::splat(32.0);
+                let rect = Rect::from_min_size(
+                    output.inner_rect.right_bottom() - size - Vec2::splat(12.0),
+                    size,
+                );
+                let button = Button::new(RichText::new("⏷").font(TEXT_FONT))
+                    .rounding(Rounding::same(size.x / 2.0));
+                scroll_to_latest = ui.put(rect, button).clicked();
+            }
+
             ui.allocate_space(ui.available_size());
         });
 
+        if let Some(example) = example {
+            self.reset_prompt(&egui_ctx, example.to_string());
+            self.history.reset(&self.prompt);
+        }
+
         self.error_window(&egui_ctx);
+        self.tokens_window(&egui_ctx);
+        self.template_window(&egui_ctx);
 
-        self.scroll_to_bottom = false;
+        self.scroll_to_bottom = scroll_to_latest;
     }
 
     fn handle_input(&mut self, app: &mut AppContext) {
@@ -204,10 +920,35 @@ impl Panel for PromptPanel {
             .input_mut(|i| i.consume_key(Modifiers::NONE, Key::Escape))
         {
             app.controller.stop();
+            self.editing = None;
+            if !app.state.keep_prompt_on_stop {
+                self.reset_prompt(&app.egui_ctx, "".to_string());
+                self.history.reset(&self.prompt);
+            }
+        }
+
+        // Clear the prompt field without stopping the replies generation.
+        if app
+            .egui_ctx
+            .input_mut(|i| i.consume_key(Modifiers::COMMAND, Key::L))
+        {
             self.reset_prompt(&app.egui_ctx, "".to_string());
             self.history.reset(&self.prompt);
         }
 
+        // Expand snippets.
+        if self.prompt.starts_with('/')
+            && app
+                .egui_ctx
+                .input_mut(|i| i.consume_key(Modifiers::NONE, Key::Tab))
+        {
+            let name = self.prompt[1..].trim();
+            if let Some(snippet) = app.state.snippets.iter().find(|s| s.name.trim() == name) {
+                self.reset_prompt(&app.egui_ctx, snippet.text.clone());
+                self.history.reset(&self.prompt);
+            }
+        }
+
         // Manage history
         if app
             .egui_ctx
@@ -228,19 +969,174 @@ impl Panel for PromptPanel {
         }
     }
 
+    fn is_busy(&self, ctx: &AppContext) -> bool {
+        // Replies being generated or waiting in the queue.
+        ctx.state
+            .history
+            .iter()
+            .any(|prompt| prompt.prompt_id.is_some())
+    }
+
+    fn model_id(&self) -> Option<ModelId> {
+        Some(self.model_id)
+    }
+
     fn handle_message(&mut self, app: &mut AppContext, msg: Message) {
         match msg {
-            Message::Token(prompt_id, s) => {
-                // Skip tokens from a previous prompt.
-                if self.last_prompt_id == prompt_id {
-                    if let Some(prompt) = app.state.history.last_mut() {
-                        prompt.reply.push_str(&s);
-                        self.scroll_to_bottom = true;
+            Message::Token(prompt_id, s, prob) => {
+                // Skip tokens for prompts that are not in the history anymore.
+                if let Some(alternative) = find_alternative(&mut self.alternatives, prompt_id) {
+                    alternative.reply.push_str(&s);
+                    self.latency.record();
+                } else if let Some(prompt) = find_prompt(&mut app.state.history, prompt_id) {
+                    // The history sticks to the bottom if it was already there,
+                    // this keeps the position if the user has scrolled up.
+                    prompt.token_probs.push((prompt.reply.len(), prob));
+                    prompt.reply.push_str(&s);
+                    self.latency.record();
+                }
+            }
+            Message::Seed(prompt_id, seed) => {
+                if let Some(alternative) = find_alternative(&mut self.alternatives, prompt_id) {
+                    alternative.seed = Some(seed);
+                } else if let Some(prompt) = find_prompt(&mut app.state.history, prompt_id) {
+                    prompt.seed = Some(seed);
+                }
+            }
+            Message::Draft(prompt_id, s) => {
+                // Drafts of alternatives are not shown.
+                if find_alternative(&mut self.alternatives, prompt_id).is_some() {
+                    return;
+                }
+
+                if let Some(prompt) = find_prompt(&mut app.state.history, prompt_id) {
+                    prompt.draft.push_str(&s);
+                }
+            }
+            Message::Template(prompt_id, s) => {
+                if let Some(prompt) = find_prompt(&mut app.state.history, prompt_id) {
+                    prompt.template = s;
+                }
+            }
+            Message::Reasoning(prompt_id, s) => {
+                // Only the reply of alternatives is shown.
+                if find_alternative(&mut self.alternatives, prompt_id).is_some() {
+                    return;
+                }
+
+                if let Some(prompt) = find_prompt(&mut app.state.history, prompt_id) {
+                    prompt.reasoning.push_str(&s);
+                    self.latency.record();
+                }
+            }
+            Message::Done(prompt_id, reason) => {
+                if self.generating == Some(prompt_id) {
+                    self.generating = None;
+                }
+
+                let alternative = find_alternative(&mut self.alternatives, prompt_id);
+                let is_alternative = alternative.is_some();
+                if let Some(alternative) = alternative {
+                    alternative.done = true;
+                }
+
+                if let Some(prompt) = find_prompt(&mut app.state.history, prompt_id) {
+                    prompt.prompt_id = None;
+                    if reason != StopReason::Eos && !is_alternative {
+                        prompt.stopped = Some(reason);
                     }
                 }
             }
+            Message::Tokens(tokens) => self.prompt_tokens_view = Some(tokens),
+            Message::Generating(prompt_id) => self.generating = Some(prompt_id),
+            Message::ContextUsed(model_id, used) if model_id == self.model_id => {
+                self.context_used = used;
+            }
             Message::Error(s) => self.error = Some(s),
+            Message::PromptError(prompt_id, s) => {
+                // Another prompt may already be generating.
+                if self.generating == Some(prompt_id) {
+                    self.generating = None;
+                }
+                self.error = Some(s);
+            }
             _ => {}
         }
     }
 }
+
+/// Paints the prompt of the exchange being read at the top of the history area.
+fn sticky_prompt(ui: &Ui, rect: Rect, prompt: &str, fill: Color32) {
+    const MAX_CHARS: usize = 80;
+    const MARGIN: Vec2 = Vec2::new(8.0, 4.0);
+
+    let line = prompt.lines().next().unwrap_or_default();
+    let mut text = line.chars().take(MAX_CHARS).collect::<String>();
+    if text.len() < prompt.trim_end().len() {
+        text.push('…');
+    }
+
+    let color = ui.visuals().text_color();
+    let galley = ui.painter().layout_no_wrap(text, STATUS_FONT, color);
+    let height = galley.size().y + 2.0 * MARGIN.y;
+    let header = Rect::from_min_size(rect.min, Vec2::new(rect.width(), height));
+
+    let painter = ui.painter().with_clip_rect(header);
+    painter.rect_filled(header, 0.0, fill);
+    painter.galley(header.min + MARGIN, galley, color);
+    painter.hline(
+        header.x_range(),
+        header.bottom() - 0.5,
+        ui.visuals().widgets.noninteractive.bg_stroke,
+    );
+}
+
+/// Finds the history entry for a reply being generated.
+fn find_prompt(history: &mut [Prompt], prompt_id: PromptId) -> Option<&mut Prompt> {
+    // Pending replies are at the end of the history.
+    history
+        .iter_mut()
+        .rev()
+        .find(|prompt| prompt.prompt_id == Some(prompt_id))
+}
+
+/// Keeps the alternative at `idx` as the reply to the last prompt and discards the
+/// others, alternatives still being generated are stopped.
+fn keep_alternative(ctx: &mut AppContext, alternatives: &mut Vec<Alternative>, idx: usize) {
+    if alternatives.iter().any(|alternative| !alternative.done) {
+        ctx.controller.stop();
+    }
+
+    let Some(alternative) = std::mem::take(alternatives).into_iter().nth(idx) else {
+        return;
+    };
+
+    if let Some(prompt) = ctx.state.history.last_mut() {
+        prompt.reply = alternative.reply;
+        prompt.prompt_id = None;
+        prompt.seed = alternative.seed;
+    }
+}
+
+/// Finds an alternative reply to the last prompt.
+fn find_alternative(
+    alternatives: &mut [Alternative],
+    prompt_id: PromptId,
+) -> Option<&mut Alternative> {
+    alternatives
+        .iter_mut()
+        .find(|alternative| alternative.prompt_id == prompt_id)
+}
+
+/// Gets where to cut a reply with more than `max_lines` lines, 0 never collapses.
+fn collapse_at(reply: &str, max_lines: usize) -> Option<usize> {
+    if max_lines == 0 {
+        return None;
+    }
+
+    reply
+        .match_indices('\n')
+        .nth(max_lines - 1)
+        .map(|(idx, _)| idx)
+        .filter(|idx| !reply[*idx..].trim().is_empty())
+}
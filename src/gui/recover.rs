@@ -0,0 +1,61 @@
+use eframe::egui::*;
+
+use crate::gui::App;
+
+impl App {
+    /// Asks to restore the exchanges recovered from the journal.
+    pub fn recover_window(&mut self, ctx: &Context) {
+        let Some(recovered) = &self.recovered else {
+            return;
+        };
+
+        let mut restore = None;
+
+        Window::new("Recover reply")
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label("Coze didn't exit cleanly, restore the unsaved replies?");
+                ui.add_space(ui.spacing().item_spacing.y);
+
+                for prompt in recovered {
+                    let preview = prompt.prompt.chars().take(80).collect::<String>();
+                    ui.label(RichText::new(preview).monospace());
+                }
+
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    if ui.button("Restore").clicked() {
+                        restore = Some(true);
+                    }
+
+                    if ui.button("Discard").clicked() {
+                        restore = Some(false);
+                    }
+                });
+            });
+
+        match restore {
+            Some(true) => {
+                let history = &mut self.ctx.state.history;
+                for recovered in self.recovered.take().unwrap_or_default() {
+                    // The reply may have been partially saved by egui.
+                    let saved = history
+                        .iter_mut()
+                        .find(|p| p.prompt == recovered.prompt && p.info == recovered.info);
+                    match saved {
+                        Some(saved) => *saved = recovered,
+                        None => history.push(recovered),
+                    }
+                }
+            }
+            Some(false) => {
+                self.recovered = None;
+                self.journal.clear();
+            }
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use eframe::egui::{containers::scroll_area::ScrollAreaOutput, Context};
+
+/// Keeps the scroll position relative to the content when the scroll area width
+/// changes.
+///
+/// Bubbles reflow when the window is resized so the content height changes and the
+/// same pixel offset would point to a different part of the history.
+#[derive(Debug, Default)]
+pub struct ScrollAnchor {
+    width: f32,
+    ratio: f32,
+    at_bottom: bool,
+}
+
+impl ScrollAnchor {
+    /// Updates the anchor with the scroll area output, restoring the relative
+    /// position on resize frames.
+    pub fn update<R>(&mut self, ctx: &Context, output: &ScrollAreaOutput<R>) {
+        let width = output.inner_rect.width();
+        let content_height = output.content_size.y;
+        let max_offset = content_height - output.inner_rect.height();
+        let at_bottom = output.state.offset.y >= max_offset - 1.0;
+
+        // A scroll area at the bottom sticks to it so there is nothing to restore.
+        if self.width > 0.0 && width != self.width && !self.at_bottom {
+            let mut state = output.state;
+            state.offset.y = self.ratio * content_height;
+            state.store(ctx, output.id);
+        } else {
+            self.ratio = if content_height > 0.0 {
+                output.state.offset.y / content_height
+            } else {
+                0.0
+            };
+            self.at_bottom = at_bottom;
+        }
+
+        self.width = width;
+    }
+}
@@ -0,0 +1,64 @@
+use eframe::egui::*;
+
+use crate::gui::{App, Snippet};
+
+impl App {
+    pub fn snippets_window(&mut self, ctx: &Context) {
+        // Show snippets dialog.
+        if self.show_snippets {
+            Window::new("Snippets")
+                .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label("Type /name at the start of the prompt and press Tab to insert.");
+                    ui.add_space(ui.spacing().item_spacing.y);
+
+                    let mut remove = None;
+                    Grid::new("snippets_grid")
+                        .num_columns(3)
+                        .spacing([10.0, 4.0])
+                        .show(ui, |ui| {
+                            for (idx, snippet) in self.ctx.state.snippets.iter_mut().enumerate() {
+                                ui.add(
+                                    TextEdit::singleline(&mut snippet.name)
+                                        .hint_text("name")
+                                        .desired_width(100.0),
+                                );
+                                ui.add(
+                                    TextEdit::multiline(&mut snippet.text)
+                                        .hint_text("text")
+                                        .desired_rows(1)
+                                        .desired_width(280.0),
+                                );
+                                if ui.button("🗑").on_hover_text("Delete snippet").clicked() {
+                                    remove = Some(idx);
+                                }
+                                ui.end_row();
+                            }
+                        });
+
+                    if let Some(idx) = remove {
+                        self.ctx.state.snippets.remove(idx);
+                    }
+
+                    ui.separator();
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Add").clicked() {
+                            self.ctx.state.snippets.push(Snippet::default());
+                        }
+
+                        if ui.button("Close").clicked() {
+                            // Names are used to insert snippets so drop the ones without one.
+                            self.ctx
+                                .state
+                                .snippets
+                                .retain(|snippet| !snippet.name.trim().is_empty());
+                            self.show_snippets = false;
+                        }
+                    });
+                });
+        }
+    }
+}
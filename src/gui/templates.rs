@@ -0,0 +1,155 @@
+use anyhow::{bail, Result};
+use eframe::egui::*;
+
+use crate::gui::{App, Template};
+
+impl App {
+    pub fn templates_window(&mut self, ctx: &Context) {
+        // Show templates dialog.
+        if self.show_templates {
+            Window::new("Templates")
+                .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label("Use {{name}} placeholders for the values to fill when inserting.");
+                    ui.add_space(ui.spacing().item_spacing.y);
+
+                    let mut remove = None;
+                    Grid::new("templates_grid")
+                        .num_columns(3)
+                        .spacing([10.0, 4.0])
+                        .show(ui, |ui| {
+                            for (idx, template) in self.ctx.state.templates.iter_mut().enumerate() {
+                                ui.add(
+                                    TextEdit::singleline(&mut template.name)
+                                        .hint_text("name")
+                                        .desired_width(100.0),
+                                );
+                                ui.add(
+                                    TextEdit::multiline(&mut template.text)
+                                        .hint_text("Translate {{text}} to {{language}}")
+                                        .desired_rows(1)
+                                        .desired_width(280.0),
+                                );
+                                if ui.button("🗑").on_hover_text("Delete template").clicked() {
+                                    remove = Some(idx);
+                                }
+                                ui.end_row();
+                            }
+                        });
+
+                    if let Some(idx) = remove {
+                        self.ctx.state.templates.remove(idx);
+                    }
+
+                    ui.separator();
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Add").clicked() {
+                            self.ctx.state.templates.push(Template::default());
+                        }
+
+                        if ui.button("Close").clicked() {
+                            // Templates are chosen by name so drop the ones without one.
+                            self.ctx
+                                .state
+                                .templates
+                                .retain(|template| !template.name.trim().is_empty());
+                            self.show_templates = false;
+                        }
+                    });
+                });
+        }
+    }
+}
+
+impl Template {
+    /// Gets the names of the `{{name}}` placeholders in order of appearance.
+    pub fn variables(&self) -> Vec<String> {
+        let mut variables = Vec::<String>::new();
+        self.expand(|name| {
+            if !variables.iter().any(|v| v == name) {
+                variables.push(name.to_string());
+            }
+            None
+        });
+        variables
+    }
+
+    /// Replaces each placeholder with the value returned by `value`, placeholders
+    /// without a value are left as they are.
+    fn expand<'a>(&'a self, mut value: impl FnMut(&'a str) -> Option<&'a str>) -> String {
+        let mut text = String::with_capacity(self.text.len());
+        let mut rest = self.text.as_str();
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start..].find("}}") else {
+                break;
+            };
+
+            let end = start + len + 2;
+            let placeholder = &rest[start..end];
+            let name = placeholder[2..placeholder.len() - 2].trim();
+
+            text.push_str(&rest[..start]);
+            match (!name.is_empty()).then(|| value(name)).flatten() {
+                Some(value) => text.push_str(value),
+                None => text.push_str(placeholder),
+            }
+            rest = &rest[end..];
+        }
+
+        text.push_str(rest);
+        text
+    }
+}
+
+/// The values for the placeholders of a template being inserted in the prompt.
+#[derive(Debug)]
+pub struct TemplateForm {
+    template: Template,
+    /// The placeholders names and their values.
+    pub values: Vec<(String, String)>,
+    /// Validation error from the last fill.
+    pub error: Option<String>,
+}
+
+impl TemplateForm {
+    pub fn new(template: Template) -> Self {
+        let values = template
+            .variables()
+            .into_iter()
+            .map(|name| (name, String::new()))
+            .collect();
+
+        Self {
+            template,
+            values,
+            error: None,
+        }
+    }
+
+    /// Checks if the template has any placeholder to fill.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Gets the template name.
+    pub fn name(&self) -> &str {
+        &self.template.name
+    }
+
+    /// Builds the prompt from the template, all the values must be set.
+    pub fn fill(&self) -> Result<String> {
+        if let Some((name, _)) = self.values.iter().find(|(_, v)| v.trim().is_empty()) {
+            bail!("Please fill the {name} value");
+        }
+
+        Ok(self.template.expand(|name| {
+            self.values
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| value.as_str())
+        }))
+    }
+}
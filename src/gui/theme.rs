@@ -0,0 +1,53 @@
+use eframe::egui::*;
+use std::time::{Duration, Instant};
+
+use crate::gui::{App, UiMode};
+
+/// How long the new theme name is shown after the shortcut is used.
+const NOTICE_DURATION: Duration = Duration::from_millis(1500);
+
+impl UiMode {
+    /// Gets the mode the theme shortcut switches to, high contrast goes to light.
+    fn toggled(&self) -> Self {
+        match self {
+            UiMode::Light => UiMode::Dark,
+            UiMode::Dark | UiMode::HighContrast => UiMode::Light,
+        }
+    }
+}
+
+impl App {
+    /// Switches between light and dark mode with Ctrl+D (Cmd+D on macOS).
+    pub fn handle_theme_shortcut(&mut self, ctx: &Context) {
+        if ctx.input_mut(|i| i.consume_key(Modifiers::COMMAND, Key::D)) {
+            let ui_mode = self.ctx.state.ui_mode.toggled();
+            self.ctx.state.ui_mode = ui_mode;
+            ctx.set_visuals(ui_mode.visuals());
+            self.theme_notice = Some(Instant::now());
+        }
+    }
+
+    /// Briefly shows the theme selected with the shortcut.
+    pub fn theme_notice(&mut self, ctx: &Context) {
+        let Some(shown) = self.theme_notice else {
+            return;
+        };
+
+        if shown.elapsed() >= NOTICE_DURATION {
+            self.theme_notice = None;
+            return;
+        }
+
+        Area::new("theme_notice")
+            .anchor(Align2::CENTER_TOP, [0.0, 40.0])
+            .interactable(false)
+            .show(ctx, |ui| {
+                Frame::popup(ui.style()).show(ui, |ui| {
+                    let text = format!("{} mode", self.ctx.state.ui_mode.description());
+                    ui.label(RichText::new(text).font(FontId::new(16.0, FontFamily::Monospace)));
+                });
+            });
+
+        ctx.request_repaint_after(NOTICE_DURATION - shown.elapsed());
+    }
+}
@@ -1,27 +1,57 @@
 //! Models configuration and loading.
-use anyhow::Result;
-use candle::{DType, Tensor};
+use anyhow::{bail, Result};
+use candle::{
+    quantized::{gguf_file, GgmlDType},
+    DType, Tensor,
+};
+use fancy_regex::Regex;
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use strum::{EnumIter, IntoEnumIterator};
+use sysinfo::System;
 
-pub use cache::ModelsCache;
-pub use config::{ModelConfig, ModelParams};
+pub use cache::{CachedModel, DownloadStatus, ModelsCache, TokenizerError};
+pub use config::{
+    env_seed, ChatTemplate, ModelConfig, ModelOptions, ModelParams, ReasoningTags, RoleLabels,
+};
+pub use grammar::JsonGrammar;
 
 mod cache;
 mod config;
+mod gguf_tokenizer;
+mod grammar;
+mod qllama;
 mod qmistral;
 mod qstablelm;
-mod qzephyr;
 mod transformers;
 
-#[derive(Debug, Clone, Copy, EnumIter)]
+/// Extra memory needed on top of the weights size to run a model.
+const MEMORY_OVERHEAD: f64 = 1.25;
+
+/// Number of context tokens reserved for the reply.
+const REPLY_TOKENS: usize = 256;
+
+/// Number of previous tokens decoded with the new ones to get the new text.
+const DECODE_LOOKBACK: usize = 5;
+
+/// Maximum number of previous tokens decoded when the lookback window starts in the
+/// middle of a character.
+const MAX_DECODE_LOOKBACK: usize = 64;
+
+/// Maximum number of byte tokens that decode to a single UTF-8 character.
+const MAX_CHAR_TOKENS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter, Serialize, Deserialize)]
 pub enum ModelId {
     Mistral7bInstructV02,
     Mistral7B,
     Zephyr7bBeta,
     StableLm2Zephyr,
+    TinyLlamaChat,
 }
 
 impl ModelId {
@@ -37,6 +67,15 @@ impl ModelId {
                 model_filename: "mistral-7b-instruct-v0.2.Q4_K_S.gguf",
                 tokenizer_repo: "mistralai/Mistral-7B-Instruct-v0.2",
                 tokenizer_filename: "tokenizer.json",
+                context_length: 32768,
+                add_bos: true,
+                prompt_prefix: "",
+                prompt_template: PromptTemplate {
+                    template: "{user} {prompt} {assistant}",
+                    system: "",
+                    user: "[INST]",
+                    assistant: "[/INST]",
+                },
             },
             ModelId::Mistral7B => ModelSpec {
                 model_id: *self,
@@ -47,6 +86,17 @@ impl ModelId {
                 model_filename: "model-q4k.gguf",
                 tokenizer_repo: "mistralai/Mistral-7B-v0.1",
                 tokenizer_filename: "tokenizer.json",
+                // The candle model attends to the last 4096 tokens of the context with
+                // a sliding window like the original weights.
+                context_length: 32768,
+                add_bos: true,
+                prompt_prefix: "",
+                prompt_template: PromptTemplate {
+                    template: "{prompt}",
+                    system: "",
+                    user: "",
+                    assistant: "",
+                },
             },
             ModelId::Zephyr7bBeta => ModelSpec {
                 model_id: *self,
@@ -57,6 +107,18 @@ impl ModelId {
                 model_filename: "zephyr-7b-beta.Q4_K_M.gguf",
                 tokenizer_repo: "mistralai/Mistral-7B-Instruct-v0.2",
                 tokenizer_filename: "tokenizer.json",
+                // Zephyr is fine-tuned from Mistral 7B v0.1 that uses a 4096 tokens
+                // sliding window, the llama transformer has no sliding window and
+                // attends to the whole context so it is capped to the window size.
+                context_length: 4096,
+                add_bos: true,
+                prompt_prefix: "",
+                prompt_template: PromptTemplate {
+                    template: "{system}\n</s>\n{user}\n{prompt}</s>\n{assistant} ",
+                    system: "<|system|>",
+                    user: "<|user|>",
+                    assistant: "<|assistant|>",
+                },
             },
             ModelId::StableLm2Zephyr => ModelSpec {
                 model_id: *self,
@@ -67,6 +129,35 @@ impl ModelId {
                 model_filename: "stablelm-2-zephyr-1_6b-Q4_1.gguf",
                 tokenizer_repo: "stabilityai/stablelm-2-zephyr-1_6b",
                 tokenizer_filename: "tokenizer.json",
+                context_length: 4096,
+                add_bos: true,
+                prompt_prefix: "",
+                prompt_template: PromptTemplate {
+                    template: "{user}\n{prompt}<|endoftext|>\n",
+                    system: "<|system|>",
+                    user: "<|user|>",
+                    assistant: "<|assistant|>",
+                },
+            },
+            ModelId::TinyLlamaChat => ModelSpec {
+                model_id: *self,
+                name: "TinyLlama Chat 1.1B v1.0",
+                size: 668788096,
+                cache_dir: "tinyllama_chat_1_1b_v10",
+                model_repo: "TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF",
+                model_filename: "tinyllama-1.1b-chat-v1.0.Q4_K_M.gguf",
+                // The tokenizer is built from the vocabulary in the weights file.
+                tokenizer_repo: "",
+                tokenizer_filename: "",
+                context_length: 2048,
+                add_bos: true,
+                prompt_prefix: "",
+                prompt_template: PromptTemplate {
+                    template: "{user}\n{prompt}</s>\n{assistant}\n",
+                    system: "<|system|>",
+                    user: "<|user|>",
+                    assistant: "<|assistant|>",
+                },
             },
         }
     }
@@ -77,18 +168,161 @@ impl ModelId {
     }
 
     /// Create a model instance.
-    pub fn model(&self, params: ModelParams) -> Result<Box<dyn Model>> {
+    ///
+    /// The weights are loaded from `model_path` if given, otherwise from the cache.
+    pub fn model(
+        &self,
+        params: ModelParams,
+        model_path: Option<PathBuf>,
+    ) -> Result<Box<dyn Model>> {
+        let cache = ModelsCache::new()?;
+        let mut cached_model = cache.cached_model(*self);
+        if let Some(model_path) = model_path {
+            cached_model.model_path = model_path;
+        }
+
+        let cm = &cached_model;
         match self {
-            ModelId::StableLm2Zephyr => Ok(Box::new(qstablelm::QuantizedStableLM::new(params)?)),
-            ModelId::Zephyr7bBeta => Ok(Box::new(qzephyr::QuantizedZephyr::new(params)?)),
-            ModelId::Mistral7bInstructV02 => {
-                Ok(Box::new(qmistral::QuantizedMistralInstruct::new(params)?))
+            ModelId::StableLm2Zephyr => {
+                Ok(Box::new(qstablelm::QuantizedStableLM::new(params, cm)?))
             }
-            ModelId::Mistral7B => Ok(Box::new(qmistral::QuantizedMistral7B::new(params)?)),
+            ModelId::Zephyr7bBeta | ModelId::TinyLlamaChat => {
+                Ok(Box::new(qllama::QuantizedLlama::new(*self, params, cm)?))
+            }
+            ModelId::Mistral7bInstructV02 => Ok(Box::new(qmistral::QuantizedMistralInstruct::new(
+                params, cm,
+            )?)),
+            ModelId::Mistral7B => Ok(Box::new(qmistral::QuantizedMistral7B::new(params, cm)?)),
+        }
+    }
+}
+
+/// Checks that the given file is a GGUF file by reading its magic header.
+pub fn check_gguf(path: &Path) -> Result<()> {
+    let mut magic = [0u8; 4];
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map_err(|e| anyhow::anyhow!("Unable to read {}: {e}", path.display()))?;
+
+    if &magic != b"GGUF" {
+        bail!("{} is not a GGUF file", path.display());
+    }
+
+    Ok(())
+}
+
+/// How well a model is expected to fit in the system memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryFit {
+    /// The model uses at most half of the system memory.
+    Recommended,
+    /// The model uses most of the system memory.
+    Tight,
+    /// The model needs about as much memory as the system has or more.
+    MayNotFit,
+}
+
+impl MemoryFit {
+    /// Gets the value description.
+    pub fn description(&self) -> &'static str {
+        match self {
+            MemoryFit::Recommended => "Recommended",
+            MemoryFit::Tight => "Tight",
+            MemoryFit::MayNotFit => "May not fit",
         }
     }
 }
 
+/// Gets the total system memory in bytes, 0 if it cannot be detected.
+pub fn total_memory() -> usize {
+    let mut system = System::new();
+    system.refresh_memory();
+    system.total_memory() as usize
+}
+
+/// Name of the device the models run on, all the models run on the CPU.
+pub fn device_name() -> &'static str {
+    "CPU"
+}
+
+/// Describes the CPU instruction set used by the quantized kernels and if the build
+/// uses it.
+pub fn cpu_isa() -> String {
+    let (isa, fast) = if cfg!(target_arch = "x86_64") {
+        ("AVX2", candle::utils::with_avx())
+    } else if cfg!(target_arch = "aarch64") {
+        ("NEON", candle::utils::with_neon())
+    } else {
+        ("Generic", false)
+    };
+
+    let detected = if cpu_has_simd() { isa } else { "no SIMD" };
+    let path = if fast { "fast path" } else { "slow path" };
+    format!("CPU {detected}, {path}")
+}
+
+/// Gets a warning if the build and the CPU features don't match, the quantized
+/// kernels are much slower without SIMD instructions.
+pub fn cpu_warning() -> Option<String> {
+    let built = candle::utils::with_avx() || candle::utils::with_neon();
+    match (built, cpu_has_simd()) {
+        (true, false) => Some(
+            "This build uses SIMD instructions the CPU doesn't support and may crash, \
+            rebuild it without `target-cpu=native`."
+                .to_string(),
+        ),
+        (false, true) => Some(
+            "This build doesn't use the SIMD instructions of the CPU and replies are slow, \
+            rebuild it with RUSTFLAGS=\"-C target-cpu=native\"."
+                .to_string(),
+        ),
+        (false, false) => {
+            Some("The CPU has no SIMD support for the models, replies are slow.".to_string())
+        }
+        (true, true) => None,
+    }
+}
+
+/// Checks if the CPU supports the SIMD instructions of the quantized kernels.
+fn cpu_has_simd() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        std::arch::is_x86_feature_detected!("avx2") && std::arch::is_x86_feature_detected!("fma")
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("neon")
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Checks there is enough available memory to load all the given models.
+pub fn check_memory(model_ids: &[ModelId]) -> Result<()> {
+    let mut system = System::new();
+    system.refresh_memory();
+
+    let available = system.available_memory() as usize;
+    let required = model_ids
+        .iter()
+        .map(|model_id| (model_id.spec().size as f64 * MEMORY_OVERHEAD) as usize)
+        .sum::<usize>();
+
+    if available > 0 && required > available {
+        bail!(
+            "Not enough memory to load the models, required {}M available {}M",
+            required / (1 << 20),
+            available / (1 << 20)
+        );
+    }
+
+    Ok(())
+}
+
 /// A model specification used to loading and UI.
 #[derive(Debug, Clone, Copy)]
 pub struct ModelSpec {
@@ -108,18 +342,482 @@ pub struct ModelSpec {
     pub tokenizer_repo: &'static str,
     /// Tokenizer path
     pub tokenizer_filename: &'static str,
+    /// Maximum number of tokens the model can attend to.
+    pub context_length: usize,
+    /// Prompt template.
+    pub prompt_template: PromptTemplate,
+    /// Let the tokenizer add the beginning of sequence token.
+    pub add_bos: bool,
+    /// Text inserted before the formatted prompt.
+    pub prompt_prefix: &'static str,
+}
+
+impl ModelSpec {
+    /// Formats the prompt using the model template or the chat template in the
+    /// parameters.
+    ///
+    /// The parameters role labels replace the template default labels, their
+    /// prompt prefix and suffix are added around the user prompt, and their reply
+    /// prefix after the assistant turn opener.
+    ///
+    /// Returns the template text before the user prompt, the user prompt with its
+    /// prefix and suffix, and the template text after it.
+    fn format_prompt(&self, prompt: &str, params: &ModelParams) -> (String, String, String) {
+        let template = match params.chat_template {
+            ChatTemplate::Model => &self.prompt_template,
+            ChatTemplate::ChatMl => &CHATML_TEMPLATE,
+        };
+        let (system, user, assistant) = match &params.role_labels {
+            Some(labels) => (
+                labels.system.as_str(),
+                labels.user.as_str(),
+                labels.assistant.as_str(),
+            ),
+            None => (template.system, template.user, template.assistant),
+        };
+
+        let labels = |text: &str| {
+            text.replace("{system}", system)
+                .replace("{user}", user)
+                .replace("{assistant}", assistant)
+        };
+
+        let (before, after) = template
+            .template
+            .split_once("{prompt}")
+            .unwrap_or((template.template, ""));
+        let prompt = format!("{}{prompt}{}", params.prompt_prefix, params.prompt_suffix);
+        (labels(before), prompt, labels(after) + &params.reply_prefix)
+    }
+
+    /// Gets the label that opens the assistant turn for the given parameters.
+    pub fn assistant_label<'a>(&self, params: &'a ModelParams) -> &'a str {
+        match (&params.role_labels, params.chat_template) {
+            (Some(labels), _) => &labels.assistant,
+            (None, ChatTemplate::Model) => self.prompt_template.assistant,
+            (None, ChatTemplate::ChatMl) => CHATML_TEMPLATE.assistant,
+        }
+    }
+
+    /// Encodes the prompt formatted with `format_prompt` and prefixed with the model
+    /// prompt prefix.
+    ///
+    /// A beginning of sequence token that is both in the prefix and added by the
+    /// tokenizer is kept only once.
+    pub fn encode_prompt(
+        &self,
+        tokenizer: &tokenizers::Tokenizer,
+        prompt: &str,
+        params: &ModelParams,
+    ) -> Result<Vec<u32>> {
+        self.encode_turn(tokenizer, prompt, params, true)
+    }
+
+    /// Encodes the prompt formatted with `format_prompt`, the first turn of a
+    /// conversation starts with the model prompt prefix and the beginning of sequence
+    /// token.
+    ///
+    /// Special tokens are only matched in the template, if the user prompt contains
+    /// the text of a special token like `</s>` its tokens are encoded again as
+    /// ordinary text so that the user can't end the turn.
+    fn encode_turn(
+        &self,
+        tokenizer: &tokenizers::Tokenizer,
+        prompt: &str,
+        params: &ModelParams,
+        first_turn: bool,
+    ) -> Result<Vec<u32>> {
+        let (before, prompt, after) = self.format_prompt(prompt, params);
+        let prefix = if first_turn { self.prompt_prefix } else { "" };
+        let text = format!("{prefix}{before}{prompt}{after}");
+        let encoding = tokenizer
+            .encode(text.as_str(), first_turn && self.add_bos)
+            .map_err(anyhow::Error::msg)?;
+
+        let mut tokens = encoding.get_ids().to_vec();
+        let special = encoding.get_special_tokens_mask();
+        let offsets = encoding.get_offsets();
+        // Only the tokens added by the tokenizer are in the special tokens mask, a
+        // beginning of sequence token in the prefix text is not.
+        let repeated_bos =
+            first_turn && tokens.len() > 1 && tokens[0] == tokens[1] && special[0] == 1;
+
+        // Tokens that overlap the user prompt, the ones added by the tokenizer have
+        // empty offsets.
+        let start = prefix.len() + before.len();
+        let end = start + prompt.len();
+        let in_prompt = |idx: usize| {
+            let (s, e) = offsets[idx];
+            s < e && s < end && e > start
+        };
+
+        let first = (0..tokens.len()).find(|&idx| in_prompt(idx));
+        let last = (0..tokens.len()).rfind(|&idx| in_prompt(idx));
+        if let (Some(first), Some(last)) = (first, last) {
+            let span = offsets[first].0..offsets[last].1;
+            let added_tokens = tokenizer.get_added_tokens_decoder();
+            let is_special = |token| added_tokens.get(token).is_some_and(|t| t.special);
+            if tokens[first..=last].iter().any(is_special) {
+                let mut plain = tokenizer.clone();
+                plain.set_encode_special_tokens(true);
+                let encoding = plain
+                    .encode(&text[span], false)
+                    .map_err(anyhow::Error::msg)?;
+                tokens.splice(first..=last, encoding.get_ids().iter().copied());
+            }
+        }
+
+        if repeated_bos {
+            tokens.remove(0);
+        }
+
+        Ok(tokens)
+    }
+
+    /// Estimates how well this model fits in the given total memory, returns `None` if
+    /// the total memory is unknown.
+    pub fn memory_fit(&self, total_memory: usize) -> Option<MemoryFit> {
+        if total_memory == 0 {
+            return None;
+        }
+
+        let required = self.size as f64 * MEMORY_OVERHEAD;
+        let ratio = required / total_memory as f64;
+        Some(if ratio <= 0.5 {
+            MemoryFit::Recommended
+        } else if ratio <= 0.9 {
+            MemoryFit::Tight
+        } else {
+            MemoryFit::MayNotFit
+        })
+    }
+
+    /// Maximum number of tokens kept in the KV cache for the given parameters.
+    pub fn kv_cache_len(&self, params: &ModelParams) -> usize {
+        match params.kv_cache_tokens {
+            0 => self.context_length,
+            tokens => tokens,
+        }
+    }
+
+    /// Maximum number of prompt tokens, leaves some room in the context for the reply.
+    pub fn max_prompt_tokens(&self) -> usize {
+        self.context_length.saturating_sub(REPLY_TOKENS)
+    }
+}
+
+/// A model prompt template.
+///
+/// The `{system}`, `{user}`, and `{assistant}` placeholders are replaced with the role
+/// labels and `{prompt}` with the user prompt.
+#[derive(Debug, Clone, Copy)]
+pub struct PromptTemplate {
+    /// The template text.
+    pub template: &'static str,
+    /// Default system label.
+    pub system: &'static str,
+    /// Default user label.
+    pub user: &'static str,
+    /// Default assistant label.
+    pub assistant: &'static str,
+}
+
+/// The ChatML prompt template.
+const CHATML_TEMPLATE: PromptTemplate = PromptTemplate {
+    template: "{user}\n{prompt}<|im_end|>\n{assistant}\n",
+    system: "<|im_start|>system",
+    user: "<|im_start|>user",
+    assistant: "<|im_start|>assistant",
+};
+
+impl ModelSpec {
+    /// Gets the capabilities of this model with the given tokenizer.
+    fn capabilities(&self, tokenizer: &tokenizers::Tokenizer, eos_token: u32) -> Capabilities {
+        let im_end = tokenizer.token_to_id("<|im_end|>");
+        Capabilities {
+            context_length: self.context_length,
+            system_prompt: self.prompt_template.template.contains("{system}"),
+            chat_ml: im_end.is_some(),
+            stop_tokens: std::iter::once(eos_token).chain(im_end).collect(),
+        }
+    }
+}
+
+/// Gets the token that ends a reply for the given chat template.
+///
+/// ChatML replies end with `<|im_end|>`, models that don't have it in their
+/// vocabulary fall back to the end of stream token.
+fn stop_token(tokenizer: &tokenizers::Tokenizer, eos_token: u32, params: &ModelParams) -> u32 {
+    match params.chat_template {
+        ChatTemplate::Model => eos_token,
+        ChatTemplate::ChatMl => tokenizer.token_to_id("<|im_end|>").unwrap_or(eos_token),
+    }
+}
+
+/// Counts prompt tokens so that the UI can check a prompt fits the model context
+/// before sending it.
+#[derive(Debug)]
+pub struct PromptCounter {
+    spec: ModelSpec,
+    tokenizer: tokenizers::Tokenizer,
+}
+
+impl PromptCounter {
+    /// Creates a counter using the cached model tokenizer.
+    pub fn new(model_id: ModelId) -> Result<Self> {
+        let cache = ModelsCache::new()?;
+        let cached_model = cache.cached_model(model_id);
+        let tokenizer = cached_model.load_tokenizer()?;
+
+        Ok(Self {
+            spec: model_id.spec(),
+            tokenizer,
+        })
+    }
+
+    /// Counts the tokens for the given prompt including the model template, the
+    /// parameters template options are applied like for generation.
+    pub fn count(&self, prompt: &str, params: &ModelParams) -> usize {
+        self.spec
+            .encode_prompt(&self.tokenizer, prompt, params)
+            .map(|tokens| tokens.len())
+            .unwrap_or_default()
+    }
+}
+
+/// Gets the tokens to forward for a prompt and the position of the first one.
+///
+/// When the context is kept and `next_pos` is after previous turns in the KV cache,
+/// the prompt continues from there starting with the stop token that ended the last
+/// reply. Otherwise, or if it doesn't fit the context, the whole prompt is returned
+/// at position 0 and the model must clear its cache.
+fn prompt_tokens(
+    spec: &ModelSpec,
+    tokenizer: &tokenizers::Tokenizer,
+    prompt: &str,
+    params: &ModelParams,
+    eos_token: u32,
+    next_pos: usize,
+) -> Result<(Vec<u32>, usize)> {
+    if params.keep_context && next_pos > 0 {
+        let tokens = std::iter::once(eos_token)
+            .chain(spec.encode_turn(tokenizer, prompt, params, false)?)
+            .collect::<Vec<_>>();
+        if next_pos + tokens.len() <= spec.max_prompt_tokens() {
+            return Ok((tokens, next_pos));
+        }
+    }
+
+    let mut tokens = spec.encode_prompt(tokenizer, prompt, params)?;
+    if params.truncate_prompt {
+        tokens = truncate_prompt(tokens, spec);
+    }
+
+    Ok((tokens, 0))
+}
+
+/// Drops the oldest prompt tokens that don't fit the model context.
+///
+/// The first token is kept as it is usually the beginning of sequence token.
+fn truncate_prompt(tokens: Vec<u32>, spec: &ModelSpec) -> Vec<u32> {
+    let max_tokens = spec.max_prompt_tokens().max(1);
+    if tokens.len() <= max_tokens {
+        tokens
+    } else {
+        let skip = tokens.len() - max_tokens + 1;
+        tokens[..1].iter().chain(&tokens[skip..]).copied().collect()
+    }
 }
 
 /// Interface to an inference model.
-pub trait Model {
+pub trait Model: Send {
     /// Initialize the model with a prompt.
     fn prompt(&mut self, prompt: &str, params: &ModelParams) -> Result<TokensStream>;
 
-    /// Runs the forward step for the given tokens.
-    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<u32>;
+    /// Encodes a prompt formatted with the model template.
+    fn encode_prompt(&self, prompt: &str, params: &ModelParams) -> Result<Vec<u32>>;
+
+    /// Runs the forward step for the given tokens and samples the next token.
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Sample>;
 
     /// Decode the given tokens.
     fn decode(&mut self, tokens: &[u32]) -> Result<String>;
+
+    /// Gets what the model supports.
+    fn capabilities(&self) -> Capabilities;
+
+    /// Gets the size and quantization of the weights.
+    fn info(&self) -> ModelInfo;
+
+    /// Forgets the previous turns kept in the context, the next prompt starts a new
+    /// conversation.
+    fn clear_context(&mut self);
+
+    /// Gets the number of tokens in the context, the position of the next token.
+    fn context_used(&self) -> usize;
+}
+
+/// Features of a loaded model used to adapt the UI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// Maximum number of tokens the model can attend to.
+    pub context_length: usize,
+    /// The model template has a system role.
+    pub system_prompt: bool,
+    /// The model vocabulary has the ChatML tags.
+    pub chat_ml: bool,
+    /// Tokens that can end a reply.
+    pub stop_tokens: Vec<u32>,
+}
+
+/// Size and quantization of the loaded weights, read from the GGUF file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelInfo {
+    /// Number of parameters in the weights tensors.
+    pub parameters: Option<usize>,
+    /// Quantization format of the weights.
+    pub quantization: Option<String>,
+}
+
+impl ModelInfo {
+    /// Reads the info from the GGUF header.
+    ///
+    /// The parameters are counted from the tensors shapes, the quantization is the
+    /// file type in the metadata or the type of most of the weights if it is missing.
+    pub fn from_gguf(content: &gguf_file::Content) -> Self {
+        let parameters = content
+            .tensor_infos
+            .values()
+            .map(|info| info.shape.elem_count())
+            .sum::<usize>();
+
+        let quantization = content
+            .metadata
+            .get("general.file_type")
+            .and_then(|file_type| file_type.to_u32().ok())
+            .and_then(file_type_name)
+            .or_else(|| {
+                let mut counts = HashMap::new();
+                for info in content.tensor_infos.values() {
+                    *counts.entry(dtype_name(info.ggml_dtype)).or_insert(0) +=
+                        info.shape.elem_count();
+                }
+                counts
+                    .into_iter()
+                    .max_by_key(|(_, count)| *count)
+                    .map(|(name, _)| name)
+            });
+
+        Self {
+            parameters: (parameters > 0).then_some(parameters),
+            quantization: quantization.map(str::to_string),
+        }
+    }
+
+    /// Reads the info from the header of a GGUF file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(path))?;
+        Ok(Self::from_gguf(&content))
+    }
+
+    /// Describes the info as `1.6B · Q4_1`, missing values are `unknown`.
+    pub fn description(&self) -> String {
+        let parameters = match self.parameters {
+            Some(n) if n >= 1_000_000_000 => format!("{:.1}B", n as f64 / 1e9),
+            Some(n) => format!("{:.0}M", n as f64 / 1e6),
+            None => "unknown".to_string(),
+        };
+
+        let quantization = self.quantization.as_deref().unwrap_or("unknown");
+        format!("{parameters} · {quantization}")
+    }
+}
+
+/// Gets the name of a llama.cpp GGUF file type.
+fn file_type_name(file_type: u32) -> Option<&'static str> {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+/// Gets the name of a tensor type.
+fn dtype_name(dtype: GgmlDType) -> &'static str {
+    match dtype {
+        GgmlDType::F32 => "F32",
+        GgmlDType::F16 => "F16",
+        GgmlDType::Q4_0 => "Q4_0",
+        GgmlDType::Q4_1 => "Q4_1",
+        GgmlDType::Q5_0 => "Q5_0",
+        GgmlDType::Q5_1 => "Q5_1",
+        GgmlDType::Q8_0 => "Q8_0",
+        GgmlDType::Q8_1 => "Q8_1",
+        GgmlDType::Q2K => "Q2_K",
+        GgmlDType::Q3K => "Q3_K",
+        GgmlDType::Q4K => "Q4_K",
+        GgmlDType::Q5K => "Q5_K",
+        GgmlDType::Q6K => "Q6_K",
+        GgmlDType::Q8K => "Q8_K",
+    }
+}
+
+/// Why token generation ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopReason {
+    /// The model generated the end of stream token.
+    Eos,
+    /// The reply reached the consecutive newlines limit.
+    Newlines,
+    /// The reply was repeating the same sequence of tokens.
+    Repeat,
+    /// The reply matched the stop regex.
+    Regex,
+    /// Generation was interrupted by the user.
+    User,
+    /// The reply could not be generated.
+    Error,
+}
+
+impl StopReason {
+    /// Gets the reason description.
+    pub fn description(&self) -> &'static str {
+        match self {
+            StopReason::Eos => "eos",
+            StopReason::Newlines => "newlines",
+            StopReason::Repeat => "repeat",
+            StopReason::Regex => "regex",
+            StopReason::User => "user",
+            StopReason::Error => "error",
+        }
+    }
+}
+
+/// A sampled token.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    /// The token id.
+    pub token: u32,
+    /// Probability of the token in the model distribution before top-k and
+    /// temperature are applied.
+    pub prob: f32,
 }
 
 /// Generates tokens for a model.
@@ -129,43 +827,255 @@ pub struct TokensStream {
     prompt_tokens_len: usize,
     tokens: Vec<u32>,
     consumed: bool,
+    stop_repeat_ngram: usize,
+    stop_repeat_count: usize,
+    stop_newlines: usize,
+    stop_regex: Option<Regex>,
+    reply: String,
+    stop_reason: StopReason,
+    rest: Option<String>,
+    prob: f32,
+    opener: Option<String>,
+    template: String,
 }
 
 impl TokensStream {
     /// Creates a new stream.
-    pub fn new(eos_token: u32, prompt_tokens_len: usize) -> Self {
+    pub fn new(eos_token: u32, prompt_tokens_len: usize, params: &ModelParams) -> Self {
         Self {
             eos_token,
             prompt_tokens_len,
             tokens: vec![0],
             consumed: false,
+            stop_repeat_ngram: params.stop_repeat_ngram,
+            stop_repeat_count: params.stop_repeat_count,
+            stop_newlines: params.stop_newlines,
+            stop_regex: params.stop_regex.clone(),
+            reply: String::new(),
+            stop_reason: StopReason::Eos,
+            rest: None,
+            prob: 1.0,
+            opener: None,
+            template: String::new(),
         }
     }
 
+    /// Strips the given assistant turn opener if the reply starts with it, some
+    /// models echo the role tag before the reply text.
+    pub fn with_opener(mut self, opener: &str) -> Self {
+        let opener = opener.trim();
+        self.opener = (!opener.is_empty()).then(|| opener.to_string());
+        self
+    }
+
+    /// Sets the prompt text the tokens were encoded from.
+    pub fn with_template(mut self, template: String) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Gets the prompt formatted with the template, including special tokens, as
+    /// it was given to the model.
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// Why generation stopped, only meaningful once the stream is consumed.
+    pub fn stop_reason(&self) -> StopReason {
+        self.stop_reason
+    }
+
+    /// Number of tokens generated so far, not counting the end of sequence token.
+    pub fn generated_tokens(&self) -> usize {
+        // The first token is a placeholder for the decode window.
+        self.tokens.len() - 1
+    }
+
+    /// Lowest probability of the tokens in the text returned by the last call to
+    /// `next` or `finish`.
+    pub fn probability(&self) -> f32 {
+        self.prob
+    }
+
+    /// Takes the text held back when the end of sequence token was generated, this
+    /// returns `Some` at most once after `next` returns `None`.
+    pub fn finish(&mut self) -> Option<String> {
+        let text = self.rest.take()?;
+        self.stop_at_regex(text)
+    }
+
     /// Generates the next token.
+    ///
+    /// The reply ends when the stop regex matches the text generated so far, the
+    /// match and the text after it are dropped.
     pub fn next(&mut self, model: &mut dyn Model) -> Result<Option<String>> {
+        let text = self.next_reply(model)?;
+        Ok(text.and_then(|text| self.stop_at_regex(text)))
+    }
+
+    /// Adds the text to the reply and ends the stream if the stop regex matches it.
+    ///
+    /// Only the new text can be trimmed, the part of a match in text that has already
+    /// been returned is kept.
+    fn stop_at_regex(&mut self, text: String) -> Option<String> {
+        let Some(regex) = &self.stop_regex else {
+            return Some(text);
+        };
+
+        let start = self.reply.len();
+        self.reply.push_str(&text);
+        match regex.find(&self.reply) {
+            Ok(Some(m)) => {
+                self.consumed = true;
+                self.rest = None;
+                self.stop_reason = StopReason::Regex;
+                let end = m.start().saturating_sub(start);
+                (end > 0).then(|| text[..end].to_string())
+            }
+            _ => Some(text),
+        }
+    }
+
+    /// Generates the text of the next tokens.
+    ///
+    /// The text at the start of the reply is held back until it is known if it starts
+    /// with the assistant turn opener, which is removed with the whitespace after it.
+    fn next_reply(&mut self, model: &mut dyn Model) -> Result<Option<String>> {
+        let Some(opener) = self.opener.take() else {
+            return self.next_text(model);
+        };
+
+        let mut text = String::new();
+        let mut prob = 1.0f32;
+        while let Some(s) = self.next_text(model)? {
+            text.push_str(&s);
+            prob = prob.min(self.prob);
+
+            let trimmed = text.trim_start();
+            if let Some(reply) = trimmed.strip_prefix(&opener) {
+                let reply = reply.trim_start();
+                if reply.is_empty() {
+                    return self.next_text(model);
+                }
+
+                self.prob = prob;
+                return Ok(Some(reply.to_string()));
+            } else if !opener.starts_with(trimmed) {
+                self.prob = prob;
+                return Ok(Some(text));
+            }
+        }
+
+        // The reply ended while it could still be the start of the opener.
+        self.prob = prob;
+        Ok((!text.is_empty()).then_some(text))
+    }
+
+    /// Generates the text of the next tokens.
+    ///
+    /// Text that ends with an incomplete character or with newlines that may reach
+    /// the newlines limit is held back until more tokens are generated, call
+    /// `finish` at the end of the stream to get it. Bytes that are still invalid after
+    /// the longest character are returned as replacement characters.
+    fn next_text(&mut self, model: &mut dyn Model) -> Result<Option<String>> {
         if self.consumed {
             Ok(None)
         } else {
-            let decode_idx = self.tokens.len().saturating_sub(5);
-            let prev_text = model.decode(&self.tokens[decode_idx..])?;
+            let (decode_idx, prev_text) = self.decode_window(model)?;
+            self.prob = 1.0;
+            let mut incomplete_tokens = 0;
             loop {
-                let token = self.next_token(model)?;
+                let Sample { token, prob } = self.next_token(model)?;
                 if token == self.eos_token {
                     self.consumed = true;
+                    let text = model.decode(&self.tokens[decode_idx..])?;
+                    if text.len() > prev_text.len() {
+                        let text = new_text(&text, &prev_text);
+                        self.rest = Some(text.to_string());
+                    }
                     return Ok(None);
                 }
 
+                self.prob = self.prob.min(prob);
                 self.tokens.push(token);
+                if self.has_repeated_cycle() {
+                    self.consumed = true;
+                    self.stop_reason = StopReason::Repeat;
+                    return Ok(None);
+                }
+
+                // Bytes of a multi-byte character split across tokens decode to the
+                // replacement character until the last byte is generated, if it is
+                // still there after the longest character the bytes are invalid and
+                // the replacement is returned so that the stream doesn't stall.
                 let text = model.decode(&self.tokens[decode_idx..])?;
-                if text.len() > prev_text.len() {
-                    return Ok(Some(text.trim_start_matches(&prev_text).to_string()));
+                if text.ends_with(char::REPLACEMENT_CHARACTER) {
+                    incomplete_tokens += 1;
+                } else {
+                    incomplete_tokens = 0;
+                }
+
+                let incomplete = incomplete_tokens > 0 && incomplete_tokens < MAX_CHAR_TOKENS;
+                if text.len() > prev_text.len() && !incomplete {
+                    let text = new_text(&text, &prev_text);
+                    if self.stop_newlines == 0 {
+                        return Ok(Some(text.to_string()));
+                    }
+
+                    // Hold back trailing newlines until we know if they are followed by
+                    // more text or we reached the newlines limit.
+                    let trimmed = text.trim_end_matches('\n');
+                    if text.len() - trimmed.len() >= self.stop_newlines {
+                        self.consumed = true;
+                        self.stop_reason = StopReason::Newlines;
+                        return Ok((!trimmed.is_empty()).then(|| trimmed.to_string()));
+                    } else if trimmed.len() == text.len() {
+                        return Ok(Some(text.to_string()));
+                    }
                 }
             }
         }
     }
 
-    fn next_token(&mut self, model: &mut dyn Model) -> Result<u32> {
+    /// Checks if the last tokens are the same sequence repeated `stop_repeat_count`
+    /// times, for sequences up to `stop_repeat_ngram` tokens.
+    fn has_repeated_cycle(&self) -> bool {
+        if self.stop_repeat_count < 2 {
+            return false;
+        }
+
+        (1..=self.stop_repeat_ngram).any(|n| {
+            let len = n * self.stop_repeat_count;
+            if len > self.tokens.len() {
+                return false;
+            }
+
+            let tail = &self.tokens[self.tokens.len() - len..];
+            tail.chunks(n).all(|chunk| chunk == &tail[..n])
+        })
+    }
+
+    /// Gets the start of the previous tokens decoded with the new ones and their text.
+    ///
+    /// The window grows if it starts in the middle of a character that spans many
+    /// byte tokens, otherwise the decoded text may change when more tokens are added.
+    fn decode_window(&self, model: &mut dyn Model) -> Result<(usize, String)> {
+        let mut lookback = DECODE_LOOKBACK;
+        loop {
+            let decode_idx = self.tokens.len().saturating_sub(lookback);
+            let text = model.decode(&self.tokens[decode_idx..])?;
+            if decode_idx == 0
+                || lookback >= MAX_DECODE_LOOKBACK
+                || !text.starts_with(char::REPLACEMENT_CHARACTER)
+            {
+                return Ok((decode_idx, text));
+            }
+
+            lookback *= 2;
+        }
+    }
+
+    fn next_token(&mut self, model: &mut dyn Model) -> Result<Sample> {
         let last_idx = self.tokens.len().saturating_sub(1);
         model.forward(
             &self.tokens[last_idx..],
@@ -174,26 +1084,107 @@ impl TokensStream {
     }
 }
 
-/// Sample a token from the given logits tensor and tokens history.
-pub fn sample_token(logits: Tensor, tokens: &[u32], params: &ModelParams) -> Result<u32> {
-    #[derive(PartialEq, Debug)]
-    struct HeapVal(f32);
+/// Gets the text added after `prev_text`.
+///
+/// If decoding more tokens changed the previous text only the text after the common
+/// prefix is returned, so that text already emitted is not repeated.
+fn new_text<'a>(text: &'a str, prev_text: &str) -> &'a str {
+    text.strip_prefix(prev_text).unwrap_or_else(|| {
+        let common = text
+            .char_indices()
+            .zip(prev_text.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((idx, _), _)| idx)
+            .unwrap_or(text.len().min(prev_text.len()));
+        &text[common..]
+    })
+}
 
-    impl Eq for HeapVal {}
+/// Gets the end of sequence token and the position before which it is suppressed so
+/// that the reply has at least `min_tokens`, none if there is no minimum.
+///
+/// `prompt_end` is the position after the prompt tokens, a `TokensStream` samples the
+/// first reply token at the position after it.
+fn min_reply(eos_token: u32, prompt_end: usize, params: &ModelParams) -> Option<(u32, usize)> {
+    (params.min_tokens > 0).then_some((eos_token, prompt_end + 1 + params.min_tokens))
+}
 
-    impl PartialOrd for HeapVal {
-        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-            Some(self.cmp(other))
-        }
+/// Records the tokens added to the model context at `pos` and gets the tokens seen
+/// by the repeat penalty, these are the whole context if `penalize_context` is set
+/// or the given tokens otherwise.
+fn penalty_tokens<'a>(
+    context_tokens: &'a mut Vec<u32>,
+    tokens: &'a [u32],
+    pos: usize,
+    params: &ModelParams,
+) -> &'a [u32] {
+    context_tokens.truncate(pos);
+    context_tokens.extend_from_slice(tokens);
+    if params.penalize_context {
+        context_tokens
+    } else {
+        tokens
     }
+}
 
-    impl Ord for HeapVal {
-        fn cmp(&self, other: &HeapVal) -> Ordering {
-            other.0.partial_cmp(&self.0).unwrap_or(Ordering::Greater)
+/// Reduces the logits of the last position to a `[vocab]` tensor.
+///
+/// Models return `[1, 1, vocab]` or `[1, vocab]` logits depending on how they narrow
+/// the last position, only leading singleton dimensions are removed.
+fn vocab_logits(logits: Tensor) -> Result<Tensor> {
+    let dims = logits.dims();
+    match dims.split_last() {
+        Some((&vocab, leading)) if vocab > 0 && leading.iter().all(|&d| d == 1) => {
+            Ok(logits.reshape(vocab)?)
         }
+        _ => bail!("Expected logits with shape [vocab] for one position, got {dims:?}"),
     }
+}
+
+/// Masks the logit of a token unless it is the only one left, a complete JSON object
+/// only allows the end of sequence token and masking it too leaves nothing to sample.
+fn suppress_token(logits: &mut [f32], token: u32) {
+    let token = token as usize;
+    let others = logits
+        .iter()
+        .enumerate()
+        .any(|(idx, v)| idx != token && *v > f32::NEG_INFINITY);
+    if let Some(v) = logits.get_mut(token).filter(|_| others) {
+        *v = f32::NEG_INFINITY;
+    }
+}
 
-    let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+/// Sample a token from the given logits tensor and tokens history.
+///
+/// If a grammar is given the tokens that are not allowed by the grammar are masked
+/// before sampling and the grammar state is advanced with the sampled token.
+///
+/// The temperature is applied to the top-k logits inside the softmax. Dividing the
+/// logits by a positive temperature doesn't change their order, so scaling the full
+/// logits before the top-k selection picks the same tokens with the same
+/// probabilities, the temperature only changes how flat the distribution is among
+/// the selected tokens.
+///
+/// With `top_k` set to 1 the temperature is ignored and the token with the highest
+/// logit is always chosen.
+///
+/// The returned probability is the softmax of the chosen token over all the allowed
+/// tokens, it measures the model confidence independently of the sampling mode.
+///
+/// Tokens are sampled with the given `rng` so that a reply seeded with the same
+/// value is generated again.
+///
+/// The `suppressed` token is never sampled, this is used to keep the model from
+/// ending a reply shorter than the minimum length.
+pub fn sample_token(
+    logits: Tensor,
+    tokens: &[u32],
+    params: &ModelParams,
+    grammar: Option<&mut JsonGrammar>,
+    rng: &mut StdRng,
+    suppressed: Option<u32>,
+) -> Result<Sample> {
+    let logits = vocab_logits(logits)?.to_dtype(DType::F32)?;
     let logits = if params.repeat_penalty == 1. {
         logits
     } else {
@@ -205,12 +1196,69 @@ pub fn sample_token(logits: Tensor, tokens: &[u32], params: &ModelParams) -> Res
         )?
     };
 
-    let logits_v: Vec<f32> = logits.to_vec1()?;
+    let mut logits_v: Vec<f32> = logits.to_vec1()?;
+    if let Some(grammar) = grammar.as_ref() {
+        for (token, v) in logits_v.iter_mut().enumerate() {
+            if !grammar.allows(token as u32) {
+                *v = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    if let Some(token) = suppressed {
+        suppress_token(&mut logits_v, token);
+    }
+
+    // Greedy decoding picks the most likely token and doesn't use the rng, this makes
+    // the output deterministic for a given prompt.
+    let token = if params.top_k <= 1 {
+        logits_v
+            .iter()
+            .enumerate()
+            .max_by(|(_, u), (_, v)| u.total_cmp(v))
+            .map(|(token, _)| token as u32)
+            .unwrap()
+    } else {
+        let (tokens, softmax) = top_k_softmax(&logits_v, params.top_k, params.temperature);
+        let distr = rand::distributions::WeightedIndex::new(softmax)?;
+        tokens[distr.sample(rng)]
+    };
+
+    if let Some(grammar) = grammar {
+        grammar.advance(token);
+    }
+
+    let max_logit = logits_v.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let total = logits_v.iter().map(|v| (v - max_logit).exp()).sum::<f32>();
+    let prob = (logits_v[token as usize] - max_logit).exp() / total;
+
+    Ok(Sample { token, prob })
+}
+
+/// Gets the `top_k` tokens with the highest logits and their softmax probabilities
+/// with the given temperature.
+fn top_k_softmax(logits: &[f32], top_k: usize, temperature: f32) -> (Vec<u32>, Vec<f32>) {
+    #[derive(PartialEq, Debug)]
+    struct HeapVal(f32);
 
-    let mut heap = BinaryHeap::with_capacity(params.top_k);
-    for (token, v) in logits_v.iter().enumerate() {
+    impl Eq for HeapVal {}
+
+    impl PartialOrd for HeapVal {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for HeapVal {
+        fn cmp(&self, other: &HeapVal) -> Ordering {
+            other.0.partial_cmp(&self.0).unwrap_or(Ordering::Greater)
+        }
+    }
+
+    let mut heap = BinaryHeap::with_capacity(top_k);
+    for (token, v) in logits.iter().enumerate() {
         heap.push((HeapVal(*v), token as u32));
-        if heap.len() > params.top_k {
+        if heap.len() > top_k {
             heap.pop();
         }
     }
@@ -221,18 +1269,388 @@ pub fn sample_token(logits: Tensor, tokens: &[u32], params: &ModelParams) -> Res
         .map(|(l, _)| l.0)
         .unwrap();
 
+    // Subtracting the max logit before scaling keeps the exponent in range.
     let (exp_logits, tokens): (Vec<_>, Vec<_>) = heap
         .into_iter()
-        .map(|(l, t)| (((l.0 - max_logit) / params.temperature).exp(), t))
+        .map(|(l, t)| (((l.0 - max_logit) / temperature).exp(), t))
         .unzip();
 
     let total = exp_logits.iter().sum::<f32>();
-    let softmax = exp_logits
-        .into_iter()
-        .map(|v| v / total)
-        .collect::<Vec<_>>();
+    let softmax = exp_logits.into_iter().map(|v| v / total).collect();
+    (tokens, softmax)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Device;
+
+    const EOS: u32 = 1000;
+
+    /// Marks the start and the end of a group of tokens that decode to one character.
+    const GROUP_OPEN: u32 = 300;
+    const GROUP_CLOSE: u32 = 301;
+
+    /// A model that samples the given tokens and decodes them with the given function.
+    struct StubModel {
+        tokens: Vec<u32>,
+        sampled: usize,
+        decode: fn(&[u32]) -> String,
+    }
+
+    impl StubModel {
+        fn new(tokens: &[u32], decode: fn(&[u32]) -> String) -> Self {
+            Self {
+                tokens: tokens.to_vec(),
+                sampled: 0,
+                decode,
+            }
+        }
+    }
+
+    impl Model for StubModel {
+        fn prompt(&mut self, _prompt: &str, _params: &ModelParams) -> Result<TokensStream> {
+            unimplemented!()
+        }
+
+        fn encode_prompt(&self, _prompt: &str, _params: &ModelParams) -> Result<Vec<u32>> {
+            unimplemented!()
+        }
+
+        fn forward(&mut self, _tokens: &[u32], _pos: usize) -> Result<Sample> {
+            let token = self.tokens.get(self.sampled).copied().unwrap_or(EOS);
+            self.sampled += 1;
+            Ok(Sample { token, prob: 1.0 })
+        }
+
+        fn decode(&mut self, tokens: &[u32]) -> Result<String> {
+            Ok((self.decode)(tokens))
+        }
+
+        fn context_used(&self) -> usize {
+            unimplemented!()
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            unimplemented!()
+        }
+
+        fn info(&self) -> ModelInfo {
+            unimplemented!()
+        }
+
+        fn clear_context(&mut self) {}
+    }
+
+    /// Decodes each token to the byte with the same value, the placeholder token 0
+    /// decodes to nothing.
+    fn decode_bytes(tokens: &[u32]) -> String {
+        let bytes = tokens
+            .iter()
+            .filter(|&&t| t != 0)
+            .map(|&t| t as u8)
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Decodes the tokens of a group to a letter that depends on the group length, a
+    /// group that is not closed yet decodes to nothing and the end of a group that
+    /// started before the slice decodes to a replacement character.
+    fn decode_groups(tokens: &[u32]) -> String {
+        let (mut text, tokens) = match tokens
+            .iter()
+            .position(|&t| t == GROUP_OPEN || t == GROUP_CLOSE)
+        {
+            Some(idx) if tokens[idx] == GROUP_CLOSE => {
+                (char::REPLACEMENT_CHARACTER.to_string(), &tokens[idx + 1..])
+            }
+            _ => (String::new(), tokens),
+        };
+
+        let mut group = None;
+        for &token in tokens {
+            match token {
+                0 => {}
+                GROUP_OPEN => group = Some(0),
+                GROUP_CLOSE => text.extend(group.take().map(|len| char::from(b'A' + len))),
+                _ => match &mut group {
+                    Some(len) => *len += 1,
+                    None => text.push(char::from(token as u8)),
+                },
+            }
+        }
+        text
+    }
+
+    /// A tokenizer that splits words and punctuation with `<s>` and `</s>` as special
+    /// tokens, `<s>` is added at the start of the sequence.
+    fn test_tokenizer() -> tokenizers::Tokenizer {
+        use tokenizers::{
+            models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace,
+            processors::template::TemplateProcessing, AddedToken,
+        };
+
+        let vocab = ["[UNK]", "</s>", "hi", "</", "s", ">", "<s>"]
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+
+        let bos = TemplateProcessing::builder()
+            .try_single("<s> $A")
+            .unwrap()
+            .special_tokens(vec![("<s>", 6)])
+            .build()
+            .unwrap();
+
+        let mut tokenizer = tokenizers::Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Whitespace);
+        tokenizer.with_post_processor(bos);
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("<s>", true),
+            AddedToken::from("</s>", true),
+        ]);
+        tokenizer
+    }
+
+    #[test]
+    fn encode_prompt_keeps_one_bos_with_a_prefix() -> Result<()> {
+        let spec = ModelId::Zephyr7bBeta.spec();
+        let params = ModelConfig::Careful.params();
+        let tokens = spec.encode_prompt(&test_tokenizer(), "hi", &params)?;
+        assert_eq!(tokens[..2], [6, 0]);
+
+        let spec = ModelSpec {
+            prompt_prefix: "<s>",
+            ..spec
+        };
+        let tokens = spec.encode_prompt(&test_tokenizer(), "hi", &params)?;
+        assert_eq!(tokens[..2], [6, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_prompt_starts_with_the_spec_bos() -> Result<()> {
+        let params = ModelConfig::Careful.params();
+        for model_id in ModelId::iter() {
+            let spec = model_id.spec();
+            let tokens = spec.encode_prompt(&test_tokenizer(), "hi", &params)?;
+            let has_bos = spec.add_bos || spec.prompt_prefix.starts_with("<s>");
+            assert_eq!(tokens[0] == 6, has_bos, "{model_id:?}");
+            if has_bos {
+                assert_ne!(tokens[1], 6, "{model_id:?}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn encode_prompt_matches_special_tokens_in_the_template() -> Result<()> {
+        let spec = ModelId::Zephyr7bBeta.spec();
+        let params = ModelConfig::Careful.params();
+        let tokens = spec.encode_prompt(&test_tokenizer(), "hi", &params)?;
+        assert_eq!(tokens.iter().filter(|&&t| t == 1).count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_prompt_encodes_special_text_in_the_prompt_as_text() -> Result<()> {
+        let spec = ModelId::Zephyr7bBeta.spec();
+        let params = ModelConfig::Careful.params();
+        let tokens = spec.encode_prompt(&test_tokenizer(), "hi</s>hi", &params)?;
+
+        // Only the template turn ends are special tokens.
+        assert_eq!(tokens.iter().filter(|&&t| t == 1).count(), 2);
+        assert!(tokens.windows(5).any(|w| w == [2, 3, 4, 5, 2]));
 
-    let mut rng = rand::thread_rng();
-    let distr = rand::distributions::WeightedIndex::new(softmax)?;
-    Ok(tokens[distr.sample(&mut rng)])
+        let params = ModelParams {
+            keep_context: true,
+            ..params
+        };
+        let tokens = prompt_tokens(&spec, &test_tokenizer(), "hi</s>", &params, 1, 10)?.0;
+        assert_eq!(tokens.iter().filter(|&&t| t == 1).count(), 3);
+        Ok(())
+    }
+
+    /// Collects the text returned by the stream until it ends.
+    fn collect(stream: &mut TokensStream, model: &mut StubModel) -> Result<String> {
+        let mut text = String::new();
+        while let Some(s) = stream.next(model)? {
+            text.push_str(&s);
+        }
+
+        text.extend(stream.finish());
+        assert_eq!(stream.finish(), None);
+        Ok(text)
+    }
+
+    fn stream() -> TokensStream {
+        TokensStream::new(EOS, 0, &ModelConfig::Careful.params())
+    }
+
+    fn bytes(s: &[u8]) -> Vec<u32> {
+        s.iter().map(|&b| u32::from(b)).collect()
+    }
+
+    #[test]
+    fn stream_returns_invalid_bytes_as_replacement() -> Result<()> {
+        let mut model = StubModel::new(&bytes(b"a\xffbc"), decode_bytes);
+        let text = collect(&mut stream(), &mut model)?;
+        assert_eq!(text, "a\u{fffd}bc");
+        Ok(())
+    }
+
+    #[test]
+    fn stream_does_not_stall_on_invalid_bytes() -> Result<()> {
+        let mut model = StubModel::new(&bytes(b"a\xff\xff\xff\xff\xff\xff\xffb"), decode_bytes);
+        let mut stream = stream();
+        assert_eq!(stream.next(&mut model)?.as_deref(), Some("a"));
+
+        // The invalid bytes are returned once they are more than a character long,
+        // without waiting for a valid token.
+        let text = stream.next(&mut model)?.unwrap();
+        assert_eq!(text, "\u{fffd}".repeat(MAX_CHAR_TOKENS));
+        assert_eq!(model.sampled, 1 + MAX_CHAR_TOKENS);
+
+        let text = text + &collect(&mut stream, &mut model)?;
+        assert_eq!(text, "\u{fffd}".repeat(7) + "b");
+        Ok(())
+    }
+
+    #[test]
+    fn stream_final_token_completes_a_character() -> Result<()> {
+        let mut model = StubModel::new(&bytes("aé".as_bytes()), decode_bytes);
+        let text = collect(&mut stream(), &mut model)?;
+        assert_eq!(text, "aé");
+        Ok(())
+    }
+
+    #[test]
+    fn stream_finishes_with_an_incomplete_character() -> Result<()> {
+        let mut model = StubModel::new(&bytes(&"aé".as_bytes()[..2]), decode_bytes);
+        let text = collect(&mut stream(), &mut model)?;
+        assert_eq!(text, "a\u{fffd}");
+        Ok(())
+    }
+
+    #[test]
+    fn stream_grows_the_lookback_for_long_characters() -> Result<()> {
+        let mut tokens = bytes(b"x");
+        tokens.push(GROUP_OPEN);
+        tokens.extend(bytes(b"........"));
+        tokens.push(GROUP_CLOSE);
+        tokens.extend(bytes(b"yz"));
+
+        let mut model = StubModel::new(&tokens, decode_groups);
+        let mut stream = stream();
+        let text = collect(&mut stream, &mut model)?;
+        assert_eq!(text, "xIyz");
+
+        // The group is longer than the default lookback, the window must start at
+        // the group open to decode it.
+        stream.tokens.truncate(12);
+        let window = stream.decode_window(&mut model)?;
+        assert_eq!(window, (2, "I".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn vocab_logits_removes_leading_singleton_dims() -> Result<()> {
+        let values = [0.1f32, 0.2, 0.3];
+        for shape in [vec![3], vec![1, 3], vec![1, 1, 3]] {
+            let logits = Tensor::new(&values, &Device::Cpu)?.reshape(shape)?;
+            let logits = vocab_logits(logits)?;
+            assert_eq!(logits.dims(), [3]);
+            assert_eq!(logits.to_vec1::<f32>()?, values);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn vocab_logits_rejects_many_positions() -> Result<()> {
+        let logits = Tensor::zeros((2, 3), DType::F32, &Device::Cpu)?;
+        assert!(vocab_logits(logits).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn suppress_token_masks_the_token() {
+        let mut logits = [0.5, 1.0, 2.0];
+        suppress_token(&mut logits, 2);
+        assert_eq!(logits, [0.5, 1.0, f32::NEG_INFINITY]);
+    }
+
+    #[test]
+    fn suppress_token_keeps_the_only_allowed_token() {
+        let mut logits = [f32::NEG_INFINITY, f32::NEG_INFINITY, 2.0];
+        suppress_token(&mut logits, 2);
+        assert_eq!(logits, [f32::NEG_INFINITY, f32::NEG_INFINITY, 2.0]);
+    }
+
+    #[test]
+    fn top_k_softmax_is_the_same_with_prescaled_logits() {
+        let logits = [1.5, -0.5, 3.0, 0.25, 2.0, -2.0, 2.5];
+        let temperature = 0.7;
+        let scaled = logits.map(|v| v / temperature);
+
+        let sorted = |(tokens, probs): (Vec<u32>, Vec<f32>)| {
+            let mut top = tokens.into_iter().zip(probs).collect::<Vec<_>>();
+            top.sort_by_key(|(token, _)| *token);
+            top
+        };
+
+        let post = sorted(top_k_softmax(&logits, 4, temperature));
+        let pre = sorted(top_k_softmax(&scaled, 4, 1.0));
+        assert_eq!(
+            post.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            [0, 2, 4, 6]
+        );
+        for ((post_token, post_prob), (pre_token, pre_prob)) in post.iter().zip(&pre) {
+            assert_eq!(post_token, pre_token);
+            assert!((post_prob - pre_prob).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn sample_token_is_the_same_with_prescaled_logits() -> Result<()> {
+        let logits = [1.5f32, -0.5, 3.0, 0.25, 2.0, -2.0, 2.5];
+        let mut params = ModelConfig::Creative.params();
+        params.top_k = 4;
+        let scaled = logits.map(|v| v / params.temperature);
+        let mut prescaled_params = params.clone();
+        prescaled_params.temperature = 1.0;
+
+        for seed in 0..32 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let logits = Tensor::new(&logits, &Device::Cpu)?;
+            let post = sample_token(logits, &[], &params, None, &mut rng, None)?;
+
+            let mut rng = StdRng::seed_from_u64(seed);
+            let scaled = Tensor::new(&scaled, &Device::Cpu)?;
+            let pre = sample_token(scaled, &[], &prescaled_params, None, &mut rng, None)?;
+            assert_eq!(post.token, pre.token);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn sample_token_with_only_the_suppressed_token_allowed() -> Result<()> {
+        let inf = f32::NEG_INFINITY;
+        let logits = Tensor::new(&[inf, inf, 2.0, inf], &Device::Cpu)?;
+        for config in [ModelConfig::Careful, ModelConfig::Creative] {
+            let params = config.params();
+            let mut rng = StdRng::seed_from_u64(0);
+            let sample = sample_token(logits.clone(), &[], &params, None, &mut rng, Some(2))?;
+            assert_eq!(sample.token, 2);
+        }
+
+        Ok(())
+    }
 }
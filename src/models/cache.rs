@@ -1,30 +1,91 @@
 use anyhow::{anyhow, bail, Result};
+use candle::quantized::gguf_file;
 use hf_hub::api::sync::ApiBuilder;
 use std::{
-    fs, io,
+    fmt, fs, io,
     path::{Path, PathBuf},
+    thread,
+    time::Duration,
 };
 
-use crate::models::{ModelId, ModelSpec};
+use crate::models::{gguf_tokenizer, ModelId, ModelSpec};
 
 const MODELS_PATH: &str = "models";
 
+/// Environment variable that sets the cache directory.
+const CACHE_DIR_VAR: &str = "COZE_CACHE_DIR";
+
+/// Maximum time to wait for download data.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum time to wait for the weights metadata when checking for updates.
+const UPDATE_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest relative difference between the download length and the expected size
+/// of the weights, larger differences mean the repo serves a different file.
+const SIZE_TOLERANCE: f64 = 0.1;
+
+/// Size of the buffer used to copy downloads to disk, reads may return less data
+/// than this depending on how much the connection has received.
+const DOWNLOAD_BUFFER_SIZE: usize = 1 << 20;
+
+/// Wait used when a rate limited response has no valid `Retry-After` header.
+const DEFAULT_RETRY_AFTER: u64 = 10;
+
+/// Longest `Retry-After` wait before giving up on a rate limited download.
+const MAX_RETRY_AFTER: u64 = 120;
+
+/// Maximum number of retries of a rate limited download.
+const MAX_RATE_LIMIT_RETRIES: usize = 3;
+
+/// Download status reported to the caller.
+#[derive(Debug, Clone, Copy)]
+pub enum DownloadStatus {
+    /// Fraction of the file downloaded.
+    Progress(f32),
+    /// The server is rate limiting downloads, retrying after the given seconds.
+    RateLimited(u64),
+}
+
 /// Models files cache.
 #[derive(Debug)]
 pub struct ModelsCache {
     cache_dir: PathBuf,
+    persistent: bool,
 }
 
 impl ModelsCache {
     /// Creates a new cache instance.
+    ///
+    /// The cache is in the `COZE_CACHE_DIR` directory if set, or in `~/.cache/coze`.
+    /// If the home directory cannot be found, as in some containers, the cache is in
+    /// the temp directory and downloads may not persist.
     pub fn new() -> Result<Self> {
-        let mut cache_dir =
-            dirs::home_dir().ok_or_else(|| anyhow!("Home directory cannot be found"))?;
-        cache_dir.push(".cache");
-        cache_dir.push("coze");
+        let (cache_dir, persistent) = match std::env::var_os(CACHE_DIR_VAR) {
+            Some(cache_dir) if !cache_dir.is_empty() => (PathBuf::from(cache_dir), true),
+            _ => match dirs::home_dir() {
+                Some(home_dir) => (home_dir.join(".cache").join("coze"), true),
+                None => (std::env::temp_dir().join("coze"), false),
+            },
+        };
+
+        fs::create_dir_all(&cache_dir)
+            .map_err(|e| anyhow!("Unable to create cache dir {}: {e}", cache_dir.display()))?;
+        Ok(Self {
+            cache_dir,
+            persistent,
+        })
+    }
+
+    /// Gets the cache directory path.
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
 
-        fs::create_dir_all(&cache_dir).map_err(|e| anyhow!("Unable to create cache dir: {e}"))?;
-        Ok(Self { cache_dir })
+    /// Checks if the cache is in a directory that persists across sessions, it is
+    /// in the temp directory when the home directory cannot be found.
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
     }
 
     /// Gets a cached model.
@@ -85,8 +146,12 @@ impl CachedModel {
 
     /// Downloads model file from Hugging Face.
     ///
-    /// The update_fn reports percentage progress to the caller.
-    pub fn download_model(&self, update_fn: impl Fn(f32) -> bool + 'static) -> Result<()> {
+    /// The update_fn reports the download status to the caller and is called after
+    /// every read, the download is interrupted when it returns false.
+    pub fn download_model(
+        &self,
+        update_fn: impl Fn(DownloadStatus) -> bool + 'static,
+    ) -> Result<()> {
         fs::create_dir_all(&self.cache_path)
             .map_err(|e| anyhow!("Unable to create model cache dir: {e}"))?;
 
@@ -99,13 +164,22 @@ impl CachedModel {
             .model(self.spec.model_repo.to_string())
             .url(self.spec.model_filename);
 
-        download_from_repo(weights_url, &self.model_path, update_fn)
+        download_from_repo(
+            weights_url,
+            &self.model_path,
+            Some(self.spec.size),
+            update_fn,
+        )
     }
 
     /// Downloads tokenizer file from Hugging Face.
     ///
-    /// The update_fn reports percentage progress to the caller.
-    pub fn download_tokenizer(&self, update_fn: impl Fn(f32) -> bool + 'static) -> Result<()> {
+    /// The update_fn reports the download status to the caller and is called after
+    /// every read, the download is interrupted when it returns false.
+    pub fn download_tokenizer(
+        &self,
+        update_fn: impl Fn(DownloadStatus) -> bool + 'static,
+    ) -> Result<()> {
         if self.has_tokenizer() {
             // If the spec has a tokenizer the path should not be empty.
             assert!(!self.tokenizer_path.as_os_str().is_empty());
@@ -122,76 +196,204 @@ impl CachedModel {
                 .model(self.spec.tokenizer_repo.to_string())
                 .url(self.spec.tokenizer_filename);
 
-            download_from_repo(weights_url, &self.tokenizer_path, update_fn)?;
+            download_from_repo(weights_url, &self.tokenizer_path, None, update_fn)?;
         }
 
         Ok(())
     }
 
+    /// Checks if the weights in the model repo differ from the cached weights.
+    ///
+    /// Sends a HEAD request for the weights file and compares its size with the
+    /// cached file size, returns false if the model is not cached.
+    pub fn check_update(&self) -> Result<bool> {
+        let Ok(metadata) = fs::metadata(&self.model_path) else {
+            return Ok(false);
+        };
+
+        let api = ApiBuilder::new()
+            .with_progress(false)
+            .build()
+            .map_err(|e| anyhow!("Hub api error: {e}"))?;
+
+        let weights_url = api
+            .model(self.spec.model_repo.to_string())
+            .url(self.spec.model_filename);
+
+        let agent = ureq::builder()
+            .try_proxy_from_env(true)
+            .timeout(UPDATE_CHECK_TIMEOUT)
+            .build();
+
+        let response = agent.head(&weights_url).call()?;
+        let size = response
+            .header("content-length")
+            .and_then(|s| s.parse::<u64>().ok());
+
+        Ok(size.is_some_and(|size| size != metadata.len()))
+    }
+
     /// Check if this model has a tokenizer
     pub fn has_tokenizer(&self) -> bool {
         !self.spec.tokenizer_filename.is_empty()
     }
+
+    /// Loads the cached tokenizer, models without a tokenizer file build it from the
+    /// vocabulary embedded in the weights file.
+    ///
+    /// Errors loading a tokenizer file are returned as `TokenizerError` so that
+    /// callers can offer to download the tokenizer again.
+    pub fn load_tokenizer(&self) -> Result<tokenizers::Tokenizer> {
+        if !self.has_tokenizer() {
+            let mut file = fs::File::open(&self.model_path)?;
+            let content =
+                gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&self.model_path))?;
+            return gguf_tokenizer::from_gguf(&content);
+        }
+
+        tokenizers::Tokenizer::from_file(&self.tokenizer_path)
+            .map_err(|e| TokenizerError(e.to_string()).into())
+    }
 }
 
+/// An error loading a tokenizer file, the file may be corrupted.
+#[derive(Debug)]
+pub struct TokenizerError(String);
+
+impl fmt::Display for TokenizerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Tokenizer error: {}", self.0)
+    }
+}
+
+impl std::error::Error for TokenizerError {}
+
+/// Downloads the file at the given url to the destination file.
+///
+/// If an expected size is given the download is aborted before reading the content
+/// when the reported length is too far from it, a zero size is not checked.
+///
+/// Rate limited requests are retried after the time in the `Retry-After` header,
+/// the wait is reported to update_fn every second.
 pub fn download_from_repo(
     url: String,
     dest_filename: &Path,
-    update_fn: impl Fn(f32) -> bool + 'static,
+    expected_size: Option<usize>,
+    update_fn: impl Fn(DownloadStatus) -> bool + 'static,
 ) -> Result<()> {
-    let agent = ureq::builder().try_proxy_from_env(true).build();
-
-    let response = agent.get(&url).call()?;
+    // A read timeout lets a stalled download notice it has been interrupted.
+    let agent = ureq::builder()
+        .try_proxy_from_env(true)
+        .timeout_read(READ_TIMEOUT)
+        .build();
+
+    let mut retries = 0;
+    let response = loop {
+        match agent.get(&url).call() {
+            Err(ureq::Error::Status(429, response)) => {
+                let retry_after = response
+                    .header("retry-after")
+                    .and_then(|s| s.trim().parse::<u64>().ok())
+                    .unwrap_or(DEFAULT_RETRY_AFTER);
+
+                if retries == MAX_RATE_LIMIT_RETRIES || retry_after > MAX_RETRY_AFTER {
+                    bail!(
+                        "Hugging Face is rate limiting downloads, wait a few minutes and try again"
+                    );
+                }
+
+                retries += 1;
+                for secs in (1..=retry_after).rev() {
+                    if !update_fn(DownloadStatus::RateLimited(secs)) {
+                        bail!("Download interrupted");
+                    }
+
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+            result => break result?,
+        }
+    };
     let content_length = response
         .header("content-length")
         .and_then(|s| s.parse::<usize>().ok())
         .unwrap_or(0);
 
+    if let Some(expected_size) = expected_size.filter(|&size| size > 0) {
+        let diff = content_length.abs_diff(expected_size) as f64;
+        if content_length > 0 && diff > expected_size as f64 * SIZE_TOLERANCE {
+            bail!(
+                "Unexpected download size {}M, expected about {}M, check the model repo",
+                content_length / (1 << 20),
+                expected_size / (1 << 20)
+            );
+        }
+    }
+
     let reader = response.into_reader();
     let mut reader = ProgressReader::new(reader, content_length, update_fn);
 
     let temp_filepath = dest_filename.with_extension("tmp");
     let mut temp_file = fs::File::create(&temp_filepath)?;
 
-    if let Err(e) = io::copy(&mut reader, &mut temp_file) {
+    let copy = copy_buffered(&mut reader, &mut temp_file, DOWNLOAD_BUFFER_SIZE)
+        .map_err(|e| anyhow!("File copy error: {e}"))
+        .and_then(|_| Ok(temp_file.sync_all()?));
+    drop(temp_file);
+
+    // Don't leave a partial file behind if the download was interrupted.
+    if let Err(e) = copy.and_then(|_| Ok(fs::rename(&temp_filepath, dest_filename)?)) {
         let _ = fs::remove_file(&temp_filepath);
-        bail!("File copy error: {e}");
+        return Err(e);
     }
 
-    temp_file.sync_all()?;
-    drop(temp_file);
+    Ok(())
+}
 
-    fs::rename(temp_filepath, dest_filename)?;
+/// Copies the reader to the writer using a buffer of the given size, `io::copy`
+/// uses a small buffer that makes many system calls on fast connections.
+fn copy_buffered(
+    reader: &mut impl io::Read,
+    writer: &mut impl io::Write,
+    buffer_size: usize,
+) -> io::Result<u64> {
+    let mut buf = vec![0; buffer_size];
+    let mut written = 0;
+    loop {
+        let nread = match reader.read(&mut buf) {
+            Ok(0) => return Ok(written),
+            Ok(nread) => nread,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
 
-    Ok(())
+        writer.write_all(&buf[..nread])?;
+        written += nread as u64;
+    }
 }
 
 struct ProgressReader {
     reader: Box<dyn io::Read + Send + Sync>,
     length: usize,
     bytes_read: usize,
-    batch_read: usize,
-    update_fn: Box<dyn Fn(f32) -> bool + 'static>,
+    update_fn: Box<dyn Fn(DownloadStatus) -> bool + 'static>,
 }
 
 impl ProgressReader {
     fn new(
         reader: Box<dyn io::Read + Send + Sync>,
         length: usize,
-        update_fn: impl Fn(f32) -> bool + 'static,
+        update_fn: impl Fn(DownloadStatus) -> bool + 'static,
     ) -> Self {
         Self {
             reader,
             length,
             bytes_read: 0,
-            batch_read: 0,
             update_fn: Box::new(update_fn),
         }
     }
 
     fn update(&mut self, n: usize) -> io::Result<()> {
-        self.batch_read += n;
-
         let pct = if self.length == 0 {
             // If we didn't get the file length cycle every 100 reads.
             self.bytes_read += 1;
@@ -201,16 +403,10 @@ impl ProgressReader {
             self.bytes_read as f32 / self.length as f32
         };
 
-        // Notify UI every half percent.
-        if self.batch_read > self.length / 200 {
-            self.batch_read = 0;
-            if (*self.update_fn)(pct) {
-                Ok(())
-            } else {
-                Err(io::Error::new(io::ErrorKind::BrokenPipe, "User interrupt"))
-            }
-        } else {
+        if (*self.update_fn)(DownloadStatus::Progress(pct)) {
             Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "User interrupt"))
         }
     }
 }
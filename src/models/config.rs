@@ -1,4 +1,10 @@
+use anyhow::{anyhow, bail, Result};
+use fancy_regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::{sync::OnceLock, time::Duration};
+
+/// Environment variable that fixes the sampling seed of every reply.
+const SEED_VAR: &str = "COZE_SEED";
 
 /// The model configuration that defines how tokens are generated.
 #[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -31,8 +37,210 @@ impl ModelConfig {
     }
 }
 
+/// Generation options applied on top of the `ModelConfig` parameters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelOptions {
+    /// Constrain the generated text to a JSON object.
+    pub json_output: bool,
+    /// Role labels that override the ones in the model template.
+    pub role_labels: Option<RoleLabels>,
+    /// Stop generation after this many consecutive newlines, 0 to disable.
+    pub stop_newlines: usize,
+    /// Maximum number of seconds to wait for a model to load.
+    pub load_timeout_secs: u64,
+    /// Keep recently used models in memory to switch back to them quickly.
+    pub keep_models: bool,
+    /// Command run when a reply completes with the reply text on its stdin, empty to
+    /// disable.
+    pub completion_command: String,
+    /// The chat template used to format prompts.
+    pub chat_template: ChatTemplate,
+    /// Generate a careful draft first and then ask the model to refine it.
+    pub refine: bool,
+    /// Seed for sampling the reply tokens, each prompt uses a new random seed if not
+    /// set.
+    pub seed: Option<u64>,
+    /// Text added before every user prompt.
+    pub prompt_prefix: String,
+    /// Text added after every user prompt.
+    pub prompt_suffix: String,
+    /// Text the replies start with, the model continues from it.
+    pub reply_prefix: String,
+    /// Keep the previous turns in the model context instead of resetting it for each
+    /// prompt.
+    pub keep_context: bool,
+    /// Tags around the reasoning text that is shown apart from the reply.
+    pub reasoning_tags: Option<ReasoningTags>,
+    /// Number of decoder layers run before computing the logits, 0 for all layers.
+    pub decoder_layers: usize,
+    /// Run generation at a lower priority than other apps.
+    pub low_priority: bool,
+    /// Apply the repeat penalty to the previous turns in the model context too.
+    pub penalize_context: bool,
+    /// Minimum number of tokens generated before the reply can end, 0 for no minimum.
+    pub min_tokens: usize,
+    /// Stop generation when this regex matches the reply, empty to disable.
+    pub stop_regex: String,
+    /// Maximum number of tokens kept in the model KV cache, 0 for the model context
+    /// length.
+    pub kv_cache_tokens: usize,
+}
+
+impl Default for ModelOptions {
+    fn default() -> Self {
+        Self {
+            json_output: false,
+            role_labels: None,
+            stop_newlines: 0,
+            load_timeout_secs: 600,
+            keep_models: false,
+            completion_command: String::new(),
+            chat_template: ChatTemplate::Model,
+            refine: false,
+            seed: None,
+            prompt_prefix: String::new(),
+            prompt_suffix: String::new(),
+            reply_prefix: String::new(),
+            keep_context: false,
+            reasoning_tags: None,
+            decoder_layers: 0,
+            low_priority: false,
+            penalize_context: false,
+            min_tokens: 0,
+            stop_regex: String::new(),
+            kv_cache_tokens: 0,
+        }
+    }
+}
+
+impl ModelOptions {
+    /// Timeout for building a model from its weights.
+    pub fn load_timeout(&self) -> Duration {
+        Duration::from_secs(self.load_timeout_secs)
+    }
+
+    /// Gets the fixed seed for sampling replies, the `COZE_SEED` environment variable
+    /// takes precedence over the seed in these options.
+    pub fn fixed_seed(&self) -> Option<u64> {
+        env_seed().or(self.seed)
+    }
+
+    /// Compiles the stop regex, `None` if it is not set.
+    pub fn stop_regex(&self) -> Result<Option<Regex>> {
+        if self.stop_regex.is_empty() {
+            return Ok(None);
+        }
+
+        Regex::new(&self.stop_regex)
+            .map(Some)
+            .map_err(|e| anyhow!("Invalid stop regex: {e}"))
+    }
+}
+
+/// Gets the seed set with the `COZE_SEED` environment variable.
+///
+/// This only makes sampling reproducible, replies may still differ across machines
+/// or thread counts because of floating point differences in the model.
+pub fn env_seed() -> Option<u64> {
+    static SEED: OnceLock<Option<u64>> = OnceLock::new();
+    *SEED.get_or_init(|| {
+        std::env::var(SEED_VAR)
+            .ok()
+            .and_then(|seed| seed.trim().parse().ok())
+    })
+}
+
+/// The chat template used to format prompts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ChatTemplate {
+    /// The template from the model specification.
+    #[default]
+    Model,
+    /// ChatML template with `<|im_start|>` and `<|im_end|>` tags, used by many
+    /// community fine-tunes.
+    ChatMl,
+}
+
+impl ChatTemplate {
+    /// Gets the value description.
+    pub fn description(&self) -> &'static str {
+        match self {
+            ChatTemplate::Model => "Model default",
+            ChatTemplate::ChatMl => "ChatML",
+        }
+    }
+}
+
+/// Role labels used to format a prompt template.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoleLabels {
+    /// System tag.
+    pub system: String,
+    /// User tag.
+    pub user: String,
+    /// Assistant tag.
+    pub assistant: String,
+}
+
+impl Default for RoleLabels {
+    fn default() -> Self {
+        Self {
+            system: "<|system|>".to_string(),
+            user: "<|user|>".to_string(),
+            assistant: "<|assistant|>".to_string(),
+        }
+    }
+}
+
+impl RoleLabels {
+    /// Checks that all the labels are set.
+    pub fn validate(&self) -> Result<()> {
+        for (name, label) in [
+            ("System", &self.system),
+            ("User", &self.user),
+            ("Assistant", &self.assistant),
+        ] {
+            if label.trim().is_empty() {
+                bail!("{name} role label cannot be empty");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Tags that enclose the reasoning a model writes before its answer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReasoningTags {
+    /// Tag that starts the reasoning.
+    pub open: String,
+    /// Tag that ends the reasoning.
+    pub close: String,
+}
+
+impl Default for ReasoningTags {
+    fn default() -> Self {
+        Self {
+            open: "<think>".to_string(),
+            close: "</think>".to_string(),
+        }
+    }
+}
+
+impl ReasoningTags {
+    /// Checks that both tags are set.
+    pub fn validate(&self) -> Result<()> {
+        if self.open.is_empty() || self.close.is_empty() {
+            bail!("Reasoning tags cannot be empty");
+        }
+
+        Ok(())
+    }
+}
+
 /// Model configuration parameters.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct ModelParams {
     /// Best K tokens
     pub top_k: usize,
@@ -42,15 +250,92 @@ pub struct ModelParams {
     pub repeat_penalty: f32,
     /// The context size to consider for the repeat penalty.
     pub repeat_last_n: usize,
+    /// Constrain the generated text to a JSON object.
+    pub json_output: bool,
+    /// Drop the oldest prompt tokens that don't fit the model context.
+    pub truncate_prompt: bool,
+    /// The longest sequence of tokens checked by the repeat detector.
+    pub stop_repeat_ngram: usize,
+    /// Stop generation when a sequence is repeated this many times, 0 to disable.
+    pub stop_repeat_count: usize,
+    /// Role labels that override the ones in the model template.
+    pub role_labels: Option<RoleLabels>,
+    /// Stop generation after this many consecutive newlines, 0 to disable.
+    pub stop_newlines: usize,
+    /// The chat template used to format prompts.
+    pub chat_template: ChatTemplate,
+    /// Seed of the random generator used to sample tokens.
+    pub seed: u64,
+    /// Text added before the user prompt inside the template.
+    pub prompt_prefix: String,
+    /// Text added after the user prompt inside the template.
+    pub prompt_suffix: String,
+    /// Text added after the assistant turn opener that starts the reply.
+    pub reply_prefix: String,
+    /// Continue after the previous turns in the model context.
+    pub keep_context: bool,
+    /// Tags around the reasoning text that is shown apart from the reply.
+    pub reasoning_tags: Option<ReasoningTags>,
+    /// Number of decoder layers run before computing the logits, 0 for all layers.
+    pub decoder_layers: usize,
+    /// Apply the repeat penalty to the previous turns in the model context too.
+    pub penalize_context: bool,
+    /// Minimum number of tokens generated before the reply can end, 0 for no minimum.
+    pub min_tokens: usize,
+    /// Stop generation when this regex matches the reply.
+    pub stop_regex: Option<Regex>,
+    /// Maximum number of tokens kept in the model KV cache, 0 for the model context
+    /// length.
+    pub kv_cache_tokens: usize,
 }
 
 impl ModelParams {
+    /// Applies the given options to these parameters.
+    pub fn with_options(self, options: &ModelOptions) -> Self {
+        Self {
+            json_output: options.json_output,
+            role_labels: options.role_labels.clone(),
+            stop_newlines: options.stop_newlines,
+            chat_template: options.chat_template,
+            prompt_prefix: options.prompt_prefix.clone(),
+            prompt_suffix: options.prompt_suffix.clone(),
+            reply_prefix: options.reply_prefix.clone(),
+            keep_context: options.keep_context,
+            reasoning_tags: options.reasoning_tags.clone(),
+            decoder_layers: options.decoder_layers,
+            penalize_context: options.penalize_context,
+            min_tokens: options.min_tokens,
+            kv_cache_tokens: options.kv_cache_tokens,
+            // The config window doesn't apply options with an invalid regex.
+            stop_regex: options.stop_regex().ok().flatten(),
+            ..self
+        }
+    }
+
     fn careful() -> Self {
         Self {
             top_k: 1,
             temperature: 1.,
             repeat_penalty: 1.2,
             repeat_last_n: 64,
+            json_output: false,
+            truncate_prompt: false,
+            stop_repeat_ngram: 0,
+            stop_repeat_count: 0,
+            role_labels: None,
+            stop_newlines: 0,
+            chat_template: ChatTemplate::Model,
+            seed: 0,
+            prompt_prefix: String::new(),
+            prompt_suffix: String::new(),
+            reply_prefix: String::new(),
+            keep_context: false,
+            reasoning_tags: None,
+            decoder_layers: 0,
+            penalize_context: false,
+            min_tokens: 0,
+            stop_regex: None,
+            kv_cache_tokens: 0,
         }
     }
 
@@ -60,6 +345,24 @@ impl ModelParams {
             temperature: 2.,
             repeat_penalty: 1.2,
             repeat_last_n: 64,
+            json_output: false,
+            truncate_prompt: false,
+            stop_repeat_ngram: 0,
+            stop_repeat_count: 0,
+            role_labels: None,
+            stop_newlines: 0,
+            chat_template: ChatTemplate::Model,
+            seed: 0,
+            prompt_prefix: String::new(),
+            prompt_suffix: String::new(),
+            reply_prefix: String::new(),
+            keep_context: false,
+            reasoning_tags: None,
+            decoder_layers: 0,
+            penalize_context: false,
+            min_tokens: 0,
+            stop_regex: None,
+            kv_cache_tokens: 0,
         }
     }
 
@@ -69,6 +372,24 @@ impl ModelParams {
             temperature: 5.,
             repeat_penalty: 2.,
             repeat_last_n: 128,
+            json_output: false,
+            truncate_prompt: false,
+            stop_repeat_ngram: 16,
+            stop_repeat_count: 5,
+            role_labels: None,
+            stop_newlines: 0,
+            chat_template: ChatTemplate::Model,
+            seed: 0,
+            prompt_prefix: String::new(),
+            prompt_suffix: String::new(),
+            reply_prefix: String::new(),
+            keep_context: false,
+            reasoning_tags: None,
+            decoder_layers: 0,
+            penalize_context: false,
+            min_tokens: 0,
+            stop_regex: None,
+            kv_cache_tokens: 0,
         }
     }
 }
@@ -0,0 +1,196 @@
+//! Tokenizers built from the vocabulary embedded in GGUF files.
+use anyhow::{anyhow, bail, Result};
+use candle::quantized::gguf_file;
+use std::collections::HashMap;
+use tokenizers::{
+    decoders::{
+        byte_fallback::ByteFallback, byte_level::ByteLevel, fuse::Fuse,
+        sequence::Sequence as DecoderSequence, strip::Strip,
+    },
+    models::bpe::BPE,
+    normalizers::{Prepend, Replace, Sequence as NormalizerSequence},
+    processors::template::TemplateProcessing,
+    AddedToken, DecoderWrapper, NormalizerWrapper, PostProcessorWrapper, PreTokenizerWrapper,
+    Tokenizer,
+};
+
+/// SentencePiece marker for the spaces between words.
+const SPACE_MARKER: &str = "▁";
+
+/// GGUF token type of the vocabulary pieces.
+const NORMAL_TOKEN: i32 = 1;
+
+/// GGUF token type of control tokens like the beginning and end of sequence.
+const CONTROL_TOKEN: i32 = 3;
+
+/// Builds a tokenizer from the vocabulary in the GGUF metadata.
+///
+/// Supports the SentencePiece vocabulary of Llama models and the byte level BPE
+/// vocabulary of GPT-2 style models.
+pub fn from_gguf(content: &gguf_file::Content) -> Result<Tokenizer> {
+    let metadata = &content.metadata;
+    let get = |key: &str| {
+        metadata
+            .get(key)
+            .ok_or_else(|| anyhow!("Missing {key} in the GGUF metadata"))
+    };
+
+    let tokens = get("tokenizer.ggml.tokens")?
+        .to_vec()?
+        .iter()
+        .map(|token| token.to_string().cloned())
+        .collect::<candle::Result<Vec<_>>>()?;
+    let token_types = match metadata.get("tokenizer.ggml.token_type") {
+        Some(types) => types
+            .to_vec()?
+            .iter()
+            .map(|ty| ty.to_i32())
+            .collect::<candle::Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let vocab = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.clone(), id as u32))
+        .collect::<HashMap<_, _>>();
+
+    let model = get("tokenizer.ggml.model")?.to_string()?.as_str();
+    let mut tokenizer = match model {
+        "llama" => {
+            let scores = get("tokenizer.ggml.scores")?
+                .to_vec()?
+                .iter()
+                .map(|score| score.to_f32())
+                .collect::<candle::Result<Vec<_>>>()?;
+            let merges = sentencepiece_merges(&vocab, &tokens, &token_types, &scores);
+            sentencepiece_tokenizer(vocab, merges)?
+        }
+        "gpt2" => {
+            let merges = get("tokenizer.ggml.merges")?
+                .to_vec()?
+                .iter()
+                .map(|merge| {
+                    let merge = merge.to_string()?;
+                    merge
+                        .split_once(' ')
+                        .map(|(left, right)| (left.to_string(), right.to_string()))
+                        .ok_or_else(|| anyhow!("Invalid GGUF merge: {merge}"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            byte_level_tokenizer(vocab, merges)?
+        }
+        model => bail!("Unsupported GGUF tokenizer model: {model}"),
+    };
+
+    // Control tokens in the prompt template are matched before splitting the text.
+    let special_tokens = tokens
+        .iter()
+        .zip(&token_types)
+        .filter(|(_, ty)| **ty == CONTROL_TOKEN)
+        .map(|(token, _)| AddedToken::from(token.clone(), true))
+        .collect::<Vec<_>>();
+    tokenizer.add_special_tokens(&special_tokens);
+
+    let add_bos = metadata
+        .get("tokenizer.ggml.add_bos_token")
+        .and_then(|add_bos| add_bos.to_bool().ok())
+        .unwrap_or(model == "llama");
+    let bos_id = metadata
+        .get("tokenizer.ggml.bos_token_id")
+        .and_then(|id| id.to_u32().ok());
+    if let Some(bos_id) = bos_id.filter(|_| add_bos) {
+        let bos = tokens
+            .get(bos_id as usize)
+            .ok_or_else(|| anyhow!("Invalid GGUF bos token id: {bos_id}"))?;
+        let processor = TemplateProcessing::builder()
+            .try_single(format!("{bos} $A"))
+            .map_err(anyhow::Error::msg)?
+            .special_tokens(vec![(bos.clone(), bos_id)])
+            .build()
+            .map_err(anyhow::Error::msg)?;
+        tokenizer.with_post_processor(PostProcessorWrapper::from(processor));
+    }
+
+    Ok(tokenizer)
+}
+
+/// Gets the BPE merges for a SentencePiece vocabulary.
+///
+/// Each piece is merged from the pairs of pieces that form it, pieces with a higher
+/// score are merged first.
+fn sentencepiece_merges(
+    vocab: &HashMap<String, u32>,
+    tokens: &[String],
+    token_types: &[i32],
+    scores: &[f32],
+) -> Vec<(String, String)> {
+    let mut merges = Vec::new();
+    for (id, token) in tokens.iter().enumerate() {
+        if token_types.get(id).is_some_and(|ty| *ty != NORMAL_TOKEN) {
+            continue;
+        }
+
+        let score = scores.get(id).copied().unwrap_or(f32::NEG_INFINITY);
+        for (idx, _) in token.char_indices().skip(1) {
+            let (left, right) = token.split_at(idx);
+            if let (Some(left_id), Some(right_id)) = (vocab.get(left), vocab.get(right)) {
+                merges.push((score, (*left_id, *right_id), left, right));
+            }
+        }
+    }
+
+    merges.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+    merges
+        .into_iter()
+        .map(|(_, _, left, right)| (left.to_string(), right.to_string()))
+        .collect()
+}
+
+/// Creates a tokenizer that works like the Llama SentencePiece tokenizer.
+fn sentencepiece_tokenizer(
+    vocab: HashMap<String, u32>,
+    merges: Vec<(String, String)>,
+) -> Result<Tokenizer> {
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .unk_token("<unk>".to_string())
+        .fuse_unk(true)
+        .byte_fallback(true)
+        .build()
+        .map_err(anyhow::Error::msg)?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_normalizer(NormalizerWrapper::from(NormalizerSequence::new(vec![
+        Prepend::new(SPACE_MARKER.to_string()).into(),
+        Replace::new(" ", SPACE_MARKER)
+            .map_err(anyhow::Error::msg)?
+            .into(),
+    ])));
+    tokenizer.with_decoder(DecoderWrapper::from(DecoderSequence::new(vec![
+        Replace::new(SPACE_MARKER, " ")
+            .map_err(anyhow::Error::msg)?
+            .into(),
+        ByteFallback::new().into(),
+        Fuse::new().into(),
+        Strip::new(' ', 1, 0).into(),
+    ])));
+
+    Ok(tokenizer)
+}
+
+/// Creates a GPT-2 style byte level tokenizer.
+fn byte_level_tokenizer(
+    vocab: HashMap<String, u32>,
+    merges: Vec<(String, String)>,
+) -> Result<Tokenizer> {
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .build()
+        .map_err(anyhow::Error::msg)?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_pre_tokenizer(PreTokenizerWrapper::from(ByteLevel::new(false, true, true)));
+    tokenizer.with_decoder(DecoderWrapper::from(ByteLevel::default()));
+
+    Ok(tokenizer)
+}
@@ -0,0 +1,489 @@
+//! Constrained generation using a minimal JSON grammar.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The next element expected by the grammar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    /// Any JSON value.
+    Value,
+    /// A value or the end of an array.
+    ValueOrEnd,
+    /// An object key or the end of an object.
+    KeyOrEnd,
+    /// An object key.
+    Key,
+    /// The colon after an object key.
+    Colon,
+    /// A comma or the end of the current object or array.
+    CommaOrEnd,
+}
+
+/// The lexeme currently being parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lexeme {
+    None,
+    /// A string, `escape` counts the remaining escape chars.
+    String {
+        key: bool,
+        escape: u8,
+    },
+    Number(Number),
+    Literal(&'static str, usize),
+}
+
+/// The part of a number being parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Number {
+    /// The minus sign.
+    Sign,
+    /// A leading zero, it can only be followed by the fraction or the exponent.
+    Zero,
+    /// The integer digits.
+    Int,
+    /// The decimal point.
+    Dot,
+    /// The fraction digits.
+    Frac,
+    /// The exponent marker.
+    Exp,
+    /// The exponent sign.
+    ExpSign,
+    /// The exponent digits.
+    ExpDigits,
+}
+
+impl Number {
+    /// Gets the state after the given char, `None` if the char can't continue the
+    /// number.
+    fn next(self, c: char) -> Option<Number> {
+        match (self, c) {
+            (Number::Sign, '0') => Some(Number::Zero),
+            (Number::Sign | Number::Int, '0'..='9') => Some(Number::Int),
+            (Number::Zero | Number::Int, '.') => Some(Number::Dot),
+            (Number::Dot | Number::Frac, '0'..='9') => Some(Number::Frac),
+            (Number::Zero | Number::Int | Number::Frac, 'e' | 'E') => Some(Number::Exp),
+            (Number::Exp, '+' | '-') => Some(Number::ExpSign),
+            (Number::Exp | Number::ExpSign | Number::ExpDigits, '0'..='9') => {
+                Some(Number::ExpDigits)
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks if the number can end in this state.
+    fn is_complete(self) -> bool {
+        matches!(
+            self,
+            Number::Zero | Number::Int | Number::Frac | Number::ExpDigits
+        )
+    }
+}
+
+/// Tracks the state of a JSON object generation and masks tokens that would make the
+/// output invalid.
+///
+/// This is a minimal implementation that checks balanced braces and the key-value
+/// structure, the top level value must be an object.
+#[derive(Debug, Clone)]
+pub struct JsonGrammar {
+    vocab: Arc<Vec<Option<String>>>,
+    eos_token: u32,
+    stack: Vec<char>,
+    expect: Expect,
+    lexeme: Lexeme,
+    complete: bool,
+}
+
+impl JsonGrammar {
+    /// Creates a new grammar for the given tokens text.
+    pub fn new(vocab: Arc<Vec<Option<String>>>, eos_token: u32) -> Self {
+        Self {
+            vocab,
+            eos_token,
+            stack: Vec::new(),
+            expect: Expect::Value,
+            lexeme: Lexeme::None,
+            complete: false,
+        }
+    }
+
+    /// Creates a new grammar using the tokenizer vocabulary.
+    pub fn from_tokenizer(tokenizer: &tokenizers::Tokenizer, eos_token: u32) -> Self {
+        Self::new(vocab_text(tokenizer), eos_token)
+    }
+
+    /// Checks if the given token keeps the output valid.
+    pub fn allows(&self, token: u32) -> bool {
+        if token == self.eos_token {
+            return self.complete;
+        }
+
+        // Once the object is complete only allow the end of stream.
+        if self.complete {
+            return false;
+        }
+
+        match self.token_text(token) {
+            Some(text) if !text.is_empty() => {
+                let mut grammar = self.clone();
+                text.chars().all(|c| grammar.feed(c))
+            }
+            _ => false,
+        }
+    }
+
+    /// Advances the grammar state with the given token.
+    pub fn advance(&mut self, token: u32) {
+        if let Some(text) = self.token_text(token).map(str::to_owned) {
+            for c in text.chars() {
+                self.feed(c);
+            }
+        }
+    }
+
+    fn token_text(&self, token: u32) -> Option<&str> {
+        self.vocab.get(token as usize).and_then(|t| t.as_deref())
+    }
+
+    fn feed(&mut self, c: char) -> bool {
+        match self.lexeme {
+            Lexeme::String { key, escape } => {
+                match escape {
+                    0 if c == '"' => {
+                        self.lexeme = Lexeme::None;
+                        if key {
+                            self.expect = Expect::Colon;
+                        } else {
+                            self.end_value();
+                        }
+                    }
+                    0 if c == '\\' => self.lexeme = Lexeme::String { key, escape: 1 },
+                    0 => return !c.is_control(),
+                    // Start of an escape sequence.
+                    1 if c == 'u' => self.lexeme = Lexeme::String { key, escape: 5 },
+                    1 if "\"\\/bfnrt".contains(c) => {
+                        self.lexeme = Lexeme::String { key, escape: 0 }
+                    }
+                    1 => return false,
+                    // Unicode escape digits.
+                    n if c.is_ascii_hexdigit() => {
+                        let escape = if n == 2 { 0 } else { n - 1 };
+                        self.lexeme = Lexeme::String { key, escape };
+                    }
+                    _ => return false,
+                }
+                true
+            }
+            Lexeme::Number(number) => match number.next(c) {
+                Some(number) => {
+                    self.lexeme = Lexeme::Number(number);
+                    true
+                }
+                // Only a separator or whitespace can follow the last digit.
+                None if number.is_complete() && (c.is_whitespace() || ",}]".contains(c)) => {
+                    self.lexeme = Lexeme::None;
+                    self.end_value();
+                    self.feed_structural(c)
+                }
+                None => false,
+            },
+            Lexeme::Literal(word, pos) => {
+                if word[pos..].starts_with(c) {
+                    if pos + 1 == word.len() {
+                        self.lexeme = Lexeme::None;
+                        self.end_value();
+                    } else {
+                        self.lexeme = Lexeme::Literal(word, pos + 1);
+                    }
+                    true
+                } else {
+                    false
+                }
+            }
+            Lexeme::None => self.feed_structural(c),
+        }
+    }
+
+    fn feed_structural(&mut self, c: char) -> bool {
+        if c.is_whitespace() {
+            return true;
+        }
+
+        if self.complete {
+            return false;
+        }
+
+        // The top level value must be an object.
+        if self.stack.is_empty() {
+            return if c == '{' {
+                self.stack.push(c);
+                self.expect = Expect::KeyOrEnd;
+                true
+            } else {
+                false
+            };
+        }
+
+        match (self.expect, c) {
+            (Expect::ValueOrEnd, ']') | (Expect::KeyOrEnd, '}') => self.close(c),
+            (Expect::Value | Expect::ValueOrEnd, '{') => {
+                self.stack.push(c);
+                self.expect = Expect::KeyOrEnd;
+                true
+            }
+            (Expect::Value | Expect::ValueOrEnd, '[') => {
+                self.stack.push(c);
+                self.expect = Expect::ValueOrEnd;
+                true
+            }
+            (Expect::Value | Expect::ValueOrEnd, '"') => {
+                self.lexeme = Lexeme::String {
+                    key: false,
+                    escape: 0,
+                };
+                true
+            }
+            (Expect::Value | Expect::ValueOrEnd, '-') => {
+                self.lexeme = Lexeme::Number(Number::Sign);
+                true
+            }
+            (Expect::Value | Expect::ValueOrEnd, '0') => {
+                self.lexeme = Lexeme::Number(Number::Zero);
+                true
+            }
+            (Expect::Value | Expect::ValueOrEnd, '1'..='9') => {
+                self.lexeme = Lexeme::Number(Number::Int);
+                true
+            }
+            (Expect::Value | Expect::ValueOrEnd, c) => {
+                match ["true", "false", "null"]
+                    .into_iter()
+                    .find(|w| w.starts_with(c))
+                {
+                    Some(word) => {
+                        self.lexeme = Lexeme::Literal(word, 1);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            (Expect::KeyOrEnd | Expect::Key, '"') => {
+                self.lexeme = Lexeme::String {
+                    key: true,
+                    escape: 0,
+                };
+                true
+            }
+            (Expect::Colon, ':') => {
+                self.expect = Expect::Value;
+                true
+            }
+            (Expect::CommaOrEnd, ',') => {
+                self.expect = if self.stack.last() == Some(&'{') {
+                    Expect::Key
+                } else {
+                    Expect::Value
+                };
+                true
+            }
+            (Expect::CommaOrEnd, '}' | ']') => self.close(c),
+            _ => false,
+        }
+    }
+
+    fn close(&mut self, c: char) -> bool {
+        let open = if c == '}' { '{' } else { '[' };
+        if self.stack.last() == Some(&open) {
+            self.stack.pop();
+            self.end_value();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn end_value(&mut self) {
+        if self.stack.is_empty() {
+            self.complete = true;
+        } else {
+            self.expect = Expect::CommaOrEnd;
+        }
+    }
+}
+
+/// Gets the text for each token in the tokenizer vocabulary.
+///
+/// Control tokens don't have a text representation and are set to `None`.
+pub fn vocab_text(tokenizer: &tokenizers::Tokenizer) -> Arc<Vec<Option<String>>> {
+    let vocab = tokenizer.get_vocab(true);
+    let vocab_size = vocab.values().max().map(|id| *id as usize + 1).unwrap_or(0);
+    let added_tokens = tokenizer.get_added_tokens_decoder();
+
+    // Byte level BPE vocabularies have a token for the char of each byte.
+    let chars = byte_level_chars();
+    let byte_level = chars
+        .keys()
+        .all(|c| vocab.contains_key(c.encode_utf8(&mut [0; 4])));
+
+    let mut texts = vec![None; vocab_size];
+    for (piece, id) in vocab {
+        if added_tokens.get(&id).is_some_and(|t| t.special) {
+            continue;
+        }
+
+        texts[id as usize] = if byte_level {
+            byte_level_text(&piece, &chars)
+        } else {
+            piece_text(&piece)
+        };
+    }
+
+    Arc::new(texts)
+}
+
+/// Gets the byte for each char used by byte level BPE vocabularies.
+///
+/// Printable bytes are their own char, the others are mapped in order to the chars
+/// starting at U+0100, like in the GPT-2 tokenizer.
+fn byte_level_chars() -> HashMap<char, u8> {
+    let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    let mut next = 0x100;
+    (0..=255u8)
+        .map(|b| {
+            if printable(b) {
+                (char::from(b), b)
+            } else {
+                let c = char::from_u32(next).unwrap();
+                next += 1;
+                (c, b)
+            }
+        })
+        .collect()
+}
+
+/// Gets the text of a byte level BPE piece, pieces that are not valid UTF-8 on
+/// their own are set to `None`.
+fn byte_level_text(piece: &str, chars: &HashMap<char, u8>) -> Option<String> {
+    let bytes = piece
+        .chars()
+        .map(|c| chars.get(&c).copied())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Gets the text of a sentencepiece piece.
+fn piece_text(piece: &str) -> Option<String> {
+    if piece.starts_with('<') && piece.ends_with('>') && piece.len() > 2 {
+        // Byte fallback tokens like <0x0A>, only ascii bytes are supported.
+        return piece
+            .strip_prefix("<0x")
+            .and_then(|hex| u8::from_str_radix(hex.trim_end_matches('>'), 16).ok())
+            .filter(|b| b.is_ascii())
+            .map(|b| char::from(b).to_string());
+    }
+
+    // Sentencepiece space marker.
+    Some(piece.replace('▁', " "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EOS: u32 = 128;
+
+    /// A grammar with a token for each ascii char.
+    fn grammar() -> JsonGrammar {
+        let vocab = (0..128u8)
+            .map(|b| Some(char::from(b).to_string()))
+            .collect();
+        JsonGrammar::new(Arc::new(vocab), EOS)
+    }
+
+    /// Feeds the text char by char and checks it ends with a complete object.
+    fn accepts(text: &str) -> bool {
+        let mut grammar = grammar();
+        for c in text.chars() {
+            if !grammar.allows(c as u32) {
+                return false;
+            }
+            grammar.advance(c as u32);
+        }
+        grammar.allows(EOS)
+    }
+
+    #[test]
+    fn accepts_valid_objects() {
+        for text in [
+            "{}",
+            "{ }",
+            r#"{"a":1}"#,
+            r#"{ "a" : 0 , "b" : -0.5 }"#,
+            r#"{"a":-1.5e+3,"b":2E-2,"c":10e5}"#,
+            r#"{"a":[true,false,null,[]],"b":{"c":{}}}"#,
+            r#"{"a":"x\"\\\/\n\u00e9"}"#,
+        ] {
+            assert!(accepts(text), "{text}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_objects() {
+        for text in [
+            "[1]",
+            r#""a""#,
+            r#"{"a":-}"#,
+            r#"{"a":1..2}"#,
+            r#"{"a":1e}"#,
+            r#"{"a":1e+}"#,
+            r#"{"a":01}"#,
+            r#"{"a":.5}"#,
+            r#"{"a":1.}"#,
+            r#"{"a":1-2}"#,
+            r#"{"a":1,}"#,
+            r#"{"a"}"#,
+            r#"{"a":tru}"#,
+            r#"{"a":"b}"#,
+            r#"{"a":"\x"}"#,
+            r#"{"a":"\u00g0"}"#,
+            r#"{"a":[1}"#,
+            r#"{"a":1}}"#,
+            "{\"a\":\"\t\"}",
+        ] {
+            assert!(!accepts(text), "{text}");
+        }
+    }
+
+    #[test]
+    fn allows_eos_only_when_complete() {
+        let text = r#"{"a":[1,{"b":2}]}"#;
+        let mut grammar = grammar();
+        for c in text.chars() {
+            assert!(!grammar.allows(EOS));
+            grammar.advance(c as u32);
+        }
+
+        assert!(grammar.allows(EOS));
+        assert!((0..EOS).all(|token| !grammar.allows(token)));
+    }
+
+    #[test]
+    fn byte_level_text_maps_every_byte() {
+        let chars = byte_level_chars();
+        assert_eq!(chars.len(), 256);
+        assert_eq!(byte_level_text("Ġhello", &chars).as_deref(), Some(" hello"));
+        assert_eq!(byte_level_text("ĊĉčĀ", &chars).as_deref(), Some("\n\t\r\0"));
+        assert_eq!(byte_level_text("Ã©", &chars).as_deref(), Some("é"));
+        // The first byte of a two bytes char is not valid on its own.
+        assert_eq!(byte_level_text("Ã", &chars), None);
+    }
+
+    #[test]
+    fn piece_text_maps_sentencepiece_markers() {
+        assert_eq!(piece_text("▁hello").as_deref(), Some(" hello"));
+        assert_eq!(piece_text("<0x0A>").as_deref(), Some("\n"));
+        assert_eq!(piece_text("<0xC3>"), None);
+        assert_eq!(piece_text("</s>"), None);
+    }
+}
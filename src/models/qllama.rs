@@ -0,0 +1,148 @@
+use anyhow::Result;
+use candle::{quantized::gguf_file, Device, Tensor};
+
+use crate::models::{
+    min_reply, penalty_tokens, prompt_tokens, sample_token, stop_token,
+    transformers::quantized_llama, CachedModel, Capabilities, JsonGrammar, Model, ModelId,
+    ModelInfo, ModelParams, Sample, TokensStream,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+/// Quantized model of the llama family, Zephyr and TinyLlama chat.
+pub struct QuantizedLlama {
+    model_id: ModelId,
+    model: quantized_llama::Transformer,
+    params: ModelParams,
+    tokenizer: tokenizers::Tokenizer,
+    eos_token: u32,
+    grammar: Option<JsonGrammar>,
+    rng: StdRng,
+    next_pos: usize,
+    context_tokens: Vec<u32>,
+    min_reply: Option<(u32, usize)>,
+    info: ModelInfo,
+}
+
+impl QuantizedLlama {
+    pub fn new(model_id: ModelId, params: ModelParams, cached_model: &CachedModel) -> Result<Self> {
+        let device = Device::Cpu;
+
+        let mut file = std::fs::File::open(&cached_model.model_path)?;
+        let gguf_content = gguf_file::Content::read(&mut file)
+            .map_err(|e| e.with_path(&cached_model.model_path))?;
+        let info = ModelInfo::from_gguf(&gguf_content);
+        let context_length = model_id.spec().context_length;
+        let model = quantized_llama::Transformer::from_gguf(
+            gguf_content,
+            &mut file,
+            context_length,
+            &device,
+        )?;
+
+        let tokenizer = cached_model.load_tokenizer()?;
+
+        let eos_token = *tokenizer.get_vocab(true).get("</s>").unwrap();
+
+        Ok(Self {
+            model_id,
+            model,
+            params,
+            tokenizer,
+            eos_token,
+            grammar: None,
+            rng: StdRng::seed_from_u64(0),
+            next_pos: 0,
+            context_tokens: Vec::new(),
+            min_reply: None,
+            info,
+        })
+    }
+}
+
+impl Model for QuantizedLlama {
+    fn prompt(&mut self, prompt: &str, params: &ModelParams) -> Result<TokensStream> {
+        self.params = params.clone();
+        self.grammar = None;
+        self.rng = StdRng::seed_from_u64(params.seed);
+        let spec = self.model_id.spec();
+        let eos_token = stop_token(&self.tokenizer, self.eos_token, params);
+        self.model.set_max_kv_cache_len(spec.kv_cache_len(params));
+
+        let (tokens, pos) = prompt_tokens(
+            &spec,
+            &self.tokenizer,
+            prompt,
+            params,
+            eos_token,
+            self.next_pos,
+        )?;
+        if pos == 0 {
+            self.model.clear_kv_cache();
+        }
+        self.min_reply = min_reply(eos_token, pos + tokens.len(), params);
+        self.forward(&tokens, pos)?;
+
+        self.grammar = params
+            .json_output
+            .then(|| JsonGrammar::from_tokenizer(&self.tokenizer, eos_token));
+
+        // Special tokens are kept to show the exact text the model has seen.
+        let template = self
+            .tokenizer
+            .decode(&tokens, false)
+            .map_err(anyhow::Error::msg)?;
+
+        Ok(TokensStream::new(eos_token, pos + tokens.len(), params)
+            .with_opener(spec.assistant_label(params))
+            .with_template(template))
+    }
+
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Sample> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let logits = self.model.forward(&input, pos)?;
+        self.next_pos = pos + tokens.len();
+        let tokens = penalty_tokens(&mut self.context_tokens, tokens, pos, &self.params);
+        let suppressed = self
+            .min_reply
+            .filter(|(_, min_pos)| pos < *min_pos)
+            .map(|(eos_token, _)| eos_token);
+        sample_token(
+            logits,
+            tokens,
+            &self.params,
+            self.grammar.as_mut(),
+            &mut self.rng,
+            suppressed,
+        )
+    }
+
+    fn encode_prompt(&self, prompt: &str, params: &ModelParams) -> Result<Vec<u32>> {
+        self.model_id
+            .spec()
+            .encode_prompt(&self.tokenizer, prompt, params)
+    }
+
+    fn decode(&mut self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(anyhow::Error::msg)
+    }
+
+    fn info(&self) -> ModelInfo {
+        self.info.clone()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.model_id
+            .spec()
+            .capabilities(&self.tokenizer, self.eos_token)
+    }
+
+    fn clear_context(&mut self) {
+        self.next_pos = 0;
+    }
+
+    fn context_used(&self) -> usize {
+        self.next_pos
+    }
+}
@@ -5,9 +5,11 @@ use candle_transformers::{
 };
 
 use crate::models::{
-    sample_token, transformers::quantized_llama, Model, ModelId, ModelParams, ModelsCache,
-    TokensStream,
+    min_reply, penalty_tokens, prompt_tokens, sample_token, stop_token,
+    transformers::quantized_llama, CachedModel, Capabilities, JsonGrammar, Model, ModelId,
+    ModelInfo, ModelParams, Sample, TokensStream,
 };
+use rand::{rngs::StdRng, SeedableRng};
 
 /// Quantized Mistral instruct model.
 pub struct QuantizedMistralInstruct {
@@ -15,22 +17,31 @@ pub struct QuantizedMistralInstruct {
     params: ModelParams,
     tokenizer: tokenizers::Tokenizer,
     eos_token: u32,
+    grammar: Option<JsonGrammar>,
+    rng: StdRng,
+    next_pos: usize,
+    context_tokens: Vec<u32>,
+    min_reply: Option<(u32, usize)>,
+    info: ModelInfo,
 }
 
 impl QuantizedMistralInstruct {
-    pub fn new(params: ModelParams) -> Result<Self> {
-        let cache = ModelsCache::new()?;
-        let cached_model = cache.cached_model(ModelId::Mistral7bInstructV02);
-
+    pub fn new(params: ModelParams, cached_model: &CachedModel) -> Result<Self> {
         let device = Device::Cpu;
 
         let mut file = std::fs::File::open(&cached_model.model_path)?;
         let gguf_content = gguf_file::Content::read(&mut file)
-            .map_err(|e| e.with_path(cached_model.model_path))?;
-        let model = quantized_llama::Transformer::from_gguf(gguf_content, &mut file, &device)?;
+            .map_err(|e| e.with_path(&cached_model.model_path))?;
+        let info = ModelInfo::from_gguf(&gguf_content);
+        let context_length = ModelId::Mistral7bInstructV02.spec().context_length;
+        let model = quantized_llama::Transformer::from_gguf(
+            gguf_content,
+            &mut file,
+            context_length,
+            &device,
+        )?;
 
-        let tokenizer = tokenizers::Tokenizer::from_file(cached_model.tokenizer_path)
-            .map_err(anyhow::Error::msg)?;
+        let tokenizer = cached_model.load_tokenizer()?;
 
         let eos_token = *tokenizer.get_vocab(true).get("</s>").unwrap();
 
@@ -39,31 +50,77 @@ impl QuantizedMistralInstruct {
             params,
             tokenizer,
             eos_token,
+            grammar: None,
+            rng: StdRng::seed_from_u64(0),
+            next_pos: 0,
+            context_tokens: Vec::new(),
+            min_reply: None,
+            info,
         })
     }
 }
 
 impl Model for QuantizedMistralInstruct {
     fn prompt(&mut self, prompt: &str, params: &ModelParams) -> Result<TokensStream> {
-        self.params = *params;
-        self.model.clear_kv_cache();
+        self.params = params.clone();
+        self.grammar = None;
+        self.rng = StdRng::seed_from_u64(params.seed);
+        let spec = ModelId::Mistral7bInstructV02.spec();
+        let eos_token = stop_token(&self.tokenizer, self.eos_token, params);
+        self.model.set_max_kv_cache_len(spec.kv_cache_len(params));
+
+        let (tokens, pos) = prompt_tokens(
+            &spec,
+            &self.tokenizer,
+            prompt,
+            params,
+            eos_token,
+            self.next_pos,
+        )?;
+        if pos == 0 {
+            self.model.clear_kv_cache();
+        }
+        self.min_reply = min_reply(eos_token, pos + tokens.len(), params);
+        self.forward(&tokens, pos)?;
 
-        let template = format!("[INST] {prompt} [/INST]");
-        let tokens = self
+        self.grammar = params
+            .json_output
+            .then(|| JsonGrammar::from_tokenizer(&self.tokenizer, eos_token));
+
+        // Special tokens are kept to show the exact text the model has seen.
+        let template = self
             .tokenizer
-            .encode(template, true)
-            .map_err(anyhow::Error::msg)?
-            .get_ids()
-            .to_vec();
-        self.forward(&tokens, 0)?;
+            .decode(&tokens, false)
+            .map_err(anyhow::Error::msg)?;
 
-        Ok(TokensStream::new(self.eos_token, tokens.len()))
+        Ok(TokensStream::new(eos_token, pos + tokens.len(), params)
+            .with_opener(spec.assistant_label(params))
+            .with_template(template))
     }
 
-    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<u32> {
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Sample> {
         let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
         let logits = self.model.forward(&input, pos)?;
-        sample_token(logits, tokens, &self.params)
+        self.next_pos = pos + tokens.len();
+        let tokens = penalty_tokens(&mut self.context_tokens, tokens, pos, &self.params);
+        let suppressed = self
+            .min_reply
+            .filter(|(_, min_pos)| pos < *min_pos)
+            .map(|(eos_token, _)| eos_token);
+        sample_token(
+            logits,
+            tokens,
+            &self.params,
+            self.grammar.as_mut(),
+            &mut self.rng,
+            suppressed,
+        )
+    }
+
+    fn encode_prompt(&self, prompt: &str, params: &ModelParams) -> Result<Vec<u32>> {
+        ModelId::Mistral7bInstructV02
+            .spec()
+            .encode_prompt(&self.tokenizer, prompt, params)
     }
 
     fn decode(&mut self, tokens: &[u32]) -> Result<String> {
@@ -71,6 +128,24 @@ impl Model for QuantizedMistralInstruct {
             .decode(tokens, true)
             .map_err(anyhow::Error::msg)
     }
+
+    fn info(&self) -> ModelInfo {
+        self.info.clone()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        ModelId::Mistral7bInstructV02
+            .spec()
+            .capabilities(&self.tokenizer, self.eos_token)
+    }
+
+    fn clear_context(&mut self) {
+        self.next_pos = 0;
+    }
+
+    fn context_used(&self) -> usize {
+        self.next_pos
+    }
 }
 
 /// Quantized Mistral 7B model.
@@ -79,21 +154,26 @@ pub struct QuantizedMistral7B {
     params: ModelParams,
     tokenizer: tokenizers::Tokenizer,
     eos_token: u32,
+    grammar: Option<JsonGrammar>,
+    rng: StdRng,
+    next_pos: usize,
+    context_tokens: Vec<u32>,
+    min_reply: Option<(u32, usize)>,
+    info: ModelInfo,
 }
 
 impl QuantizedMistral7B {
-    pub fn new(params: ModelParams) -> Result<Self> {
-        let cache = ModelsCache::new()?;
-        let cached_model = cache.cached_model(ModelId::Mistral7B);
-
+    pub fn new(params: ModelParams, cached_model: &CachedModel) -> Result<Self> {
         let device = Device::Cpu;
 
-        let vb = VarBuilder::from_gguf(cached_model.model_path, &device)?;
+        let info = ModelInfo::from_file(&cached_model.model_path)?;
+        let vb = VarBuilder::from_gguf(&cached_model.model_path, &device)?;
+        // The config fields are private in candle 0.4, its `max_position_embeddings`
+        // is the context length in the model spec.
         let config = mistral::Config::config_7b_v0_1(false);
         let model = quantized_mistral::Model::new(&config, vb)?;
 
-        let tokenizer = tokenizers::Tokenizer::from_file(cached_model.tokenizer_path)
-            .map_err(anyhow::Error::msg)?;
+        let tokenizer = cached_model.load_tokenizer()?;
 
         let eos_token = *tokenizer.get_vocab(true).get("</s>").unwrap();
 
@@ -102,30 +182,76 @@ impl QuantizedMistral7B {
             params,
             tokenizer,
             eos_token,
+            grammar: None,
+            rng: StdRng::seed_from_u64(0),
+            next_pos: 0,
+            context_tokens: Vec::new(),
+            min_reply: None,
+            info,
         })
     }
 }
 
 impl Model for QuantizedMistral7B {
     fn prompt(&mut self, prompt: &str, params: &ModelParams) -> Result<TokensStream> {
-        self.params = *params;
-        self.model.clear_kv_cache();
+        self.params = params.clone();
+        self.grammar = None;
+        self.rng = StdRng::seed_from_u64(params.seed);
+        let spec = ModelId::Mistral7B.spec();
+        let eos_token = stop_token(&self.tokenizer, self.eos_token, params);
+
+        let (tokens, pos) = prompt_tokens(
+            &spec,
+            &self.tokenizer,
+            prompt,
+            params,
+            eos_token,
+            self.next_pos,
+        )?;
+        if pos == 0 {
+            self.model.clear_kv_cache();
+        }
+        self.min_reply = min_reply(eos_token, pos + tokens.len(), params);
+        self.forward(&tokens, pos)?;
 
-        let tokens = self
+        self.grammar = params
+            .json_output
+            .then(|| JsonGrammar::from_tokenizer(&self.tokenizer, eos_token));
+
+        // Special tokens are kept to show the exact text the model has seen.
+        let template = self
             .tokenizer
-            .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?
-            .get_ids()
-            .to_vec();
-        self.forward(&tokens, 0)?;
+            .decode(&tokens, false)
+            .map_err(anyhow::Error::msg)?;
 
-        Ok(TokensStream::new(self.eos_token, tokens.len()))
+        Ok(TokensStream::new(eos_token, pos + tokens.len(), params)
+            .with_opener(spec.assistant_label(params))
+            .with_template(template))
     }
 
-    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<u32> {
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Sample> {
         let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
         let logits = self.model.forward(&input, pos)?;
-        sample_token(logits, tokens, &self.params)
+        self.next_pos = pos + tokens.len();
+        let tokens = penalty_tokens(&mut self.context_tokens, tokens, pos, &self.params);
+        let suppressed = self
+            .min_reply
+            .filter(|(_, min_pos)| pos < *min_pos)
+            .map(|(eos_token, _)| eos_token);
+        sample_token(
+            logits,
+            tokens,
+            &self.params,
+            self.grammar.as_mut(),
+            &mut self.rng,
+            suppressed,
+        )
+    }
+
+    fn encode_prompt(&self, prompt: &str, params: &ModelParams) -> Result<Vec<u32>> {
+        ModelId::Mistral7B
+            .spec()
+            .encode_prompt(&self.tokenizer, prompt, params)
     }
 
     fn decode(&mut self, tokens: &[u32]) -> Result<String> {
@@ -133,4 +259,22 @@ impl Model for QuantizedMistral7B {
             .decode(tokens, true)
             .map_err(anyhow::Error::msg)
     }
+
+    fn info(&self) -> ModelInfo {
+        self.info.clone()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        ModelId::Mistral7B
+            .spec()
+            .capabilities(&self.tokenizer, self.eos_token)
+    }
+
+    fn clear_context(&mut self) {
+        self.next_pos = 0;
+    }
+
+    fn context_used(&self) -> usize {
+        self.next_pos
+    }
 }
@@ -3,9 +3,11 @@ use candle::{Device, Tensor};
 use candle_transformers::quantized_var_builder::VarBuilder;
 
 use crate::models::{
-    sample_token, transformers::quantized_stable_lm, Model, ModelId, ModelParams, ModelsCache,
-    TokensStream,
+    min_reply, penalty_tokens, prompt_tokens, sample_token, stop_token,
+    transformers::quantized_stable_lm, CachedModel, Capabilities, JsonGrammar, Model, ModelId,
+    ModelInfo, ModelParams, Sample, TokensStream,
 };
+use rand::{rngs::StdRng, SeedableRng};
 
 /// Quantized StableLM model.
 pub struct QuantizedStableLM {
@@ -13,18 +15,22 @@ pub struct QuantizedStableLM {
     params: ModelParams,
     tokenizer: tokenizers::Tokenizer,
     eos_token: u32,
+    grammar: Option<JsonGrammar>,
+    rng: StdRng,
+    next_pos: usize,
+    context_tokens: Vec<u32>,
+    min_reply: Option<(u32, usize)>,
+    info: ModelInfo,
 }
 
 impl QuantizedStableLM {
-    pub fn new(params: ModelParams) -> Result<Self> {
-        let cache = ModelsCache::new()?;
-        let cached_model = cache.cached_model(ModelId::StableLm2Zephyr);
-
+    pub fn new(params: ModelParams, cached_model: &CachedModel) -> Result<Self> {
         let device = Device::Cpu;
-        let vb = VarBuilder::from_gguf(cached_model.model_path, &device)?;
-        let model = quantized_stable_lm::Transformer::new(vb)?;
-        let tokenizer = tokenizers::Tokenizer::from_file(cached_model.tokenizer_path)
-            .map_err(anyhow::Error::msg)?;
+        let info = ModelInfo::from_file(&cached_model.model_path)?;
+        let vb = VarBuilder::from_gguf(&cached_model.model_path, &device)?;
+        let context_length = ModelId::StableLm2Zephyr.spec().context_length;
+        let model = quantized_stable_lm::Transformer::new(vb, context_length)?;
+        let tokenizer = cached_model.load_tokenizer()?;
         let eos_token = *tokenizer.get_vocab(true).get("<|endoftext|>").unwrap();
 
         Ok(Self {
@@ -32,31 +38,83 @@ impl QuantizedStableLM {
             params,
             tokenizer,
             eos_token,
+            grammar: None,
+            rng: StdRng::seed_from_u64(0),
+            next_pos: 0,
+            context_tokens: Vec::new(),
+            min_reply: None,
+            info,
         })
     }
 }
 
 impl Model for QuantizedStableLM {
     fn prompt(&mut self, prompt: &str, params: &ModelParams) -> Result<TokensStream> {
-        self.params = *params;
-        self.model.clear_kv_cache();
+        // The skipped layers have not seen the previous turns.
+        if params.decoder_layers != self.params.decoder_layers {
+            self.next_pos = 0;
+        }
+
+        self.params = params.clone();
+        self.grammar = None;
+        self.rng = StdRng::seed_from_u64(params.seed);
+        let spec = ModelId::StableLm2Zephyr.spec();
+        let eos_token = stop_token(&self.tokenizer, self.eos_token, params);
+        self.model.set_max_kv_cache_len(spec.kv_cache_len(params));
 
-        let template = format!("<|user|>\n{prompt}<|endoftext|>\n");
-        let tokens = self
+        let (tokens, pos) = prompt_tokens(
+            &spec,
+            &self.tokenizer,
+            prompt,
+            params,
+            eos_token,
+            self.next_pos,
+        )?;
+        if pos == 0 {
+            self.model.clear_kv_cache();
+        }
+        self.min_reply = min_reply(eos_token, pos + tokens.len(), params);
+        self.forward(&tokens, pos)?;
+
+        self.grammar = params
+            .json_output
+            .then(|| JsonGrammar::from_tokenizer(&self.tokenizer, eos_token));
+
+        // Special tokens are kept to show the exact text the model has seen.
+        let template = self
             .tokenizer
-            .encode(template, true)
-            .map_err(anyhow::Error::msg)?
-            .get_ids()
-            .to_vec();
-        self.forward(&tokens, 0)?;
+            .decode(&tokens, false)
+            .map_err(anyhow::Error::msg)?;
 
-        Ok(TokensStream::new(self.eos_token, tokens.len()))
+        Ok(TokensStream::new(eos_token, pos + tokens.len(), params)
+            .with_opener(spec.assistant_label(params))
+            .with_template(template))
     }
 
-    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<u32> {
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Sample> {
         let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
-        let logits = self.model.forward(&input, pos)?;
-        sample_token(logits, tokens, &self.params)
+        let num_layers = (self.params.decoder_layers > 0).then_some(self.params.decoder_layers);
+        let logits = self.model.forward(&input, pos, num_layers)?;
+        self.next_pos = pos + tokens.len();
+        let tokens = penalty_tokens(&mut self.context_tokens, tokens, pos, &self.params);
+        let suppressed = self
+            .min_reply
+            .filter(|(_, min_pos)| pos < *min_pos)
+            .map(|(eos_token, _)| eos_token);
+        sample_token(
+            logits,
+            tokens,
+            &self.params,
+            self.grammar.as_mut(),
+            &mut self.rng,
+            suppressed,
+        )
+    }
+
+    fn encode_prompt(&self, prompt: &str, params: &ModelParams) -> Result<Vec<u32>> {
+        ModelId::StableLm2Zephyr
+            .spec()
+            .encode_prompt(&self.tokenizer, prompt, params)
     }
 
     fn decode(&mut self, tokens: &[u32]) -> Result<String> {
@@ -64,4 +122,22 @@ impl Model for QuantizedStableLM {
             .decode(tokens, false)
             .map_err(anyhow::Error::msg)
     }
+
+    fn info(&self) -> ModelInfo {
+        self.info.clone()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        ModelId::StableLm2Zephyr
+            .spec()
+            .capabilities(&self.tokenizer, self.eos_token)
+    }
+
+    fn clear_context(&mut self) {
+        self.next_pos = 0;
+    }
+
+    fn context_used(&self) -> usize {
+        self.next_pos
+    }
 }
@@ -2,7 +2,10 @@
 //
 // https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/quantized_llama.rs
 //
-// with some changes to rerun the same model instance on a new prompt (clear_kv_cache).
+// with some changes to rerun the same model instance on a new prompt (clear_kv_cache),
+// to size the rotary embeddings from the model context length, to mask prompts
+// that continue after the tokens in the KV cache, and to keep the KV cache within a
+// maximum size (max_kv_cache_len).
 use std::collections::HashMap;
 
 use candle::quantized::QTensor;
@@ -10,6 +13,7 @@ use candle::quantized::{ggml_file, gguf_file};
 use candle::{DType, Device, IndexOp, Result, Tensor, D};
 use candle_nn::{Embedding, Module};
 
+/// Maximum sequence length for models loaded from GGML files.
 pub const MAX_SEQ_LEN: usize = 4096;
 
 #[derive(Debug, Clone)]
@@ -160,10 +164,12 @@ struct LayerWeights {
     n_head: usize,
     n_kv_head: usize,
     head_dim: usize,
+    theta: Tensor,
     cos: Tensor,
     sin: Tensor,
     neg_inf: Tensor,
     kv_cache: Option<(Tensor, Tensor)>,
+    max_kv_cache_len: usize,
     span_attn: tracing::Span,
     span_rot: tracing::Span,
     span_mlp: tracing::Span,
@@ -179,14 +185,17 @@ impl LayerWeights {
     fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
         let (b_sz, n_head, seq_len, n_embd) = x.dims4()?;
-        let cos = self
-            .cos
-            .narrow(0, index_pos, seq_len)?
-            .reshape((seq_len, n_embd / 2, 1))?;
-        let sin = self
-            .sin
-            .narrow(0, index_pos, seq_len)?
-            .reshape((seq_len, n_embd / 2, 1))?;
+        // With a sliding KV cache positions can go past the precomputed table.
+        let (cos, sin) = if index_pos + seq_len <= self.cos.dim(0)? {
+            (
+                self.cos.narrow(0, index_pos, seq_len)?,
+                self.sin.narrow(0, index_pos, seq_len)?,
+            )
+        } else {
+            rope_freqs(&self.theta, index_pos, seq_len)?
+        };
+        let cos = cos.reshape((seq_len, n_embd / 2, 1))?;
+        let sin = sin.reshape((seq_len, n_embd / 2, 1))?;
         let cos = cos.broadcast_as((b_sz, 1, seq_len, n_embd / 2, 1))?;
         let sin = sin.broadcast_as((b_sz, 1, seq_len, n_embd / 2, 1))?;
         // This mimics the llama.cpp behavior.
@@ -237,7 +246,17 @@ impl LayerWeights {
                 }
             }
         };
-        self.kv_cache = Some((k.clone(), v.clone()));
+        // Drop the oldest entries to keep the cache within the configured size.
+        let kv_len = k.dim(2)?;
+        self.kv_cache = if self.max_kv_cache_len > 0 && kv_len > self.max_kv_cache_len {
+            let start = kv_len - self.max_kv_cache_len;
+            Some((
+                k.narrow(2, start, self.max_kv_cache_len)?,
+                v.narrow(2, start, self.max_kv_cache_len)?,
+            ))
+        } else {
+            Some((k.clone(), v.clone()))
+        };
 
         // Support for MQA, useful for 70B models.
         let k = self.repeat_kv(k)?;
@@ -287,17 +306,25 @@ pub struct Transformer {
 fn precomput_freqs_cis(
     head_dim: usize,
     freq_base: f32,
+    max_seq_len: usize,
     device: &Device,
-) -> Result<(Tensor, Tensor)> {
+) -> Result<(Tensor, Tensor, Tensor)> {
     let theta: Vec<_> = (0..head_dim)
         .step_by(2)
         .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
         .collect();
     let theta = Tensor::new(theta.as_slice(), device)?;
-    let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
+    let theta = theta.reshape((1, theta.elem_count()))?;
+    let (cos, sin) = rope_freqs(&theta, 0, max_seq_len)?;
+    Ok((theta, cos, sin))
+}
+
+/// Computes the rotary embeddings for `seq_len` positions starting at `offset`.
+fn rope_freqs(theta: &Tensor, offset: usize, seq_len: usize) -> Result<(Tensor, Tensor)> {
+    let idx_theta = Tensor::arange(offset as u32, (offset + seq_len) as u32, theta.device())?
         .to_dtype(DType::F32)?
-        .reshape((MAX_SEQ_LEN, 1))?
-        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+        .reshape((seq_len, 1))?
+        .matmul(theta)?;
     let cos = idx_theta.cos()?;
     let sin = idx_theta.sin()?;
     Ok((cos, sin))
@@ -307,7 +334,8 @@ impl Transformer {
     #[allow(dead_code)]
     pub fn from_ggml(mut ct: ggml_file::Content, gqa: usize) -> Result<Self> {
         let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
-        let (cos, sin) = precomput_freqs_cis(head_dim, 10000., &ct.device)?;
+        let (theta, cos, sin) = precomput_freqs_cis(head_dim, 10000., MAX_SEQ_LEN, &ct.device)?;
+        let max_kv_cache_len = MAX_SEQ_LEN;
         let neg_inf = Tensor::new(f32::NEG_INFINITY, &ct.device)?;
         let tok_embeddings = ct.remove("tok_embeddings.weight")?;
         let tok_embeddings = tok_embeddings.dequantize(&ct.device)?;
@@ -346,10 +374,12 @@ impl Transformer {
                 n_head: ct.hparams.n_head as usize,
                 n_kv_head: ct.hparams.n_head as usize / gqa,
                 head_dim: (ct.hparams.n_embd / ct.hparams.n_head) as usize,
+                theta: theta.clone(),
                 cos: cos.clone(),
                 sin: sin.clone(),
                 neg_inf: neg_inf.clone(),
                 kv_cache: None,
+                max_kv_cache_len,
                 span_attn,
                 span_rot,
                 span_mlp,
@@ -368,9 +398,12 @@ impl Transformer {
         })
     }
 
+    /// Loads the model weights, `max_seq_len` is the model context length and the
+    /// maximum number of tokens kept in the KV cache.
     pub fn from_gguf<R: std::io::Seek + std::io::Read>(
         ct: gguf_file::Content,
         reader: &mut R,
+        max_seq_len: usize,
         device: &Device,
     ) -> Result<Self> {
         let md_get = |s: &str| match ct.metadata.get(s) {
@@ -396,7 +429,8 @@ impl Transformer {
         let rope_freq_base = md_get("llama.rope.freq_base")
             .and_then(|m| m.to_f32())
             .unwrap_or(10000f32);
-        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device)?;
+        let (theta, cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, max_seq_len, device)?;
+        let max_kv_cache_len = max_seq_len;
         let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
 
         let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
@@ -466,10 +500,12 @@ impl Transformer {
                 n_head: head_count,
                 n_kv_head: head_count_kv,
                 head_dim: embedding_length / head_count,
+                theta: theta.clone(),
                 cos: cos.clone(),
                 sin: sin.clone(),
                 neg_inf: neg_inf.clone(),
                 kv_cache: None,
+                max_kv_cache_len,
                 span_attn,
                 span_rot,
                 span_mlp,
@@ -488,22 +524,30 @@ impl Transformer {
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
+    fn mask(&mut self, t: usize, cache_len: usize, device: &Device) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t).filter(|_| cache_len == 0) {
             Ok(mask.clone())
         } else {
+            // The new tokens attend to all the cached tokens.
             let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .flat_map(|i| (0..cache_len + t).map(move |j| u8::from(j > cache_len + i)))
                 .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
-            self.masks.insert(t, mask.clone());
+            let mask = Tensor::from_slice(&mask, (t, cache_len + t), device)?;
+            if cache_len == 0 {
+                self.masks.insert(t, mask.clone());
+            }
             Ok(mask)
         }
     }
 
     pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
-        let mask = self.mask(seq_len, x.device())?;
+        // A single token attends to all the cached tokens without a mask.
+        let cache_len = match self.layers.first().and_then(|l| l.kv_cache.as_ref()) {
+            Some((k, _)) if index_pos > 0 && seq_len > 1 => k.dim(2)?,
+            _ => 0,
+        };
+        let mask = self.mask(seq_len, cache_len, x.device())?;
         let _enter = self.span.enter();
         let mut layer_in = self.tok_embeddings.forward(x)?;
         for layer in self.layers.iter_mut() {
@@ -527,6 +571,13 @@ impl Transformer {
         self.output.forward(&x)
     }
 
+    /// Sets the maximum number of tokens kept in the KV cache, 0 for no limit.
+    pub fn set_max_kv_cache_len(&mut self, max_kv_cache_len: usize) {
+        for layer in &mut self.layers {
+            layer.max_kv_cache_len = max_kv_cache_len;
+        }
+    }
+
     /// Resets the mode for a new prompt.
     pub fn clear_kv_cache(&mut self) {
         for layer in &mut self.layers {
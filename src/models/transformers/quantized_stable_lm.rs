@@ -2,7 +2,9 @@
 //
 // https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/quantized_stable_lm.rs
 //
-// with some changes to rerun the same model instance on a new prompt (clear_kv_cache).
+// with some changes to rerun the same model instance on a new prompt (clear_kv_cache)
+// and to keep the KV cache within a maximum size (max_kv_cache_len), the context
+// length comes from the model spec.
 use candle::{DType, Device, Module, Result, Tensor, D};
 use candle_nn::{Activation, LayerNorm};
 use candle_transformers::{
@@ -27,15 +29,19 @@ pub struct Config {
     pub use_cache: bool,
     #[serde(default)]
     pub use_qkv_bias: bool,
+    /// Maximum number of tokens kept in the KV cache, older entries are dropped when
+    /// the cache grows past this size.
+    #[serde(default)]
+    pub max_kv_cache_len: usize,
 }
 
 impl Config {
-    fn new() -> Self {
+    fn new(context_length: usize) -> Self {
         Self {
             hidden_act: Activation::Silu,
             hidden_size: 2048,
             intermediate_size: 5632,
-            max_position_embeddings: 4096,
+            max_position_embeddings: context_length,
             norm_eps: 1e-5,
             num_attention_heads: 32,
             num_hidden_layers: 24,
@@ -45,6 +51,7 @@ impl Config {
             use_cache: true,
             use_qkv_bias: true,
             vocab_size: 100352,
+            max_kv_cache_len: context_length,
         }
     }
     pub fn head_dim(&self) -> usize {
@@ -64,6 +71,7 @@ impl Config {
 pub(crate) struct RotaryEmbedding {
     sin: Tensor,
     cos: Tensor,
+    inv_freq: Tensor,
 }
 
 fn rotate_half(xs: &Tensor) -> Result<Tensor> {
@@ -81,17 +89,22 @@ impl RotaryEmbedding {
             .collect();
         let inv_freq_len = inv_freq.len();
         let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(dtype)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(dtype)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        let freqs = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
+        let freqs = Self::freqs(&inv_freq, 0, max_seq_len)?;
         Ok(Self {
             sin: freqs.sin()?,
             cos: freqs.cos()?,
+            inv_freq,
         })
     }
 
+    fn freqs(inv_freq: &Tensor, offset: usize, seq_len: usize) -> Result<Tensor> {
+        let t = Tensor::arange(offset as u32, (offset + seq_len) as u32, inv_freq.device())?
+            .to_dtype(inv_freq.dtype())?
+            .reshape((seq_len, 1))?;
+        let freqs = t.matmul(inv_freq)?;
+        Tensor::cat(&[&freqs, &freqs], D::Minus1)
+    }
+
     pub(crate) fn apply_rotary_emb_qkv(
         &self,
         q: &Tensor,
@@ -99,8 +112,16 @@ impl RotaryEmbedding {
         seqlen_offset: usize,
     ) -> Result<(Tensor, Tensor)> {
         let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
-        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
-        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        // With a sliding KV cache positions can go past the precomputed table.
+        let (cos, sin) = if seqlen_offset + seq_len <= self.cos.dim(0)? {
+            (
+                self.cos.narrow(0, seqlen_offset, seq_len)?,
+                self.sin.narrow(0, seqlen_offset, seq_len)?,
+            )
+        } else {
+            let freqs = Self::freqs(&self.inv_freq, seqlen_offset, seq_len)?;
+            (freqs.cos()?, freqs.sin()?)
+        };
         let cos = cos.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
         let sin = sin.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
         let q_embed = (q.broadcast_mul(&cos)? + rotate_half(q)?.broadcast_mul(&sin))?;
@@ -156,6 +177,7 @@ struct Attention {
     rotary_emb: Arc<RotaryEmbedding>,
     kv_cache: Option<(Tensor, Tensor)>,
     use_cache: bool,
+    max_kv_cache_len: usize,
     rotary_ndims: usize,
 }
 
@@ -187,6 +209,7 @@ impl Attention {
             rotary_emb,
             kv_cache: None,
             use_cache: cfg.use_cache,
+            max_kv_cache_len: cfg.max_kv_cache_len,
             rotary_ndims: cfg.rotary_ndims(),
         })
     }
@@ -195,6 +218,13 @@ impl Attention {
         self.kv_cache = None;
     }
 
+    fn kv_cache_len(&self) -> Result<usize> {
+        match &self.kv_cache {
+            Some((k, _)) => k.dim(2),
+            None => Ok(0),
+        }
+    }
+
     fn repeat_kv(&self, xs: Tensor) -> Result<Tensor> {
         let n_rep = self.num_kv_groups;
         if n_rep == 1 {
@@ -249,7 +279,17 @@ impl Attention {
             }
         };
         if self.use_cache {
-            self.kv_cache = Some((key_states.clone(), value_states.clone()));
+            // Drop the oldest entries to keep the cache within the configured size.
+            let kv_len = key_states.dim(2)?;
+            self.kv_cache = if self.max_kv_cache_len > 0 && kv_len > self.max_kv_cache_len {
+                let start = kv_len - self.max_kv_cache_len;
+                Some((
+                    key_states.narrow(2, start, self.max_kv_cache_len)?,
+                    value_states.narrow(2, start, self.max_kv_cache_len)?,
+                ))
+            } else {
+                Some((key_states.clone(), value_states.clone()))
+            };
         }
 
         let key_states = self.repeat_kv(key_states)?.contiguous()?;
@@ -303,6 +343,10 @@ impl DecoderLayer {
         self.self_attn.clear_kv_cache();
     }
 
+    fn kv_cache_len(&self) -> Result<usize> {
+        self.self_attn.kv_cache_len()
+    }
+
     fn forward(
         &mut self,
         xs: &Tensor,
@@ -329,8 +373,9 @@ pub struct Transformer {
 }
 
 impl Transformer {
-    pub fn new(vb: VarBuilder) -> Result<Self> {
-        let cfg = Config::new();
+    /// Loads the model weights, `context_length` is the model context length.
+    pub fn new(vb: VarBuilder, context_length: usize) -> Result<Self> {
+        let cfg = Config::new(context_length);
 
         let vb_m = vb.pp("model");
         let embed_tokens =
@@ -353,37 +398,53 @@ impl Transformer {
         })
     }
 
+    /// Builds the causal mask for the new tokens, `cache_len` is the number of entries
+    /// in the KV cache that may be smaller than the sequence offset once the cache
+    /// starts to slide.
     fn prepare_decoder_attention_mask(
         &self,
         b_size: usize,
         tgt_len: usize,
-        seqlen_offset: usize,
+        cache_len: usize,
     ) -> Result<Tensor> {
-        // Sliding window mask?
         let mask: Vec<_> = (0..tgt_len)
             .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
             .collect();
         let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+        let mask = if cache_len > 0 {
+            let mask0 = Tensor::zeros((tgt_len, cache_len), DType::F32, &self.device)?;
             Tensor::cat(&[&mask0, &mask], D::Minus1)?
         } else {
             mask
         };
-        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
+        mask.expand((b_size, 1, tgt_len, tgt_len + cache_len))?
             .to_dtype(DType::F32)
     }
 
-    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+    /// Computes the logits of the last position.
+    ///
+    /// If `num_layers` is set only the first decoder layers run before the final norm
+    /// and head, this is faster but the logits are of lower quality.
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+        num_layers: Option<usize>,
+    ) -> Result<Tensor> {
         let (b_size, seq_len) = input_ids.dims2()?;
         let attention_mask = if seq_len <= 1 {
             None
         } else {
-            let mask = self.prepare_decoder_attention_mask(b_size, seq_len, seqlen_offset)?;
+            let cache_len = match self.layers.first() {
+                Some(layer) => layer.kv_cache_len()?,
+                None => 0,
+            };
+            let mask = self.prepare_decoder_attention_mask(b_size, seq_len, cache_len)?;
             Some(mask)
         };
         let mut xs = self.embed_tokens.forward(input_ids)?;
-        for layer in self.layers.iter_mut() {
+        let num_layers = num_layers.unwrap_or(self.layers.len());
+        for layer in self.layers.iter_mut().take(num_layers) {
             xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
         }
         xs.narrow(1, seq_len - 1, 1)?
@@ -391,6 +452,13 @@ impl Transformer {
             .apply(&self.lm_head)
     }
 
+    /// Sets the maximum number of tokens kept in the KV cache, 0 for no limit.
+    pub fn set_max_kv_cache_len(&mut self, max_kv_cache_len: usize) {
+        for layer in &mut self.layers {
+            layer.self_attn.max_kv_cache_len = max_kv_cache_len;
+        }
+    }
+
     /// Resets the mode for a new prompt.
     pub fn clear_kv_cache(&mut self) {
         for layer in &mut self.layers {